@@ -0,0 +1,44 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "rpc")]
+    compile_rpc();
+}
+
+/// Generate the gRPC control API's client and server. Its messages are
+/// written by hand in src/rpc.rs, mirroring proto/harbor.proto, so
+/// building needs no protoc
+#[cfg(feature = "rpc")]
+fn compile_rpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::rpc::{input}"))
+            .output_type(format!("crate::rpc::{output}"))
+            .codec_path("tonic::codec::ProstCodec")
+            .build()
+    };
+    let control = Service::builder()
+        .name("Control")
+        .package("harbor")
+        .method(method("ping", "Ping", "PingRequest", "PingReply"))
+        .method(method(
+            "get_peers",
+            "GetPeers",
+            "GetPeersRequest",
+            "GetPeersReply",
+        ))
+        .method(method("put", "Put", "PutRequest", "PutReply"))
+        .method(method("get", "Get", "GetRequest", "GetReply"))
+        .method(method("join", "Join", "JoinRequest", "JoinReply"))
+        .method(method(
+            "get_greylist",
+            "GetGreylist",
+            "GetGreylistRequest",
+            "GetGreylistReply",
+        ))
+        .build();
+    Builder::new().build_transport(false).compile(&[control]);
+}
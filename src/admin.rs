@@ -0,0 +1,389 @@
+use crate::{
+    alias::Aliases,
+    lifecycle::State,
+    peer::{Key, Peer},
+    protocol::{Protocol, Response},
+    Error,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, prelude::*, BufReader},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+/// How often the server checks whether its peer is stopping
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Longest call the server reads. A longer one ends the session
+const MAX_LINE: u64 = 64 << 10;
+
+/// How long a session may sit idle before it is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/* JSON-RPC 2.0 error codes */
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+const UNAUTHORIZED: i64 = -32001;
+
+/// A JSON-RPC 2.0 endpoint for administering a running peer from scripts,
+/// served on localhost. Requests and responses are one JSON object per
+/// line. Keys can be given by alias. Each session has to call `auth` with
+/// the token from the token file before anything else. Methods:
+///
+/// - `auth {token}`: unlock the other methods for this session
+/// - `peer_info`: describe the peer
+/// - `peer_list`: the PeerIds in its PeerStore
+/// - `peer_greylist`: the peers it is ignoring for now, with the seconds
+///   until each is considered again
+/// - `store_put {key, value}`: store a UTF-8 value, returning whether one
+///   was replaced
+/// - `store_get {key}`: read a stored value
+/// - `shutdown`: stop the peer
+#[derive(Debug)]
+pub struct AdminServer {
+    addr: SocketAddr,
+    handle: thread::JoinHandle<Result<(), Error>>,
+}
+
+/// One call, as it arrives
+#[derive(Deserialize)]
+struct Call {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+/// A failed call, as JSON-RPC reports it
+struct Failure(i64, String);
+
+impl AdminServer {
+    /// Serve the admin endpoint for `node` on localhost at `port` until the
+    /// node starts draining, resolving keys through the aliases saved at
+    /// `aliases`. Callers authenticate with the token in the file at
+    /// `token`, which is made if it doesn't exist. Port 0 picks a free port
+    pub fn start<P: AsRef<Path>, T: AsRef<Path>>(
+        node: Peer,
+        port: u16,
+        aliases: P,
+        token: T,
+    ) -> Result<Self, Error> {
+        let token = load_or_create_token(token)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        info!("serving the admin endpoint on {addr}");
+        let aliases = aliases.as_ref().to_path_buf();
+        let handle = thread::spawn(move || serve(node, aliases, token, listener));
+        Ok(Self { addr, handle })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the server to stop
+    pub fn join(self) -> Result<(), Error> {
+        self.handle
+            .join()
+            .map_err(|_| io::Error::other("the admin server panicked"))?
+    }
+}
+
+/// The admin token saved at `path`, or a new random one saved there,
+/// readable only by its owner
+pub fn load_or_create_token<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    match fs::read_to_string(&path) {
+        Ok(token) => return Ok(token.trim().to_string()),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => (),
+    }
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+    let token = hex::encode(bytes);
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    writeln!(opts.open(&path)?, "{token}")?;
+    info!("saved a new admin token to {}", path.as_ref().display());
+    Ok(token)
+}
+
+fn serve(
+    node: Peer,
+    aliases: PathBuf,
+    token: String,
+    listener: TcpListener,
+) -> Result<(), Error> {
+    let token = Arc::new(token);
+    while node.state() < State::Draining {
+        match listener.accept() {
+            Ok((conn, _)) => {
+                let (node, aliases, token) =
+                    (node.clone(), aliases.clone(), token.clone());
+                thread::spawn(move || {
+                    if let Err(e) = session(&node, &aliases, &token, conn) {
+                        warn!("admin session failed: {e}");
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(SHUTDOWN_POLL)
+            }
+            Err(e) => warn!("failed to accept an admin connection: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Answer each call on a connection in turn, until it closes, goes quiet
+/// for IDLE_TIMEOUT, or sends a line over MAX_LINE
+fn session(node: &Peer, aliases: &Path, token: &str, conn: TcpStream) -> io::Result<()> {
+    conn.set_nonblocking(false)?;
+    conn.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = conn.try_clone()?;
+    let mut reader = BufReader::new(conn);
+    let mut authed = false;
+    loop {
+        let mut line = String::new();
+        if reader.by_ref().take(MAX_LINE).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE {
+            let too_long = Failure(
+                INVALID_REQUEST,
+                format!("calls are limited to {MAX_LINE} bytes"),
+            );
+            writeln!(writer, "{}", failure(Value::Null, too_long))?;
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match serde_json::from_str::<Value>(&line) {
+            Err(e) => failure(Value::Null, Failure(PARSE_ERROR, e.to_string())),
+            Ok(call) => answer(node, aliases, token, &mut authed, call),
+        };
+        writeln!(writer, "{answer}")?;
+    }
+}
+
+fn answer(
+    node: &Peer,
+    aliases: &Path,
+    token: &str,
+    authed: &mut bool,
+    call: Value,
+) -> Value {
+    let call = match serde_json::from_value::<Call>(call) {
+        Ok(call) if call.jsonrpc == "2.0" => call,
+        Ok(call) => return failure(call.id, invalid("jsonrpc must be \"2.0\"")),
+        Err(e) => return failure(Value::Null, invalid(&e.to_string())),
+    };
+    if call.method == "auth" {
+        return match param(&call.params, "token") {
+            Ok(given) if same(given.as_bytes(), token.as_bytes()) => {
+                *authed = true;
+                json!({ "jsonrpc": "2.0", "id": call.id, "result": true })
+            }
+            Ok(_) => failure(call.id, Failure(UNAUTHORIZED, "wrong token".to_string())),
+            Err(e) => failure(call.id, e),
+        };
+    }
+    if !*authed {
+        let locked = Failure(
+            UNAUTHORIZED,
+            "call auth with the admin token first".to_string(),
+        );
+        return failure(call.id, locked);
+    }
+    match call_method(node, aliases, &call.method, call.params) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": call.id, "result": result }),
+        Err(e) => failure(call.id, e),
+    }
+}
+
+fn call_method(
+    node: &Peer,
+    aliases: &Path,
+    method: &str,
+    params: Value,
+) -> Result<Value, Failure> {
+    match method {
+        "peer_info" => match node.handle_info() {
+            Ok(Response::Info(info)) => {
+                let id = info.id.to_string();
+                let mut info = json!(info);
+                info["id"] = json!(id);
+                Ok(info)
+            }
+            Ok(res) => Err(Failure(SERVER_ERROR, format!("unexpected {res:?}"))),
+            Err(e) => Err(Failure(SERVER_ERROR, e.to_string())),
+        },
+        "peer_list" => {
+            let peers = node
+                .lock_peers()
+                .map_err(|e| Failure(SERVER_ERROR, e.to_string()))?;
+            let ids: Vec<String> = peers.iter().map(|p| p.id().to_string()).collect();
+            Ok(json!(ids))
+        }
+        "peer_greylist" => {
+            let greylisted: Vec<Value> = node
+                .greylisted()
+                .into_iter()
+                .map(|(id, left)| json!({ "peer": id.to_string(), "seconds_left": left.as_secs() }))
+                .collect();
+            Ok(json!(greylisted))
+        }
+        "store_put" => {
+            let key = key(aliases, &params)?;
+            let value = param(&params, "value")?.as_bytes().to_vec();
+            let old = node
+                .put(key, value)
+                .map_err(|e| Failure(SERVER_ERROR, e.to_string()))?;
+            Ok(json!({ "replaced": old.is_some() }))
+        }
+        "store_get" => {
+            let key = key(aliases, &params)?;
+            let value = node
+                .get(&key)
+                .map_err(|e| Failure(SERVER_ERROR, e.to_string()))?
+                .ok_or_else(|| {
+                    Failure(SERVER_ERROR, format!("no value for key {key}"))
+                })?;
+            let value = String::from_utf8(value).map_err(|_| {
+                Failure(SERVER_ERROR, format!("value for {key} is not UTF-8"))
+            })?;
+            Ok(json!({ "value": value }))
+        }
+        "shutdown" => {
+            // Stopping waits on the accept loops, so don't hold up the answer
+            let node = node.clone();
+            thread::spawn(move || node.stop());
+            Ok(json!(true))
+        }
+        _ => Err(Failure(METHOD_NOT_FOUND, format!("no method {method:?}"))),
+    }
+}
+
+/// A string parameter, by name
+fn param<'a>(params: &'a Value, name: &str) -> Result<&'a str, Failure> {
+    params.get(name).and_then(Value::as_str).ok_or_else(|| {
+        Failure(INVALID_PARAMS, format!("expected a string param {name:?}"))
+    })
+}
+
+/// The key param, or the key it is an alias for
+fn key(aliases: &Path, params: &Value) -> Result<Key, Failure> {
+    let aliases =
+        Aliases::load(aliases).map_err(|e| Failure(SERVER_ERROR, e.to_string()))?;
+    Ok(aliases.resolve(param(params, "key")?))
+}
+
+/// Compare tokens in time that doesn't depend on where they differ
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn invalid(msg: &str) -> Failure {
+    Failure(INVALID_REQUEST, msg.to_string())
+}
+
+fn failure(id: Value, Failure(code, message): Failure) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::tests::test_peer;
+
+    #[test]
+    fn test_admin() {
+        let node = test_peer(9928);
+        let dir =
+            std::env::temp_dir().join(format!("harbor-admin-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let aliases = dir.join("aliases.txt");
+        fs::write(&aliases, "greeting k\n").unwrap();
+        let token_file = dir.join(crate::ADMIN_TOKEN_FILE);
+        let _ = fs::remove_file(&token_file);
+        let server = AdminServer::start(node.clone(), 0, &aliases, &token_file).unwrap();
+        let token = fs::read_to_string(&token_file).unwrap();
+        let conn = TcpStream::connect(server.addr()).unwrap();
+        let mut writer = conn.try_clone().unwrap();
+        let mut lines = BufReader::new(conn).lines();
+        let mut call = |line: &str| -> Value {
+            writeln!(writer, "{line}").unwrap();
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap()
+        };
+
+        // Nothing works until the session has shown the token
+        let res = call(r#"{"jsonrpc":"2.0","id":0,"method":"shutdown"}"#);
+        assert_eq!(res["error"]["code"], UNAUTHORIZED);
+        let res =
+            call(r#"{"jsonrpc":"2.0","id":0,"method":"auth","params":{"token":"x"}}"#);
+        assert_eq!(res["error"]["code"], UNAUTHORIZED);
+        let auth = json!({
+            "jsonrpc": "2.0", "id": 0, "method": "auth", "params": { "token": token.trim() },
+        });
+        assert_eq!(call(&auth.to_string())["result"], true);
+
+        let res = call(
+            r#"{"jsonrpc":"2.0","id":1,"method":"store_put","params":{"key":"k","value":"v"}}"#,
+        );
+        assert_eq!(res["result"]["replaced"], false);
+        let res =
+            call(r#"{"jsonrpc":"2.0","id":2,"method":"store_get","params":{"key":"k"}}"#);
+        assert_eq!(res["result"]["value"], "v");
+        assert_eq!(res["id"], 2);
+        let res = call(
+            r#"{"jsonrpc":"2.0","id":2,"method":"store_get","params":{"key":"greeting"}}"#,
+        );
+        assert_eq!(res["result"]["value"], "v");
+
+        let res = call(r#"{"jsonrpc":"2.0","id":3,"method":"peer_info"}"#);
+        assert_eq!(res["result"]["peers"], 0);
+        assert_eq!(res["result"]["id"], node.id.to_string());
+        let res = call(r#"{"jsonrpc":"2.0","id":4,"method":"peer_list"}"#);
+        assert_eq!(res["result"], json!([]));
+        let res = call(r#"{"jsonrpc":"2.0","id":4,"method":"peer_greylist"}"#);
+        assert_eq!(res["result"], json!([]));
+
+        // Mistakes are answered with JSON-RPC errors
+        assert_eq!(call("not json")["error"]["code"], PARSE_ERROR);
+        let res = call(r#"{"jsonrpc":"2.0","id":5,"method":"nope"}"#);
+        assert_eq!(res["error"]["code"], METHOD_NOT_FOUND);
+        let res = call(r#"{"jsonrpc":"2.0","id":6,"method":"store_get","params":{}}"#);
+        assert_eq!(res["error"]["code"], INVALID_PARAMS);
+
+        // A call too long to be real ends the session
+        let mut other = TcpStream::connect(server.addr()).unwrap();
+        other.write_all(&vec![b'x'; MAX_LINE as usize]).unwrap();
+        let mut answer = String::new();
+        BufReader::new(&other).read_to_string(&mut answer).unwrap();
+        assert!(answer.contains(&INVALID_REQUEST.to_string()));
+
+        let res = call(r#"{"jsonrpc":"2.0","id":7,"method":"shutdown"}"#);
+        assert_eq!(res["result"], true);
+        server.join().unwrap();
+        assert!(node.state() >= State::Draining);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{peer::Key, Error};
+use std::{collections::BTreeMap, fmt, fs, io, path::Path};
+
+/// Local pet names for keys, so hashes can be typed as words. Purely
+/// local: aliases never go over the wire. Saved one `name key` pair per
+/// line, with `#` comments
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Aliases {
+    names: BTreeMap<String, Key>,
+}
+
+impl Aliases {
+    /// Read aliases from a file. A missing file has no aliases yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut aliases = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(name), Some(key), None) => {
+                    aliases.names.insert(name.to_string(), Key::new(key));
+                }
+                _ => {
+                    return Err(Error::Decode(format!(
+                        "line {}: expected `name key`, got {line:?}",
+                        n + 1
+                    )))
+                }
+            }
+        }
+        Ok(aliases)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Name a key, returning the key the name was given before
+    pub fn add(&mut self, name: &str, key: Key) -> Result<Option<Key>, Error> {
+        if name.is_empty() || name.starts_with('#') || name.contains(char::is_whitespace)
+        {
+            return Err(Error::Decode(format!("{name:?} can't be used as an alias")));
+        }
+        Ok(self.names.insert(name.to_string(), key))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Key> {
+        self.names.remove(name)
+    }
+
+    /// Turn what a user typed into a key: an alias if there is one by that
+    /// name, otherwise the key itself
+    pub fn resolve(&self, name_or_key: &str) -> Key {
+        self.names
+            .get(name_or_key)
+            .cloned()
+            .unwrap_or_else(|| Key::new(name_or_key))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Key)> {
+        self.names.iter().map(|(name, key)| (name.as_str(), key))
+    }
+}
+
+impl fmt::Display for Aliases {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, key) in self.iter() {
+            writeln!(f, "{name} {key}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aliases() {
+        let mut aliases = Aliases::parse("# pet names\nthesis abc123\n\n").unwrap();
+        assert_eq!(aliases.resolve("thesis"), Key::new("abc123"));
+        assert_eq!(aliases.resolve("def456"), Key::new("def456"));
+
+        aliases.add("notes", Key::new("def456")).unwrap();
+        assert!(aliases.add("two words", Key::new("x")).is_err());
+        assert_eq!(Aliases::parse(&aliases.to_string()).unwrap(), aliases);
+
+        assert_eq!(aliases.remove("thesis"), Some(Key::new("abc123")));
+        assert!(Aliases::parse("thesis").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{
+    peer::{Peer, PeerId},
+    protocol::{NetworkResult, Request, Response},
+    transport::Transport,
+    NetworkError, BATCH_WINDOW, DIAL_TIMEOUT, MAX_BATCH_LEN,
+};
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{mpsc, Arc},
+    thread,
+    time::Instant,
+};
+
+/// Batches each batcher can have on the wire at once. A peer slow to
+/// answer only holds up the sender its batch went to
+const SENDERS: usize = 4;
+
+/// Where the response to a queued request is delivered
+type Reply = mpsc::Sender<NetworkResult<Response>>;
+
+type Batch = Vec<(Request, Reply)>;
+
+/// Coalesces small requests (pings, joins, PEX) headed for the same peer,
+/// and sends those queued within BATCH_WINDOW of each other as one
+/// `Request::Batch` instead of paying a round trip per message. One
+/// flusher thread keeps the windows and hands closed batches to a few
+/// sender threads, which stop once the batcher is dropped. Batches are
+/// sent as whoever holds `secret`, so requests like Join can be checked
+#[derive(Debug)]
+pub struct Batcher {
+    queued: mpsc::Sender<(PeerId, Request, Reply)>,
+}
+
+impl Batcher {
+    pub fn new(secret: [u8; 32]) -> Self {
+        let (queued, rx) = mpsc::channel();
+        thread::spawn(move || flush(rx, secret));
+        Self { queued }
+    }
+
+    /// Queue a request for a peer, returning where its response will
+    /// arrive. The first request queued for a peer opens the window, and a
+    /// batch that fills up is sent right away
+    pub fn queue(
+        &self,
+        to: &PeerId,
+        req: Request,
+    ) -> mpsc::Receiver<NetworkResult<Response>> {
+        let (tx, rx) = mpsc::channel();
+        // If the flusher is gone, dropping the reply says so
+        let _ = self.queued.send((to.clone(), req, tx));
+        rx
+    }
+
+    /// Queue a request for a peer and wait for its response
+    pub fn request(&self, to: &PeerId, req: Request) -> NetworkResult<Response> {
+        wait(self.queue(to, req))
+    }
+}
+
+/// Collect queued requests into a batch per peer until each batch's window
+/// closes or it fills up, and pass it on to be sent. Whatever is still
+/// waiting once every handle on the batcher is gone is sent straight away
+fn flush(queued: mpsc::Receiver<(PeerId, Request, Reply)>, secret: [u8; 32]) {
+    let (batches, rx) = mpsc::channel::<(PeerId, Batch)>();
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..SENDERS {
+        let rx = rx.clone();
+        thread::spawn(move || loop {
+            // Take the lock just long enough to pick up a batch
+            let next = rx.lock().recv();
+            match next {
+                Ok((to, batch)) => send(&to, &secret, batch),
+                Err(_) => break,
+            }
+        });
+    }
+
+    let mut windows: HashMap<PeerId, (Instant, Batch)> = HashMap::new();
+    let mut open = true;
+    while open {
+        let next = match windows.values().map(|(closes, _)| *closes).min() {
+            Some(closes) => {
+                queued.recv_timeout(closes.saturating_duration_since(Instant::now()))
+            }
+            None => queued
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok((to, req, reply)) => {
+                let (_, batch) = windows
+                    .entry(to.clone())
+                    .or_insert_with(|| (Instant::now() + BATCH_WINDOW, Vec::new()));
+                batch.push((req, reply));
+                if batch.len() >= MAX_BATCH_LEN {
+                    let (_, batch) = windows.remove(&to).unwrap();
+                    let _ = batches.send((to, batch));
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => open = false,
+        }
+
+        let now = Instant::now();
+        let closed: Vec<PeerId> = windows
+            .iter()
+            .filter(|(_, (closes, _))| !open || *closes <= now)
+            .map(|(to, _)| to.clone())
+            .collect();
+        for to in closed {
+            let (_, batch) = windows.remove(&to).unwrap();
+            let _ = batches.send((to, batch));
+        }
+    }
+}
+
+/// Wait for the response to a queued request
+pub fn wait(reply: mpsc::Receiver<NetworkResult<Response>>) -> NetworkResult<Response> {
+    reply.recv().unwrap_or_else(|_| {
+        Err(NetworkError::Fail("the batch was never sent".to_string()))
+    })
+}
+
+/// Send a batch and hand each response to whoever queued its request. A
+/// batch of one is sent on its own
+fn send(to: &PeerId, secret: &[u8; 32], batch: Vec<(Request, Reply)>) {
+    let (requests, replies): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    let len = requests.len();
+    let responses = match requests.len() {
+        1 => {
+            let req = requests.into_iter().next().unwrap();
+            Peer::send_request_as(to, req, DIAL_TIMEOUT, Some(secret))
+                .and_then(|mut conn| Peer::recv_response(&mut conn))
+                .map(|res| vec![res])
+        }
+        _ => send_batch(to, requests, Some(secret)),
+    };
+    match responses {
+        Ok(responses) => {
+            info!("sent {len} batched requests to {to:?}");
+            let mut responses = responses.into_iter();
+            for reply in replies {
+                let res = responses.next().ok_or_else(|| {
+                    NetworkError::Fail(format!("{to:?} answered too few requests"))
+                });
+                let _ = reply.send(res);
+            }
+        }
+        Err(e) => {
+            warn!("could not send {len} batched requests to {to:?}: {e}");
+            for reply in replies {
+                let _ = reply.send(Err(copy(&e)));
+            }
+        }
+    }
+}
+
+/// A copy of an error to hand to each request in a failed batch, keeping
+/// what it says about whether to retry
+fn copy(e: &NetworkError) -> NetworkError {
+    match e {
+        NetworkError::Io(msg, Some(src)) => {
+            io::Error::new(src.kind(), msg.clone()).into()
+        }
+        NetworkError::Rejected(reason, detail) => {
+            NetworkError::Rejected(*reason, detail.clone())
+        }
+        NetworkError::NoRoute(id) => NetworkError::NoRoute(id.clone()),
+        e => NetworkError::Fail(e.to_string()),
+    }
+}
+
+/// Send several requests to a peer as a single message, as whoever holds
+/// `secret`, returning their responses in the same order
+pub fn send_batch(
+    to: &PeerId,
+    batch: Vec<Request>,
+    secret: Option<&[u8; 32]>,
+) -> NetworkResult<Vec<Response>> {
+    let mut conn =
+        Peer::send_request_as(to, Request::Batch(batch), DIAL_TIMEOUT, secret)?;
+    match Peer::recv_response(&mut conn)? {
+        Response::Batch(responses) => Ok(responses),
+        Response::Err(e) => Err(e),
+        res => Err(NetworkError::Fail(format!(
+            "expected a batch response, got {res:?}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hooks::{Decision, Hooks},
+        peer::tests::{start_ready, test_peer},
+    };
+    use std::{net::IpAddr, time::Duration};
+
+    /// Remembers the kind of every request that arrives
+    #[derive(Debug, Default)]
+    struct Arrivals(Mutex<Vec<&'static str>>);
+
+    impl Hooks for Arrivals {
+        fn on_request(&self, from: IpAddr, request: &Request) -> Decision {
+            self.0.lock().push(request.kind());
+            Decision::Allow
+        }
+    }
+
+    #[test]
+    fn test_batcher() {
+        let mut peer = test_peer(9930);
+        let arrivals = Arc::new(Arrivals::default());
+        peer.set_hooks(arrivals.clone());
+        let handle = start_ready(&peer);
+
+        // Requests queued within the window go over together, and each
+        // gets its own response back
+        let batcher = Batcher::new([7; 32]);
+        let ping = batcher.queue(&peer.id, Request::Ping);
+        let identity = batcher.queue(&peer.id, Request::Identity);
+        let time = batcher.queue(&peer.id, Request::Time);
+        assert!(matches!(ping.recv().unwrap(), Ok(Response::Pong)));
+        assert!(
+            matches!(identity.recv().unwrap(), Ok(Response::Identity(id)) if id == peer.id)
+        );
+        assert!(matches!(time.recv().unwrap(), Ok(Response::Time(_))));
+        assert_eq!(*arrivals.0.lock(), ["Batch", "Ping", "Identity", "Time"]);
+
+        // One that is alone in its window goes on its own
+        assert!(matches!(
+            batcher.request(&peer.id, Request::Ping),
+            Ok(Response::Pong)
+        ));
+        assert_eq!(arrivals.0.lock().last(), Some(&"Ping"));
+        assert_eq!(arrivals.0.lock().len(), 5);
+
+        // Everyone waiting on a batch that can't be sent hears why
+        let nobody = PeerId::from("127.0.0.1".parse().unwrap(), 9);
+        let a = batcher.queue(&nobody, Request::Ping);
+        let b = batcher.queue(&nobody, Request::Ping);
+        for res in [a.recv().unwrap(), b.recv().unwrap()] {
+            assert!(res.is_err_and(|e| e.is_retryable()));
+        }
+
+        // Requests still waiting when the batcher goes are sent anyway
+        let last = batcher.queue(&peer.id, Request::Ping);
+        drop(batcher);
+        assert!(matches!(last.recv().unwrap(), Ok(Response::Pong)));
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use parking_lot::{Condvar, Mutex};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A cap on the bytes a peer holds for requests and responses in flight.
+/// Work that can't fit waits for room instead of growing the process
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// Bytes taken out of a MemoryBudget, given back when this is dropped
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes reserved right now
+    pub fn used(&self) -> usize {
+        *self.used.lock()
+    }
+
+    /// Whether most of the budget is in use, so work that can wait should
+    pub fn under_pressure(&self) -> bool {
+        self.used() >= self.limit / 4 * 3
+    }
+
+    /// Reserve `bytes` if there is room right now
+    pub fn try_reserve(self: &Arc<Self>, bytes: usize) -> Option<Reservation> {
+        self.reserve(bytes, Duration::ZERO)
+    }
+
+    /// Reserve `bytes`, waiting up to `wait` for other reservations to make
+    /// room. Asking for more than the whole budget always fails
+    pub fn reserve(
+        self: &Arc<Self>,
+        bytes: usize,
+        wait: Duration,
+    ) -> Option<Reservation> {
+        if bytes > self.limit {
+            return None;
+        }
+        let deadline = Instant::now() + wait;
+        let mut used = self.used.lock();
+        while *used + bytes > self.limit {
+            if self.freed.wait_until(&mut used, deadline).timed_out() {
+                return None;
+            }
+        }
+        *used += bytes;
+        Some(Reservation {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+
+    /// Reserve `bytes` like `reserve`, waiting for room on a blocking task
+    /// if there is none right now, so a runtime thread isn't held up
+    #[cfg(feature = "async")]
+    pub async fn reserve_async(
+        self: &Arc<Self>,
+        bytes: usize,
+        wait: Duration,
+    ) -> Option<Reservation> {
+        if let Some(reservation) = self.try_reserve(bytes) {
+            return Some(reservation);
+        }
+        let budget = self.clone();
+        tokio::task::spawn_blocking(move || budget.reserve(bytes, wait))
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.budget.used.lock() -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let held = budget.try_reserve(80).unwrap();
+        assert!(budget.under_pressure());
+        assert!(budget.try_reserve(30).is_none());
+        assert!(budget.try_reserve(101).is_none());
+
+        // A waiting reservation goes through once room is made
+        let waiter = {
+            let budget = budget.clone();
+            thread::spawn(move || budget.reserve(30, Duration::from_secs(5)).is_some())
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(held);
+        assert!(waiter.join().unwrap());
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{
+    peer::{Peer, PeerId},
+    protocol::{NetworkResult, Request, Response},
+    transport::Transport,
+    NetworkError, CLOCK_SAMPLES, DIAL_TIMEOUT,
+};
+use std::collections::VecDeque;
+
+/// This machine's time, in milliseconds since the epoch
+pub fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Ask a peer for its time and estimate how far its clock is ahead of
+/// ours, in milliseconds. The round trip is assumed to be symmetric
+pub fn sample(to: &PeerId) -> NetworkResult<i64> {
+    let sent = now_millis();
+    let mut conn = Peer::send_request_timeout(to, Request::Time, DIAL_TIMEOUT)?;
+    let theirs = match Peer::recv_response(&mut conn)? {
+        Response::Time(t) => t,
+        res => {
+            return Err(NetworkError::Fail(format!(
+                "unexpected time response {res:?}"
+            )))
+        }
+    };
+    let received = now_millis();
+    Ok(theirs - (sent + received) / 2)
+}
+
+/// Recent samples of how far other peers' clocks are from ours. Taking
+/// the median keeps one peer with a broken clock from skewing the estimate
+#[derive(Debug, Default)]
+pub struct ClockSkew {
+    samples: VecDeque<i64>,
+}
+
+impl ClockSkew {
+    pub fn add(&mut self, offset: i64) {
+        if self.samples.len() >= CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(offset);
+    }
+
+    /// How far the network's clock is estimated to be ahead of ours, in
+    /// milliseconds. None until some peer has been sampled
+    pub fn estimate(&self) -> Option<i64> {
+        let mut sorted: Vec<i64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted.get(sorted.len() / 2).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skew_estimate() {
+        let mut skew = ClockSkew::default();
+        assert_eq!(skew.estimate(), None);
+        for offset in [1000, 1200, 900, -3_600_000, 1100] {
+            skew.add(offset);
+        }
+        // The peer an hour behind doesn't drag the estimate with it
+        assert_eq!(skew.estimate(), Some(1000));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{
+    doctor::Check,
+    identity::Identity,
+    noise::Conn,
+    peer::{Key, Peer, PeerId},
+    protocol::{NetworkResult, Request, Response},
+    trace,
+    transport::{self, Transport},
+    NetworkError, Reason, DIAL_TIMEOUT,
+};
+use std::{
+    io::prelude::*,
+    net::{Shutdown, SocketAddr, TcpStream},
+};
+
+/// Drive the peer at `target` through every request it should understand,
+/// plus some malformed ones, checking each response against the protocol
+pub fn run(target: &PeerId) -> Vec<Check> {
+    // A peer with an identity goes by an id derived from its key, not the
+    // one derived from the address it was given by. Once known, every
+    // handshake also checks the peer holds that key
+    let target = &match request(target, Request::Identity) {
+        Ok(Response::Identity(id)) if id.as_socket() == target.as_socket() => id,
+        _ => target.clone(),
+    };
+
+    let mut checks = vec![
+        expect(target, "Ping", Request::Ping, |res| {
+            matches!(res, Response::Pong)
+        }),
+        expect(
+            target,
+            "Identity",
+            Request::Identity,
+            |res| matches!(res, Response::Identity(id) if id == target && id.verify_derivation()),
+        ),
+        expect(target, "List", Request::List, |res| {
+            matches!(res, Response::List(_))
+        }),
+        expect(
+            target,
+            "Get missing key",
+            Request::Get(Key::new("harbor-conformance-missing")),
+            |res| matches!(res, Response::Err(_)),
+        ),
+        expect(
+            target,
+            "PeerStore",
+            Request::PeerStore,
+            |res| matches!(res, Response::PeerStore(peers) if peers.iter().all(|p| p.id() != target)),
+        ),
+        expect(target, "Join self", Request::Join(target.clone()), |res| {
+            matches!(res, Response::Err(_))
+        }),
+        expect(
+            target,
+            "Batch",
+            Request::Batch(vec![Request::Ping, Request::Identity]),
+            |res| {
+                matches!(res, Response::Batch(r)
+                    if r.len() == 2 && matches!(r[0], Response::Pong))
+            },
+        ),
+        expect(
+            target,
+            "nested Batch",
+            Request::Batch(vec![Request::Batch(vec![])]),
+            |res| {
+                matches!(
+                    res,
+                    Response::Err(NetworkError::Rejected(Reason::Malformed, _))
+                )
+            },
+        ),
+        expect(
+            target,
+            "LivePeers",
+            Request::LivePeers,
+            |res| matches!(res, Response::LivePeers(ids) if !ids.contains(target)),
+        ),
+        expect(
+            target,
+            "Collect",
+            Request::Collect(Identity::from_secret([1; 32]).public_key()),
+            |res| {
+                matches!(
+                    res,
+                    Response::Err(NetworkError::Rejected(Reason::Unauthorized, _))
+                )
+            },
+        ),
+        expect(target, "DialBack", Request::DialBack { port: 1 }, |res| {
+            matches!(res, Response::Err(NetworkError::NoRoute(_)))
+        }),
+        expect(target, "Stats", Request::Stats, |res| {
+            matches!(res, Response::Stats(_))
+        }),
+        expect(
+            target,
+            "Info",
+            Request::Info,
+            |res| matches!(res, Response::Info(info) if info.id == *target),
+        ),
+        expect(
+            target,
+            "Time",
+            Request::Time,
+            |res| matches!(res, Response::Time(t) if *t > 0),
+        ),
+    ];
+
+    // Garbage must be turned away with the reason why, and without hurting
+    // the peer
+    let framed = |payload: &[u8]| {
+        let mut frame = Vec::new();
+        transport::write_frame(&mut frame, payload).unwrap();
+        frame
+    };
+    let malformed: [(&'static str, Vec<u8>, Reason); 5] = [
+        ("empty request", framed(&[]), Reason::Malformed),
+        (
+            "unknown request",
+            framed(&[0x00, 0xff, 0xff, 0xff, 0x7f]),
+            Reason::Malformed,
+        ),
+        (
+            "truncated request",
+            framed(&[0x00, 0x04, 0x00]),
+            Reason::Malformed,
+        ),
+        (
+            "truncated frame",
+            vec![0x10, 0x00, 0x00, 0x00, 0x00],
+            Reason::Malformed,
+        ),
+        (
+            "oversized frame",
+            vec![0xff, 0xff, 0xff, 0xff],
+            Reason::TooLarge,
+        ),
+    ];
+    for (name, bytes, reason) in malformed {
+        checks.push(reject(target, name, send_raw(target, &bytes), Some(reason)));
+    }
+
+    // Everything must be encrypted, so a well formed request sent in the
+    // clear must be dropped too
+    let ping = framed(&bincode::serialize(&trace::envelope(Request::Ping)).unwrap());
+    checks.push(reject(
+        target,
+        "plaintext request",
+        send_plain(target, &ping),
+        None,
+    ));
+    checks
+}
+
+/// Send a request and check the response with `ok`
+fn expect<F>(target: &PeerId, name: &'static str, req: Request, ok: F) -> Check
+where
+    F: Fn(&Response) -> bool,
+{
+    match request(target, req) {
+        Ok(res) if ok(&res) => Check::pass(name, format!("{res:?}")),
+        Ok(res) => Check::fail(name, format!("unexpected response {res:?}")),
+        Err(e) => Check::fail(name, format!("{e}")),
+    }
+}
+
+/// Check the answer to bytes that are not a valid request. The peer must
+/// turn them away for `reason`, or say nothing if there is none, and must
+/// still answer a ping afterwards
+fn reject(
+    target: &PeerId,
+    name: &'static str,
+    answer: NetworkResult<Vec<u8>>,
+    reason: Option<Reason>,
+) -> Check {
+    let alive = matches!(request(target, Request::Ping), Ok(Response::Pong));
+    let answer = match answer {
+        Ok(answer) if !answer.is_empty() => answer,
+        _ if !alive => {
+            return Check::fail(name, "peer stopped answering pings".to_string())
+        }
+        _ if reason.is_none() => return Check::pass(name, "dropped".to_string()),
+        _ => return Check::fail(name, "peer hung up without a reason".to_string()),
+    };
+    let response = transport::read_frame(&mut &answer[..])
+        .ok()
+        .and_then(|frame| bincode::deserialize::<Response>(&frame).ok());
+    match (response, alive) {
+        (_, false) => Check::fail(name, "peer stopped answering pings".to_string()),
+        (Some(Response::Err(NetworkError::Rejected(r, detail))), true)
+            if Some(r) == reason =>
+        {
+            Check::pass(name, format!("rejected ({r}): {detail}"))
+        }
+        (Some(res), true) => Check::fail(name, format!("unexpected response {res:?}")),
+        (None, true) => {
+            Check::fail(name, format!("peer answered with {} bytes", answer.len()))
+        }
+    }
+}
+
+fn request(target: &PeerId, req: Request) -> NetworkResult<Response> {
+    let mut conn = Peer::send_request_timeout(target, req, DIAL_TIMEOUT)?;
+    Peer::recv_response(&mut conn)
+}
+
+/// Send raw bytes over an encrypted connection, returning what came back
+fn send_raw(target: &PeerId, bytes: &[u8]) -> NetworkResult<Vec<u8>> {
+    let addr = SocketAddr::from((target.ip(), target.port()));
+    let conn = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)?;
+    conn.set_read_timeout(Some(DIAL_TIMEOUT))?;
+    let mut conn = Conn::dial(conn, target)?;
+    conn.write_all(bytes)?;
+    conn.shutdown(Shutdown::Write)?;
+
+    let mut answer = Vec::new();
+    conn.read_to_end(&mut answer)?;
+    Ok(answer)
+}
+
+/// Send raw bytes without a handshake, returning what came back
+fn send_plain(target: &PeerId, bytes: &[u8]) -> NetworkResult<Vec<u8>> {
+    let addr = SocketAddr::from((target.ip(), target.port()));
+    let mut conn = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)?;
+    conn.set_read_timeout(Some(DIAL_TIMEOUT))?;
+    conn.write_all(bytes)?;
+    conn.shutdown(Shutdown::Write)?;
+
+    let mut answer = Vec::new();
+    conn.read_to_end(&mut answer)?;
+    Ok(answer)
+}
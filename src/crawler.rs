@@ -0,0 +1,143 @@
+use crate::{
+    peer::{Key, Peer, PeerId},
+    protocol::{NetworkResult, NodeInfo, Request, Response},
+    transport::Transport,
+    NetworkError, DIAL_TIMEOUT,
+};
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt,
+};
+
+/// Maximum number of keys sampled from each crawled peer
+pub const KEY_SAMPLE_SIZE: usize = 10;
+
+/// Walk the network breadth first from `start`, going at most `depth` hops
+/// out. `visit` is called once for every peer found and returns the peers
+/// that peer knows about. Returns the peers that could not be visited
+pub fn walk<F>(start: &PeerId, depth: usize, mut visit: F) -> Vec<PeerId>
+where
+    F: FnMut(&PeerId) -> NetworkResult<Vec<PeerId>>,
+{
+    let mut unreachable = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([(start.clone(), 0)]);
+    visited.insert(start.clone());
+
+    while let Some((id, hops)) = queue.pop_front() {
+        let peers = match visit(&id) {
+            Ok(peers) => peers,
+            Err(e) => {
+                warn!("could not crawl {id:?}: {e}");
+                unreachable.push(id);
+                continue;
+            }
+        };
+
+        for peer in peers {
+            if hops < depth && visited.insert(peer.clone()) {
+                queue.push_back((peer, hops + 1));
+            }
+        }
+    }
+    unreachable
+}
+
+/// What was learned about one crawled peer
+#[derive(Serialize, Debug)]
+pub struct NodeReport {
+    pub info: NodeInfo,
+    pub peers: Vec<String>,
+    pub key_sample: Vec<String>,
+}
+
+/// The results of crawling the network
+#[derive(Serialize, Debug, Default)]
+pub struct CrawlReport {
+    pub nodes: Vec<NodeReport>,
+    pub unreachable: Vec<String>,
+}
+
+impl CrawlReport {
+    /// Crawl the network from `start`, at most `depth` hops out, recording
+    /// what each reachable peer reports about itself
+    pub fn crawl(start: &PeerId, depth: usize) -> Self {
+        let mut report = CrawlReport::default();
+        let unreachable = walk(start, depth, |id| {
+            let node = survey(id)?;
+            let peers = node.peers.iter().filter_map(|p| p.parse().ok()).collect();
+            info!("crawled {id:?}");
+            report.nodes.push(node);
+            Ok(peers)
+        });
+        report.unreachable = unreachable.iter().map(|id| id.to_string()).collect();
+        report
+    }
+}
+
+impl fmt::Display for CrawlReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reached = self.nodes.len();
+        writeln!(f, "peers reached:     {reached}")?;
+        writeln!(f, "peers unreachable: {}", self.unreachable.len())?;
+
+        if reached > 0 {
+            let uptime: u64 = self.nodes.iter().map(|n| n.info.uptime).sum();
+            let known: usize = self.nodes.iter().map(|n| n.peers.len()).sum();
+            writeln!(f, "mean uptime:       {}s", uptime / reached as u64)?;
+            writeln!(f, "mean peerstore:    {:.1}", known as f64 / reached as f64)?;
+        }
+
+        let mut agents: BTreeMap<&str, usize> = BTreeMap::new();
+        for node in self.nodes.iter() {
+            *agents.entry(&node.info.agent).or_default() += 1;
+        }
+        writeln!(f, "agents:")?;
+        for (agent, count) in agents {
+            writeln!(f, "    {agent}: {count}")?;
+        }
+
+        let keys: HashSet<&String> =
+            self.nodes.iter().flat_map(|n| &n.key_sample).collect();
+        writeln!(f, "distinct keys sampled: {}", keys.len())
+    }
+}
+
+/// Ask a peer about itself, its PeerStore, and a sample of its keys
+fn survey(id: &PeerId) -> NetworkResult<NodeReport> {
+    let info = match request(id, Request::Info)? {
+        Response::Info(info) => info,
+        res => return Err(unexpected(res)),
+    };
+    let peers = match request(id, Request::PeerStore)? {
+        Response::PeerStore(store) => store.iter().map(|p| p.id().to_string()).collect(),
+        res => return Err(unexpected(res)),
+    };
+
+    // Not every peer has keys to list
+    let key_sample = match request(id, Request::List) {
+        Ok(Response::List(keys)) => keys
+            .iter()
+            .take(KEY_SAMPLE_SIZE)
+            .map(Key::to_string)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok(NodeReport {
+        info,
+        peers,
+        key_sample,
+    })
+}
+
+fn request(id: &PeerId, req: Request) -> NetworkResult<Response> {
+    let mut conn = Peer::send_request_timeout(id, req, DIAL_TIMEOUT)?;
+    Peer::recv_response(&mut conn)
+}
+
+fn unexpected(res: Response) -> NetworkError {
+    NetworkError::Fail(format!("unexpected response {res:?}"))
+}
@@ -0,0 +1,196 @@
+use crate::{
+    protocol::{Envelope, Request, Response},
+    Error,
+};
+use bincode::Options;
+use std::{fmt, net::Ipv4Addr};
+
+/// A frame decoded from captured bytes. The wire format doesn't say
+/// whether a frame is a request or a response, so both are tried, and
+/// frames that parse as either are ambiguous. Bytes captured off the wire
+/// are encrypted, and only their size can be told
+#[derive(Debug)]
+pub enum Decoded {
+    Request(Envelope),
+    Response(Response),
+    Ambiguous(Envelope, Response),
+    Encrypted(usize),
+    Unknown(Vec<u8>),
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decoded::Request(req) => write!(f, "request {req:#?}"),
+            Decoded::Response(res) => write!(f, "response {res:#?}"),
+            Decoded::Ambiguous(req, res) => {
+                write!(f, "request {req:#?}\nor response {res:#?}")
+            }
+            Decoded::Encrypted(len) => write!(
+                f,
+                "encrypted {len} bytes; record the session with HARBOR_RECORD to see its frames"
+            ),
+            Decoded::Unknown(bytes) => {
+                write!(f, "unknown {} bytes: {}", bytes.len(), hex::encode(bytes))
+            }
+        }
+    }
+}
+
+/// Decode one frame. Only decodings that use up every byte count. Bytes
+/// copied off the wire start with the frame's length, which is skipped
+pub fn decode_frame(bytes: &[u8]) -> Decoded {
+    let bytes = match bytes {
+        [a, b, c, d, payload @ ..]
+            if u32::from_le_bytes([*a, *b, *c, *d]) as usize == payload.len() =>
+        {
+            payload
+        }
+        _ => bytes,
+    };
+    // Same encoding as bincode::serialize, but strict about trailing bytes
+    let strict = || {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+    };
+    let req = strict().deserialize::<Envelope>(bytes).ok();
+    let res = strict().deserialize::<Response>(bytes).ok();
+    match (req, res) {
+        (Some(req), Some(res)) => Decoded::Ambiguous(req, res),
+        (Some(req), None) => Decoded::Request(req),
+        (None, Some(res)) => Decoded::Response(res),
+        (None, None) if looks_encrypted(bytes) => Decoded::Encrypted(bytes.len()),
+        (None, None) => Decoded::Unknown(bytes.to_vec()),
+    }
+}
+
+/// Whether bytes look like a Noise message, prefixed by its length as a
+/// big endian u16, or a TLS record
+fn looks_encrypted(bytes: &[u8]) -> bool {
+    match bytes {
+        [a, b, rest @ ..] if u16::from_be_bytes([*a, *b]) as usize == rest.len() => true,
+        [0x14..=0x17, 0x03, 0x01..=0x04, ..] => true,
+        _ => false,
+    }
+}
+
+/// Parse a hexdump into bytes. Whitespace and `0x` prefixes are ignored,
+/// so `xxd -p` output and hex copied out of a packet analyzer both work
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, Error> {
+    let digits: String = text
+        .split_whitespace()
+        .map(|word| word.trim_start_matches("0x"))
+        .collect();
+    hex::decode(digits).map_err(|e| Error::Decode(e.to_string()))
+}
+
+/// A TCP payload carried in a captured packet
+#[derive(Debug)]
+pub struct Segment {
+    pub src: (Ipv4Addr, u16),
+    pub dst: (Ipv4Addr, u16),
+    pub payload: Vec<u8>,
+}
+
+/// Pull the TCP payloads out of a pcap capture of ethernet frames. Packets
+/// that are not IPv4 TCP, or carry no payload, are skipped
+pub fn parse_pcap(data: &[u8]) -> Result<Vec<Segment>, Error> {
+    let bad = |why: &str| Error::Decode(format!("bad pcap: {why}"));
+    if data.len() < 24 {
+        return Err(bad("too short"));
+    }
+
+    let magic = [data[0], data[1], data[2], data[3]];
+    let read_u32 = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] => u32::from_le_bytes,
+        [0xa1, 0xb2, 0xc3, 0xd4] => u32::from_be_bytes,
+        _ => return Err(bad("unknown magic number")),
+    };
+    let link_type = read_u32([data[20], data[21], data[22], data[23]]);
+    if link_type != 1 {
+        return Err(bad("only ethernet captures are supported"));
+    }
+
+    let mut segments = Vec::new();
+    let mut at = 24;
+    while at + 16 <= data.len() {
+        let len = read_u32([data[at + 8], data[at + 9], data[at + 10], data[at + 11]]);
+        let start = at + 16;
+        let end = start + len as usize;
+        if end > data.len() {
+            return Err(bad("truncated packet"));
+        }
+        if let Some(segment) = parse_ethernet(&data[start..end]) {
+            segments.push(segment);
+        }
+        at = end;
+    }
+    Ok(segments)
+}
+
+fn parse_ethernet(frame: &[u8]) -> Option<Segment> {
+    // Ethernet header, then IPv4 only
+    let ip = frame.get(14..)?;
+    if frame.get(12..14)? != [0x08, 0x00] || ip.first()? >> 4 != 4 {
+        return None;
+    }
+    let ip_len = ((ip[0] & 0x0f) as usize) * 4;
+    let total_len = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+    if *ip.get(9)? != 6 {
+        return None; // Not TCP
+    }
+    let src_ip = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_ip = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+
+    let tcp = ip.get(ip_len..total_len.min(ip.len()))?;
+    let src_port = u16::from_be_bytes([*tcp.first()?, *tcp.get(1)?]);
+    let dst_port = u16::from_be_bytes([*tcp.get(2)?, *tcp.get(3)?]);
+    let tcp_len = ((tcp.get(12)? >> 4) as usize) * 4;
+    let payload = tcp.get(tcp_len..)?;
+    if payload.is_empty() {
+        return None;
+    }
+
+    Some(Segment {
+        src: (src_ip, src_port),
+        dst: (dst_ip, dst_port),
+        payload: payload.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        let envelope = Envelope {
+            trace: None,
+            request: Request::DialBack { port: 3300 },
+        };
+        let bytes = bincode::serialize(&envelope).unwrap();
+        let text = format!("0x{}\n", hex::encode(&bytes));
+        let decoded = decode_frame(&parse_hex(&text).unwrap());
+        let shown = decoded.to_string();
+        assert!(shown.starts_with("request Envelope"));
+        assert!(shown.contains("DialBack") && shown.contains("3300"));
+        assert!(matches!(
+            decoded,
+            Decoded::Request(Envelope {
+                request: Request::DialBack { port: 3300 },
+                ..
+            })
+        ));
+
+        // As captured off the wire, with the frame length in front
+        let mut frame = Vec::new();
+        crate::transport::write_frame(&mut frame, &bytes).unwrap();
+        assert!(matches!(decode_frame(&frame), Decoded::Request(_)));
+
+        // A Noise message can't be read, but can be told apart from junk
+        let mut noise = vec![0, 48];
+        noise.extend([0xa5; 48]);
+        assert!(matches!(decode_frame(&noise), Decoded::Encrypted(50)));
+    }
+}
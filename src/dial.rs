@@ -0,0 +1,165 @@
+use crate::HAPPY_EYEBALLS_DELAY;
+use log::info;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How dialing one address has gone so far
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddrStats {
+    pub successes: u32,
+    pub failures: u32,
+}
+
+impl AddrStats {
+    /// Addresses that worked before are tried first, ones that never did
+    /// are tried last
+    fn rank(&self) -> i64 {
+        self.successes as i64 - self.failures as i64
+    }
+}
+
+/// Dials a peer that can be reached at several addresses, RFC 8305 style:
+/// attempts start one after another, a short delay apart, and the first
+/// connection to succeed wins
+#[derive(Debug, Default)]
+pub struct Dialer {
+    stats: Mutex<HashMap<SocketAddr, AddrStats>>,
+}
+
+impl Dialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How dialing an address has gone so far
+    pub fn stats(&self, addr: &SocketAddr) -> AddrStats {
+        self.stats.lock().get(addr).copied().unwrap_or_default()
+    }
+
+    /// The order candidates will be tried in: best track record first,
+    /// alternating address families so one broken family can't stall
+    /// every attempt
+    pub fn order(&self, candidates: &[SocketAddr]) -> Vec<SocketAddr> {
+        let mut sorted = candidates.to_vec();
+        sorted.sort_by_key(|addr| -self.stats(addr).rank());
+        let (mut v6, mut v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            sorted.iter().partition(|a| a.is_ipv6());
+        v6.reverse();
+        v4.reverse();
+
+        let first_v6 = sorted.first().is_some_and(SocketAddr::is_ipv6);
+        let mut ordered = Vec::with_capacity(sorted.len());
+        let mut turn_v6 = first_v6;
+        while !v6.is_empty() || !v4.is_empty() {
+            let next = match turn_v6 {
+                true => v6.pop().or_else(|| v4.pop()),
+                false => v4.pop().or_else(|| v6.pop()),
+            };
+            ordered.extend(next);
+            turn_v6 = !turn_v6;
+        }
+        ordered
+    }
+
+    /// Connect to whichever candidate answers first, giving each attempt up
+    /// to `timeout`
+    pub fn dial(
+        &self,
+        candidates: &[SocketAddr],
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        let (tx, rx) = mpsc::channel();
+        let mut pending = self.order(candidates).into_iter();
+        let mut running = 0;
+        let mut last_err = None;
+
+        let mut start_next = |running: &mut usize| -> bool {
+            match pending.next() {
+                Some(addr) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        let _ =
+                            tx.send((addr, TcpStream::connect_timeout(&addr, timeout)));
+                    });
+                    *running += 1;
+                    true
+                }
+                None => false,
+            }
+        };
+
+        let deadline =
+            Instant::now() + timeout + HAPPY_EYEBALLS_DELAY * candidates.len() as u32;
+        start_next(&mut running);
+        while running > 0 {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(wait.min(HAPPY_EYEBALLS_DELAY)) {
+                Ok((addr, Ok(conn))) => {
+                    info!("dialed {addr}");
+                    self.record(addr, true);
+                    return Ok(conn);
+                }
+                Ok((addr, Err(e))) => {
+                    self.record(addr, false);
+                    last_err = Some(e);
+                    running -= 1;
+                    // Don't wait out the delay after a failure
+                    start_next(&mut running);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() < deadline => {
+                    start_next(&mut running);
+                }
+                Err(_) => break,
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::TimedOut, "no candidate address answered")
+        }))
+    }
+
+    fn record(&self, addr: SocketAddr, ok: bool) {
+        let mut stats = self.stats.lock();
+        let entry = stats.entry(addr).or_default();
+        match ok {
+            true => entry.successes += 1,
+            false => entry.failures += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_dial_any() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+
+        // Nothing listens on a port just freed up
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let dialer = Dialer::new();
+        let conn = dialer
+            .dial(&[closed, good], Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), good);
+        assert_eq!(dialer.stats(&closed).failures, 1);
+        assert_eq!(dialer.stats(&good).successes, 1);
+
+        // Next time the address that worked goes first
+        assert_eq!(dialer.order(&[closed, good]), vec![good, closed]);
+        assert!(dialer.dial(&[closed], Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    clock,
+    identity::Identity,
+    noise::Conn,
+    peer::{dial_back, parse_bootstrap, Peer, PeerId},
+    protocol::{Request, Response},
+    resolve::SystemResolver,
+    transport::Transport,
+    util, BOOTSTRAP_FILE, DIAL_TIMEOUT, MAX_CLOCK_SKEW,
+};
+use std::{fmt, net::TcpListener, thread, time::Instant};
+
+/// The outcome of a single diagnostic check
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    pub(crate) fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            passed: true,
+            detail,
+        }
+    }
+
+    pub(crate) fn fail(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            passed: false,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.passed { "ok" } else { "FAIL" };
+        write!(f, "[{status:>4}] {}: {}", self.name, self.detail)
+    }
+}
+
+/// Run every diagnostic check for a peer that would listen on `port`
+pub fn run(port: u16) -> Vec<Check> {
+    let mut checks = vec![check_clock()];
+
+    let ip = match util::get_local_ip() {
+        Ok(ip) => {
+            checks.push(Check::pass("local ip", format!("detected {ip}")));
+            ip
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "local ip",
+                format!("{e}; is this machine connected to a network?"),
+            ));
+            return checks;
+        }
+    };
+
+    let listener = TcpListener::bind((ip, port));
+    checks.push(match &listener {
+        Ok(_) => Check::pass("listener", format!("can bind {ip}:{port}")),
+        Err(e) => Check::fail(
+            "listener",
+            format!("cannot bind {ip}:{port}: {e}; is another peer already running?"),
+        ),
+    });
+
+    checks.push(if ip.is_private() || ip.is_loopback() {
+        Check::pass(
+            "nat",
+            format!("{ip} is a private address, so this peer is likely behind a NAT and needs port {port} forwarded to be reachable"),
+        )
+    } else {
+        Check::pass("nat", format!("{ip} is publicly addressed"))
+    });
+
+    let (bootstrap, live) = check_bootstrap();
+    checks.extend(bootstrap);
+
+    if let Some(via) = &live {
+        checks.push(check_skew(via));
+    }
+    if let (Ok(listener), Some(via)) = (listener, live) {
+        checks.push(check_dial_back(listener, &via, port));
+    }
+    checks
+}
+
+/// Make sure the system clock is at least plausible
+fn check_clock() -> Check {
+    let now = chrono::Utc::now();
+    let earliest = chrono::NaiveDate::from_ymd_opt(2022, 1, 1).unwrap();
+    if now.date_naive() < earliest {
+        Check::fail(
+            "clock",
+            format!("system clock reads {now}, which is in the past; fix the time"),
+        )
+    } else {
+        Check::pass("clock", format!("system clock reads {now}"))
+    }
+}
+
+/// Compare this machine's clock with a live peer's
+fn check_skew(via: &PeerId) -> Check {
+    match clock::sample(via) {
+        Ok(offset) if offset.unsigned_abs() as u128 > MAX_CLOCK_SKEW.as_millis() => {
+            Check::fail(
+                "clock skew",
+                format!(
+                    "{} is {offset}ms ahead of this machine; fix the time",
+                    via.as_socket()
+                ),
+            )
+        }
+        Ok(offset) => Check::pass(
+            "clock skew",
+            format!("within {}ms of {}", offset.abs(), via.as_socket()),
+        ),
+        Err(e) => Check::fail(
+            "clock skew",
+            format!("could not get the time from {}: {e}", via.as_socket()),
+        ),
+    }
+}
+
+/// Ping every host in the bootstrap file. Also returns the first host that
+/// answered, if any
+fn check_bootstrap() -> (Vec<Check>, Option<PeerId>) {
+    let hosts = match util::read_lines(BOOTSTRAP_FILE) {
+        Ok(lines) => parse_bootstrap(lines.map_while(Result::ok), false, &SystemResolver)
+            .unwrap_or_default(),
+        Err(e) => {
+            let check =
+                Check::fail("bootstrap", format!("cannot read {BOOTSTRAP_FILE}: {e}"));
+            return (vec![check], None);
+        }
+    };
+    if hosts.is_empty() {
+        let check =
+            Check::fail("bootstrap", format!("no usable hosts in {BOOTSTRAP_FILE}"));
+        return (vec![check], None);
+    }
+
+    let checks: Vec<Check> = hosts.iter().map(ping).collect();
+    let live = hosts
+        .into_iter()
+        .zip(checks.iter())
+        .find(|(_, check)| check.passed)
+        .map(|(id, _)| id);
+    (checks, live)
+}
+
+/// Have a peer dial this machine back, answering its ping from the
+/// listener bound earlier
+fn check_dial_back(listener: TcpListener, via: &PeerId, port: u16) -> Check {
+    thread::spawn(move || {
+        let secret = match Identity::generate() {
+            Ok(identity) => identity.noise_secret(),
+            Err(_) => return,
+        };
+        if let Ok((conn, _)) = listener.accept() {
+            if let Ok(mut conn) = Conn::accept(conn, &secret) {
+                if let Ok(Request::Ping) = Peer::recv_request(&mut conn) {
+                    let _ = Peer::send_response(&mut conn, Response::Pong);
+                }
+            }
+        }
+    });
+
+    match dial_back(via, port) {
+        Ok(true) => Check::pass(
+            "reachability",
+            format!("{} reached this machine on port {port}", via.as_socket()),
+        ),
+        Ok(false) => Check::fail(
+            "reachability",
+            format!(
+                "{} could not reach this machine on port {port}; forward the port or open your firewall",
+                via.as_socket()
+            ),
+        ),
+        Err(e) => Check::fail(
+            "reachability",
+            format!("dial back through {} failed: {e}", via.as_socket()),
+        ),
+    }
+}
+
+/// Ping a peer, reporting the round trip time
+fn ping(id: &PeerId) -> Check {
+    let started = Instant::now();
+    let res = Peer::send_request_timeout(id, Request::Ping, DIAL_TIMEOUT)
+        .and_then(|mut conn| Peer::recv_response(&mut conn));
+    match res {
+        Ok(Response::Pong) => Check::pass(
+            "bootstrap",
+            format!("{} answered in {:?}", id.as_socket(), started.elapsed()),
+        ),
+        Ok(res) => Check::fail(
+            "bootstrap",
+            format!("{} answered a ping with {res:?}", id.as_socket()),
+        ),
+        Err(e) => Check::fail(
+            "bootstrap",
+            format!(
+                "cannot reach {}: {e}; check the address and your firewall",
+                id.as_socket()
+            ),
+        ),
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{identity::PublicKey, lifecycle::State};
+use parking_lot::Mutex;
+use std::sync::{mpsc::Sender, Arc};
+
+/// Something that happened on this peer that an application may want to
+/// react to. Subscribe with `Peer::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Every known peer stopped responding
+    NetworkDown,
+
+    /// Contact with the network was restored after a `NetworkDown`
+    NetworkUp,
+
+    /// The peer moved to a new phase of its lifecycle
+    StateChanged(State),
+
+    /// A friend sent this peer's identity a message, directly or through
+    /// another friend holding it
+    Message {
+        from: PublicKey,
+        /// When it was sent, in milliseconds since the epoch by the
+        /// sender's clock
+        sent: i64,
+        body: Vec<u8>,
+    },
+
+    /// A friend came online, or this peer did and the friend answered,
+    /// advertising a status
+    FriendOnline { from: PublicKey, status: String },
+
+    /// A member published a value into a group this peer is in, now in
+    /// this peer's store under `group::key(group, name)`
+    Published {
+        group: PublicKey,
+        name: String,
+        from: PublicKey,
+    },
+}
+
+/// The set of channels events are delivered to
+pub(crate) type Subscribers = Arc<Mutex<Vec<Sender<Event>>>>;
+
+/// Send an event to every subscriber, forgetting subscribers that hung up
+pub(crate) fn emit(subscribers: &Subscribers, event: Event) {
+    subscribers
+        .lock()
+        .retain(|tx| tx.send(event.clone()).is_ok());
+}
@@ -0,0 +1,115 @@
+use crate::{identity::PublicKey, Error};
+use std::{collections::BTreeMap, fmt, fs, io, path::Path};
+
+/// The identities this node trusts, by a local name. Only friends may send
+/// it messages, and it only holds messages for one friend from another.
+/// Saved one `name key` pair per line, with `#` comments
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Friends {
+    names: BTreeMap<String, PublicKey>,
+}
+
+impl Friends {
+    /// Read friends from a file. A missing file has no friends yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut friends = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(name), Some(key), None) => {
+                    friends.names.insert(name.to_string(), key.parse()?);
+                }
+                _ => {
+                    return Err(Error::Decode(format!(
+                        "line {}: expected `name key`, got {line:?}",
+                        n + 1
+                    )))
+                }
+            }
+        }
+        Ok(friends)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Trust a key under a name, returning the key the name was given before
+    pub fn add(
+        &mut self,
+        name: &str,
+        key: PublicKey,
+    ) -> Result<Option<PublicKey>, Error> {
+        if name.is_empty() || name.starts_with('#') || name.contains(char::is_whitespace)
+        {
+            return Err(Error::Decode(format!("{name:?} can't be used as a name")));
+        }
+        Ok(self.names.insert(name.to_string(), key))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<PublicKey> {
+        self.names.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PublicKey> {
+        self.names.get(name)
+    }
+
+    /// The name a key is trusted under, if it is
+    pub fn name_of(&self, key: &PublicKey) -> Option<&str> {
+        self.iter().find(|(_, k)| *k == key).map(|(name, _)| name)
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.names.values().any(|k| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PublicKey)> {
+        self.names.iter().map(|(name, key)| (name.as_str(), key))
+    }
+}
+
+impl fmt::Display for Friends {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, key) in self.iter() {
+            writeln!(f, "{name} {key}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+
+    #[test]
+    fn test_friends() {
+        let alice = Identity::generate().unwrap().public_key();
+        let bob = Identity::generate().unwrap().public_key();
+        let mut friends =
+            Friends::parse(&format!("# people\nalice {alice}\n\n")).unwrap();
+        assert!(friends.contains(&alice) && !friends.contains(&bob));
+        assert_eq!(friends.name_of(&alice), Some("alice"));
+
+        friends.add("bob", bob).unwrap();
+        assert!(friends.add("two words", bob).is_err());
+        assert_eq!(Friends::parse(&friends.to_string()).unwrap(), friends);
+
+        assert_eq!(friends.remove("alice"), Some(alice));
+        assert!(Friends::parse("alice").is_err());
+        assert!(Friends::parse("alice nothex").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::peer::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Strikes {
+    count: u32,
+    last: Instant,
+    until: Option<Instant>,
+}
+
+/// Peers that keep flapping or failing verification, ignored for a while
+/// instead of being retried over and over. A peer is greylisted once it
+/// racks up enough strikes, each within `cooldown` of the last, and comes
+/// off the list by itself once `cooldown` has passed
+#[derive(Debug)]
+pub struct Greylist {
+    peers: HashMap<PeerId, Strikes>,
+    strikes: u32,
+    cooldown: Duration,
+}
+
+impl Greylist {
+    /// Greylist peers for `cooldown` after `strikes` strikes
+    pub fn new(strikes: u32, cooldown: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            strikes,
+            cooldown,
+        }
+    }
+
+    /// Count a strike against a peer. Returns true if this strike got it
+    /// greylisted
+    pub fn strike(&mut self, id: &PeerId) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        let strikes = self.peers.entry(id.clone()).or_insert(Strikes {
+            count: 0,
+            last: now,
+            until: None,
+        });
+        if strikes.until.is_some() {
+            return false;
+        }
+        if now.duration_since(strikes.last) >= self.cooldown {
+            strikes.count = 0;
+        }
+        strikes.count += 1;
+        strikes.last = now;
+        if strikes.count < self.strikes {
+            return false;
+        }
+        strikes.until = Some(now + self.cooldown);
+        true
+    }
+
+    /// Whether a peer is greylisted right now
+    pub fn contains(&self, id: &PeerId) -> bool {
+        self.peers
+            .get(id)
+            .and_then(|s| s.until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// The peers greylisted right now, and how long until each comes off
+    pub fn active(&self) -> Vec<(PeerId, Duration)> {
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .filter_map(|(id, s)| match s.until {
+                Some(until) if now < until => Some((id.clone(), until - now)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Forget greylistings that are over, and strikes too old to count
+    fn expire(&mut self, now: Instant) {
+        let cooldown = self.cooldown;
+        self.peers.retain(|_, s| match s.until {
+            Some(until) => now < until,
+            None => now.duration_since(s.last) < cooldown,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_greylist() {
+        let id = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        let mut list = Greylist::new(2, Duration::from_secs(60));
+        assert!(!list.strike(&id));
+        assert!(!list.contains(&id));
+        assert!(list.strike(&id));
+        assert!(list.contains(&id));
+        assert_eq!(list.active().len(), 1);
+
+        // Greylistings run out by themselves
+        let mut list = Greylist::new(1, Duration::ZERO);
+        assert!(list.strike(&id));
+        assert!(!list.contains(&id));
+        assert!(list.active().is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    identity::{Identity, PublicKey},
+    peer::Key,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Who may publish into a group's namespace. Signed with the group's key,
+/// held by whoever runs the group, and replaced by any record with a higher
+/// version, so membership can change after the group is shared
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct Membership {
+    pub group: PublicKey,
+    pub version: u64,
+    pub members: Vec<PublicKey>,
+
+    /// The group key's signature over all of the above
+    pub sig: Vec<u8>,
+}
+
+impl Membership {
+    /// A membership record for the group `owner` is the key of
+    pub fn sign(owner: &Identity, version: u64, members: Vec<PublicKey>) -> Self {
+        let mut record = Self {
+            group: owner.public_key(),
+            version,
+            members,
+            sig: Vec::new(),
+        };
+        record.sig = owner.sign(&record.signed()).to_vec();
+        record
+    }
+
+    /// Whether the group key signed this record
+    pub fn verify(&self) -> bool {
+        verify(&self.group, &self.signed(), &self.sig)
+    }
+
+    pub fn is_member(&self, key: &PublicKey) -> bool {
+        self.members.contains(key)
+    }
+
+    fn signed(&self) -> Vec<u8> {
+        let mut signed = b"harbor membership".to_vec();
+        signed.extend_from_slice(self.group.as_bytes());
+        signed.extend_from_slice(&self.version.to_be_bytes());
+        for member in self.members.iter() {
+            signed.extend_from_slice(member.as_bytes());
+        }
+        signed
+    }
+}
+
+/// A value a member published under a name in a group's namespace
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct Publication {
+    pub group: PublicKey,
+    pub name: String,
+    pub value: Vec<u8>,
+    pub from: PublicKey,
+
+    /// The publishing member's signature over all of the above
+    pub sig: Vec<u8>,
+}
+
+impl Publication {
+    pub fn sign(
+        member: &Identity,
+        group: &PublicKey,
+        name: &str,
+        value: Vec<u8>,
+    ) -> Self {
+        let mut publication = Self {
+            group: *group,
+            name: name.to_string(),
+            value,
+            from: member.public_key(),
+            sig: Vec::new(),
+        };
+        publication.sig = member.sign(&publication.signed()).to_vec();
+        publication
+    }
+
+    /// Whether the member named in the publication signed it
+    pub fn verify(&self) -> bool {
+        verify(&self.from, &self.signed(), &self.sig)
+    }
+
+    /// Where the value is stored
+    pub fn key(&self) -> Key {
+        key(&self.group, &self.name)
+    }
+
+    fn signed(&self) -> Vec<u8> {
+        let mut signed = b"harbor publication".to_vec();
+        signed.extend_from_slice(self.group.as_bytes());
+        signed.extend_from_slice(&(self.name.len() as u64).to_be_bytes());
+        signed.extend_from_slice(self.name.as_bytes());
+        signed.extend_from_slice(&self.value);
+        signed.extend_from_slice(self.from.as_bytes());
+        signed
+    }
+}
+
+/// The key a value published under `name` in a group's namespace is stored
+/// under
+pub fn key(group: &PublicKey, name: &str) -> Key {
+    Key::new(&format!("/group/{group}/{name}"))
+}
+
+fn verify(key: &PublicKey, signed: &[u8], sig: &[u8]) -> bool {
+    match <[u8; 64]>::try_from(sig) {
+        Ok(sig) => key.verify(signed, &sig),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group() {
+        let owner = Identity::generate().unwrap();
+        let (alice, bob) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+        let record = Membership::sign(&owner, 1, vec![alice.public_key()]);
+        assert!(record.verify() && record.is_member(&alice.public_key()));
+
+        // Only the group key can change who is in it
+        let mut forged = record.clone();
+        forged.members.push(bob.public_key());
+        assert!(!forged.verify());
+        let mut forged = Membership::sign(&bob, 2, vec![bob.public_key()]);
+        forged.group = owner.public_key();
+        assert!(!forged.verify());
+
+        let publication =
+            Publication::sign(&alice, &record.group, "notes", b"hi".to_vec());
+        assert!(publication.verify());
+        assert_eq!(publication.key(), key(&owner.public_key(), "notes"));
+        let mut forged = publication;
+        forged.from = bob.public_key();
+        assert!(!forged.verify());
+    }
+}
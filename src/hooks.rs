@@ -0,0 +1,37 @@
+use crate::{
+    peer::{Key, PeerId},
+    protocol::Request,
+};
+use std::{fmt, net::IpAddr};
+
+/// Whether a request should be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// Callbacks into the application embedding a peer. Every method does
+/// nothing by default, so implement only the ones needed
+pub trait Hooks: fmt::Debug + Send + Sync {
+    /// A peer was added to the PeerStore
+    fn on_peer_added(&self, id: &PeerId) {}
+
+    /// A peer was evicted from the PeerStore
+    fn on_peer_removed(&self, id: &PeerId) {}
+
+    /// A value was stored under `key` with `Peer::put`
+    fn on_content_stored(&self, key: &Key) {}
+
+    /// A request arrived from `from`. Denied requests are answered with an
+    /// error instead of being handled, which also lets joins be vetoed
+    fn on_request(&self, from: IpAddr, request: &Request) -> Decision {
+        Decision::Allow
+    }
+}
+
+/// The hooks a peer starts with, which do nothing
+#[derive(Debug, Default)]
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
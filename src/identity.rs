@@ -0,0 +1,174 @@
+use crate::{peer::PeerId, Error};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    net::Ipv4Addr,
+    path::Path,
+};
+
+/// An Ed25519 public key, as it goes over the wire
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Whether `signature` was made over `message` with the secret half of
+    /// this key
+    pub fn verify(&self, message: &[u8], signature: &[u8; 64]) -> bool {
+        match VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key
+                .verify(message, &Signature::from_bytes(signature))
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl std::str::FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(s, &mut bytes)
+            .map_err(|e| Error::BadIdentity(format!("bad public key {s:?}: {e}")))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// The Ed25519 keypair a peer is known by. Its PeerId is derived from the
+/// public key, so the peer keeps its place in the network when its
+/// address changes. Saved to disk as the hex of the 32 byte secret key
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    /// A fresh identity from the OS's random number generator
+    pub fn generate() -> Result<Self, Error> {
+        let mut secret = [0; 32];
+        getrandom::getrandom(&mut secret)
+            .map_err(|e| Error::BadIdentity(format!("could not get randomness: {e}")))?;
+        Ok(Self::from_secret(secret))
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self {
+            key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)?;
+        let mut secret = [0; 32];
+        hex::decode_to_slice(text.trim(), &mut secret)
+            .map_err(|e| Error::BadIdentity(format!("bad secret key: {e}")))?;
+        Ok(Self::from_secret(secret))
+    }
+
+    /// Load the identity saved at `path`, or make one and save it there if
+    /// there is none yet
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match Self::load(&path) {
+            Err(Error::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate()?;
+                identity.save(&path)?;
+                Ok(identity)
+            }
+            res => res,
+        }
+    }
+
+    /// Save the secret key, readable only by its owner. Never overwrites an
+    /// existing identity
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut opts = fs::OpenOptions::new();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+        let mut file = opts.open(path)?;
+        writeln!(file, "{}", hex::encode(self.key.to_bytes()))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.key.sign(message).to_bytes()
+    }
+
+    /// The X25519 form of the secret key, for Noise handshakes
+    pub(crate) fn noise_secret(&self) -> [u8; 32] {
+        self.key.to_scalar_bytes()
+    }
+
+    /// The PeerId of this identity at an address
+    pub fn peer_id(&self, ip: Ipv4Addr, port: u16) -> PeerId {
+        PeerId::with_key(self.public_key(), ip, port)
+    }
+}
+
+/// Only ever shows the public key
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        // The first test vector of RFC 8032
+        let secret = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+        let path = std::env::temp_dir().join("harbor-test-identity.key");
+        let _ = fs::remove_file(&path);
+        fs::write(&path, secret).unwrap();
+        let identity = Identity::load_or_generate(&path).unwrap();
+        assert_eq!(
+            identity.public_key().to_string(),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+
+        let signature = identity.sign(b"hello");
+        assert!(identity.public_key().verify(b"hello", &signature));
+        assert!(!identity.public_key().verify(b"goodbye", &signature));
+
+        // The id follows the key across addresses
+        let here = identity.peer_id("10.0.0.1".parse().unwrap(), 3300);
+        let there = identity.peer_id("10.0.0.2".parse().unwrap(), 3301);
+        assert_eq!(here.hash(), there.hash());
+        assert!(here.verify_derivation() && there.verify_derivation());
+        assert!(!format!("{identity:?}").contains(secret));
+
+        // A fresh identity is saved, and loads back the same
+        fs::remove_file(&path).unwrap();
+        let fresh = Identity::load_or_generate(&path).unwrap();
+        let again = Identity::load_or_generate(&path).unwrap();
+        assert_eq!(fresh.public_key(), again.public_key());
+        assert!(fresh.save(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
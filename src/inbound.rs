@@ -0,0 +1,93 @@
+use parking_lot::Mutex;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    by_source: HashMap<IpAddr, usize>,
+}
+
+/// Counts the incoming connections being handled, in total and per source
+/// address, so a peer can turn away connections past its limits instead
+/// of falling over
+#[derive(Debug)]
+pub struct Inbound {
+    open: Mutex<Open>,
+    max_total: usize,
+    max_per_source: usize,
+}
+
+/// A connection let in by `Inbound::admit`. It stops counting towards the
+/// limits once this is dropped
+#[derive(Debug)]
+pub struct Admission {
+    inbound: Arc<Inbound>,
+    source: IpAddr,
+}
+
+impl Inbound {
+    pub fn new(max_total: usize, max_per_source: usize) -> Self {
+        Self {
+            open: Mutex::new(Open::default()),
+            max_total,
+            max_per_source,
+        }
+    }
+
+    /// Let in a connection from `source`, unless that would go over either
+    /// limit
+    pub fn admit(self: &Arc<Self>, source: IpAddr) -> Option<Admission> {
+        let mut open = self.open.lock();
+        let from_source = open.by_source.get(&source).copied().unwrap_or(0);
+        if open.total >= self.max_total || from_source >= self.max_per_source {
+            return None;
+        }
+        open.total += 1;
+        *open.by_source.entry(source).or_default() += 1;
+        Some(Admission {
+            inbound: self.clone(),
+            source,
+        })
+    }
+
+    /// Number of connections being handled
+    pub fn open(&self) -> usize {
+        self.open.lock().total
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut open = self.inbound.open.lock();
+        open.total -= 1;
+        if let Some(n) = open.by_source.get_mut(&self.source) {
+            *n -= 1;
+            if *n == 0 {
+                open.by_source.remove(&self.source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbound_limits() {
+        let inbound = Arc::new(Inbound::new(3, 2));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = inbound.admit(a).unwrap();
+        let _second = inbound.admit(a).unwrap();
+        assert!(inbound.admit(a).is_none());
+        let _third = inbound.admit(b).unwrap();
+        assert!(inbound.admit(b).is_none());
+        assert_eq!(inbound.open(), 3);
+
+        // Finished connections make room
+        drop(first);
+        assert!(inbound.admit(a).is_some());
+    }
+}
@@ -2,27 +2,365 @@
 #![allow(unused_variables)]
 #![allow(unused_imports)]
 
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod alias;
+pub mod batch;
+pub mod budget;
+pub mod clock;
+#[cfg(feature = "tools")]
+pub mod conformance;
+#[cfg(feature = "tools")]
+pub mod crawler;
+#[cfg(feature = "tools")]
+pub mod decode;
+pub mod dial;
+#[cfg(feature = "tools")]
+pub mod doctor;
+pub mod event;
+pub mod friends;
+pub mod greylist;
+pub mod group;
+pub mod hooks;
+pub mod identity;
+pub mod inbound;
+pub mod lifecycle;
+pub mod message;
+pub mod metrics;
+pub mod noise;
 pub mod peer;
+pub mod peerstore;
+pub mod prelude;
 pub mod protocol;
+pub mod record;
+pub mod region;
+pub mod resolve;
+pub mod routing;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod score;
+pub mod seen;
+#[cfg(feature = "tools")]
+pub mod spec;
+pub mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "tools")]
+pub mod topology;
+pub mod trace;
 pub mod transport;
 pub mod util;
 
+pub use peer::{Peer, PeerId};
+pub use protocol::{Request, Response};
+
+/// Traits that only this crate may implement, so methods can be added to
+/// them without breaking downstream code
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for crate::peer::Peer {}
+}
+
+/// Version of the wire protocol and the peer cache format. Peers on
+/// different versions fail the handshake rather than misread each other
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Version of the on-disk store layout. Stores marked with a newer one are
+/// refused, and older ones have to be migrated first
+pub const STORE_LAYOUT: u32 = 1;
+
+/// The name and version of this implementation, reported to other peers
+pub const AGENT: &str = concat!("harbor/", env!("CARGO_PKG_VERSION"));
+
 /// Maximum number of peers on the network
 pub const MAX_PEERS: u8 = 32;
 
+/// Maximum number of peers from the same public /24 allowed in the PeerStore,
+/// so a single network cannot crowd out everyone else
+pub const MAX_PEERS_PER_SUBNET: usize = 4;
+
 /// Path to local file to read bootstrap PeerId's from
 pub const BOOTSTRAP_FILE: &str = "bootstrap.txt";
 
-use crate::peer::PeerId;
+/// Path to local file to read anchor PeerId's from. Anchors are never
+/// evicted from the PeerStore
+pub const ANCHOR_FILE: &str = "anchors.txt";
+
+/// Directory each named profile keeps its own identity, store and lists in,
+/// under `<PROFILE_DIR>/<name>/`
+pub const PROFILE_DIR: &str = "profiles";
+
+/// File in a profile's directory holding `HARBOR_*=value` lines, the
+/// settings that profile runs with unless the environment overrides them
+pub const PROFILE_CONFIG_FILE: &str = "config";
+
+/// Path to local file holding this node's secret identity key
+pub const IDENTITY_FILE: &str = "identity.key";
+
+/// File, next to the identity file, holding the token callers of the admin
+/// endpoint have to present
+pub const ADMIN_TOKEN_FILE: &str = "admin.token";
+
+/// Path to local file the CLI keeps key aliases in
+pub const ALIAS_FILE: &str = "aliases.txt";
+
+/// Path to local file listing the identities this node trusts
+pub const FRIENDS_FILE: &str = "friends.txt";
+
+/// Most messages held for any one friend until they come to collect them
+pub const MAX_HELD_MESSAGES: usize = 64;
+
+/// Longest status a peer may advertise to its friends, in bytes
+pub const MAX_STATUS_LEN: usize = 256;
+
+/// How often to re-verify anchor peers while running
+pub const ANCHOR_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Path to local file the most recently seen peers are saved to, and
+/// bootstrapped from on the next start
+pub const PEER_CACHE_FILE: &str = "peers.cache";
+
+/// File in a store directory holding the layout version it is in
+pub const STORE_LAYOUT_FILE: &str = "LAYOUT";
+
+/// Maximum number of peers to save to the peer cache
+pub const PEER_CACHE_SIZE: usize = 16;
+
+/// How often to save the peer cache while running
+pub const PEER_CACHE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often to check that this peer can still reach the network, and to
+/// ping any peers that are due a ping
+pub const HEALTH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Shortest interval between pings to a peer, used for new or flaky peers
+pub const MIN_PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Longest interval between pings to a peer, reached by long-lived
+/// reliable peers
+pub const MAX_PING_INTERVAL: Duration = Duration::from_secs(900);
+
+/// Longest to wait between attempts to rejoin the network
+pub const MAX_REJOIN_BACKOFF: Duration = Duration::from_secs(600);
+
+/// How long to hold small requests for a peer before sending them as a batch
+pub const BATCH_WINDOW: Duration = Duration::from_millis(50);
+
+/// Maximum number of requests in a single batch
+pub const MAX_BATCH_LEN: usize = 32;
+
+/// Default time a request handler may take before it is logged as slow
+pub const HANDLER_BUDGET: Duration = Duration::from_secs(2);
+
+/// Most hops a flooded request (QueryKey, SyncPeers) may ask to travel.
+/// Requests asking for more are dropped and count against the sender
+pub const MAX_TTS: u16 = 8;
+
+/// Number of recently handled flooded requests remembered, so ones that
+/// loop back are dropped
+pub const SEEN_CACHE_SIZE: usize = 4096;
+
+/// How long a handled flooded request is remembered
+pub const SEEN_CACHE_TTL: Duration = Duration::from_secs(120);
+
+/// Strikes (failed verifications, or dropping off and coming back) that
+/// get a peer greylisted
+pub const GREYLIST_STRIKES: u32 = 3;
+
+/// How long a greylisted peer is ignored, and how long a strike counts
+pub const GREYLIST_COOLDOWN: Duration = Duration::from_secs(600);
+
+/// Most peers sent back in one PeerStore response, so it fits in a single
+/// transfer. The most recently seen peers are sent
+pub const MAX_PEERSTORE_RESPONSE: usize = 32;
+
+/// Number of peers a QueryKey that can't be answered locally is
+/// forwarded to
+pub const QUERY_FANOUT: usize = 3;
+
+/// Most peers kept in each k-bucket, and how many peers a FindNode
+/// answer and a lookup return
+pub const K_BUCKET_SIZE: usize = 8;
+
+/// How many peers a lookup queries at once
+pub const LOOKUP_PARALLELISM: usize = 3;
+
+/// How long a forwarded QueryKey waits on each hop still to go
+pub const QUERY_HOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most peers learned from another peer's PeerStore that are checked and
+/// added at once. The rest are dropped, and may come up again next sync
+pub const VERIFY_SAMPLE: usize = 8;
+
+/// Peers another peer last saw longer ago than this, by the network's
+/// clock, are too stale to be worth learning
+pub const MAX_GOSSIP_AGE: Duration = Duration::from_secs(3600);
+
+/// How long a request handler waits for the PeerStore lock before giving up
+pub const PEER_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default number of pending connections the listening socket queues
+pub const LISTEN_BACKLOG: i32 = 128;
+
+/// How long a client has to send the length of its request frame
+pub const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Slowest a client may send the rest of its request frame, in bytes per
+/// second. Clients trickling bytes slower than this are cut off
+pub const MIN_READ_RATE: u64 = 16 << 10;
+
+/// Default most bytes held for requests and responses in flight
+pub const MEMORY_BUDGET: usize = 64 << 20;
+
+/// Default most incoming connections handled at once
+pub const MAX_INBOUND: usize = 256;
+
+/// Default most incoming connections handled at once from one address
+pub const MAX_INBOUND_PER_SOURCE: usize = 16;
+
+/// How long to back off after failing to accept a connection, so running
+/// out of file descriptors doesn't spin the accept loop
+pub const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Path to local file snapshots of this peer's metrics are appended to
+pub const METRICS_FILE: &str = "metrics.history";
+
+/// How often to snapshot this peer's metrics
+pub const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of recent clock samples the skew estimate is taken from
+pub const CLOCK_SAMPLES: usize = 16;
+
+/// How far this machine's clock may drift from the network's before the
+/// operator is warned
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// How long a resolved hostname is cached when the resolver doesn't say
+pub const DEFAULT_DNS_TTL: Duration = Duration::from_secs(300);
+
+/// How long to wait on one address of a peer before also trying the next
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// How long to wait on a peer when dialing it before giving up
+pub const DIAL_TIMEOUT: Duration = Duration::from_secs(3);
+
 use serde::{Deserialize, Serialize};
-use std::{error::Error as StdError, fmt};
+use std::{error::Error as StdError, fmt, time::Duration};
 
 /// Some general error that happened on the network
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+#[non_exhaustive]
 pub enum NetworkError {
     Fail(String),
     NoRoute(PeerId),
     DeadPeer(PeerId),
+    /// An io error talking to a peer. The source is only kept locally,
+    /// it is not sent over the wire
+    Io(String, #[serde(skip)] Option<std::io::Error>),
+    /// A message that could not be encoded or decoded
+    Codec(String, #[serde(skip)] Option<bincode::Error>),
+    /// A peer turned a request away, and says why
+    Rejected(Reason, String),
+}
+
+/// Why a peer turned a request away, as a code the requester can act on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+#[non_exhaustive]
+pub enum Reason {
+    /// The request could not be decoded
+    Malformed,
+    /// The request's frame is over MAX_TRANSFER_SIZE
+    TooLarge,
+    /// The request took too long to arrive, or to handle
+    TooSlow,
+    /// The requester may not make this request
+    Unauthorized,
+    /// The peer has no room for the request right now
+    Overloaded,
+    /// The request asks to be flooded further than MAX_TTS hops
+    TooManyHops,
+    /// The request already arrived by another path
+    Duplicate,
+    /// This peer doesn't handle this kind of request
+    Unsupported,
+    /// Something went wrong on the peer's side while handling the request
+    Internal,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match self {
+            Reason::Malformed => "malformed",
+            Reason::TooLarge => "too_large",
+            Reason::TooSlow => "too_slow",
+            Reason::Unauthorized => "unauthorized",
+            Reason::Overloaded => "overloaded",
+            Reason::TooManyHops => "too_many_hops",
+            Reason::Duplicate => "duplicate",
+            Reason::Unsupported => "unsupported",
+            Reason::Internal => "internal",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+impl NetworkError {
+    /// Whether the same request might succeed if tried again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NetworkError::NoRoute(_) => true,
+            NetworkError::Io(_, Some(e)) => io_retryable(e),
+            NetworkError::Rejected(reason, _) => *reason == Reason::Overloaded,
+            _ => false,
+        }
+    }
+
+    /// Why the requester should be told its request was turned away, if
+    /// this error is a reason to turn it away. Rejections raised while
+    /// reading a request arrive wrapped in io errors
+    pub fn rejection(&self) -> Option<(Reason, String)> {
+        match self {
+            NetworkError::Rejected(reason, detail) => Some((*reason, detail.clone())),
+            NetworkError::Codec(msg, _) => Some((Reason::Malformed, msg.clone())),
+            NetworkError::Io(_, Some(e)) => e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<NetworkError>())
+                .and_then(NetworkError::rejection),
+            _ => None,
+        }
+    }
+
+    /// Whether this node cannot carry on networking without intervention
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            NetworkError::Io(_, Some(e)) => io_fatal(e),
+            _ => false,
+        }
+    }
+}
+
+fn io_retryable(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        TimedOut
+            | WouldBlock
+            | Interrupted
+            | ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | NotConnected
+            | BrokenPipe
+            | UnexpectedEof
+    )
+}
+
+fn io_fatal(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(), AddrInUse | AddrNotAvailable | PermissionDenied)
 }
 
 impl fmt::Display for NetworkError {
@@ -31,6 +369,11 @@ impl fmt::Display for NetworkError {
             NetworkError::Fail(msg) => write!(f, "{}", msg),
             NetworkError::NoRoute(id) => write!(f, "could not route to {:?}", id),
             NetworkError::DeadPeer(p) => write!(f, "Peer {:?} is no longer alive", p),
+            NetworkError::Io(msg, _) => write!(f, "{}", msg),
+            NetworkError::Codec(msg, _) => write!(f, "bad message: {}", msg),
+            NetworkError::Rejected(reason, msg) => {
+                write!(f, "rejected ({}): {}", reason, msg)
+            }
         }
     }
 }
@@ -41,30 +384,62 @@ impl StdError for NetworkError {
             NetworkError::Fail(_) => None,
             NetworkError::NoRoute(_) => None,
             NetworkError::DeadPeer(_) => None,
+            NetworkError::Io(_, e) => e.as_ref().map(|e| e as _),
+            NetworkError::Codec(_, e) => e.as_ref().map(|e| e as _),
+            NetworkError::Rejected(..) => None,
         }
     }
 }
 
 impl From<std::io::Error> for NetworkError {
     fn from(err: std::io::Error) -> NetworkError {
-        NetworkError::Fail(err.to_string())
+        NetworkError::Io(err.to_string(), Some(err))
     }
 }
 
 impl From<bincode::Error> for NetworkError {
     fn from(err: bincode::Error) -> NetworkError {
-        NetworkError::Fail(err.to_string())
+        NetworkError::Codec(err.to_string(), Some(err))
     }
 }
 
 /// The general crate error
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     NoIp,
     Ipv6Disabled(std::net::Ipv6Addr),
     IoError(std::io::Error),
     BinaryError(bincode::Error),
     NetworkError(NetworkError),
+    InvalidPeerId(String),
+    BadBootstrapLine(usize, String),
+    Decode(String),
+    BadIdentity(String),
+}
+
+impl Error {
+    /// Whether the operation that failed might succeed if tried again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::IoError(e) => io_retryable(e),
+            Error::NetworkError(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether this node cannot carry on without intervention, like a bad
+    /// config or a port it cannot bind
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::NoIp | Error::Ipv6Disabled(_) => true,
+            Error::InvalidPeerId(_) | Error::BadBootstrapLine(_, _) => true,
+            Error::BadIdentity(_) => true,
+            Error::IoError(e) => io_fatal(e),
+            Error::NetworkError(e) => e.is_fatal(),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -79,6 +454,12 @@ impl fmt::Display for Error {
             Error::IoError(e) => write!(f, "{:?}", e),
             Error::BinaryError(e) => write!(f, "{:?}", e),
             Error::NetworkError(e) => write!(f, "{:?}", e),
+            Error::InvalidPeerId(s) => write!(f, "invalid peer id '{}'", s),
+            Error::BadBootstrapLine(n, line) => {
+                write!(f, "bad bootstrap entry on line {}: '{}'", n, line)
+            }
+            Error::Decode(msg) => write!(f, "could not decode: {}", msg),
+            Error::BadIdentity(msg) => write!(f, "bad identity: {}", msg),
         }
     }
 }
@@ -91,6 +472,10 @@ impl StdError for Error {
             Error::IoError(ref e) => Some(e),
             Error::BinaryError(ref e) => Some(e),
             Error::NetworkError(ref e) => Some(e),
+            Error::InvalidPeerId(_) => None,
+            Error::BadBootstrapLine(_, _) => None,
+            Error::Decode(_) => None,
+            Error::BadIdentity(_) => None,
         }
     }
 }
@@ -112,3 +497,25 @@ impl From<NetworkError> for Error {
         Error::NetworkError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_error_class() {
+        let timeout = NetworkError::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(timeout.is_retryable() && !timeout.is_fatal());
+        assert!(timeout.source().is_some());
+
+        // The io source does not survive the wire
+        let sent: NetworkError =
+            bincode::deserialize(&bincode::serialize(&timeout).unwrap()).unwrap();
+        assert!(!sent.is_retryable() && sent.source().is_none());
+
+        let in_use = Error::from(io::Error::from(io::ErrorKind::AddrInUse));
+        assert!(in_use.is_fatal() && !in_use.is_retryable());
+        assert!(Error::InvalidPeerId("x".to_string()).is_fatal());
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The phases a peer goes through, in order. A peer only ever moves
+/// forward through them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub enum State {
+    /// Constructed, but not started
+    Initializing,
+
+    /// Dialing bootstrap, anchor and cached peers
+    Bootstrapping,
+
+    /// Bound to its socket, starting background tasks
+    Listening,
+
+    /// Handling requests
+    Ready,
+
+    /// Asked to stop. No new connections are handled
+    Draining,
+
+    /// Finished, with the peer cache saved
+    Stopped,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::Initializing => "initializing",
+            State::Bootstrapping => "bootstrapping",
+            State::Listening => "listening",
+            State::Ready => "ready",
+            State::Draining => "draining",
+            State::Stopped => "stopped",
+        };
+        write!(f, "{name}")
+    }
+}
@@ -1,27 +1,562 @@
-use harbor::peer;
-use std::{env, error::Error};
+use harbor::{
+    admin::AdminServer,
+    alias::Aliases,
+    conformance,
+    crawler::CrawlReport,
+    decode, doctor,
+    event::Event,
+    friends::Friends,
+    identity::Identity,
+    message::Message,
+    metrics,
+    peer::{self, Key, Peer, PeerId},
+    protocol::{Request, Response},
+    record,
+    resolve::{CachingResolver, DnsServer},
+    spec,
+    store::{self, Store},
+    topology::Topology,
+    transport::Transport,
+    util, ADMIN_TOKEN_FILE, ALIAS_FILE, BOOTSTRAP_FILE, DIAL_TIMEOUT, FRIENDS_FILE,
+    IDENTITY_FILE, METRICS_FILE, PROFILE_CONFIG_FILE, PROFILE_DIR, STORE_LAYOUT,
+};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+/// Where a file a node keeps between runs lives: in the directory of the
+/// profile HARBOR_PROFILE names, or else in the working directory
+fn profile_path(file: &str) -> PathBuf {
+    match env::var("HARBOR_PROFILE") {
+        Ok(name) => Path::new(PROFILE_DIR).join(name).join(file),
+        Err(_) => PathBuf::from(file),
+    }
+}
+
+/// Take `--profile <name>` out of the arguments, wherever it is, and run as
+/// that profile, or the one HARBOR_PROFILE names, with the settings from its
+/// config file. Names are plain directory names, so they stay in PROFILE_DIR
+fn take_profile(args: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    if let Some(i) = args.iter().position(|a| a == "--profile") {
+        let name = args.get(i + 1).ok_or("usage: --profile <name>")?.clone();
+        args.drain(i..i + 2);
+        env::set_var("HARBOR_PROFILE", name);
+    }
+
+    if let Ok(name) = env::var("HARBOR_PROFILE") {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(format!("bad profile name {name:?}").into());
+        }
+        fs::create_dir_all(profile_path(""))?;
+        if let Ok(lines) = util::read_lines(profile_path(PROFILE_CONFIG_FILE)) {
+            for line in lines.map_while(Result::ok) {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.split_once('=') {
+                    Some((var, value)) if var.trim().starts_with("HARBOR_") => {
+                        if env::var_os(var.trim()).is_none() {
+                            env::set_var(var.trim(), value.trim());
+                        }
+                    }
+                    _ => return Err(format!("bad profile setting {line:?}").into()),
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
 fn peer(port: u16) -> Result<(), Box<dyn Error>> {
-    let peer = peer::Peer::new(true, port)?;
+    // Record every frame of this session for debugging
+    if let Ok(path) = env::var("HARBOR_RECORD") {
+        record::start_recording(path)?;
+    }
+
+    let mut peer = peer::Peer::new(true, port)?;
+
+    // A profile keeps everything this node remembers in its own directory,
+    // and bootstraps from its own list, so it joins its own network. New
+    // profiles start from the working directory's list
+    if env::var("HARBOR_PROFILE").is_ok() {
+        let dir = profile_path("");
+        let bootstrap = dir.join(BOOTSTRAP_FILE);
+        if !bootstrap.exists() && Path::new(BOOTSTRAP_FILE).exists() {
+            fs::copy(BOOTSTRAP_FILE, bootstrap)?;
+        }
+        peer.set_data_dir(&dir);
+    }
+
+    // Keep the same identity across restarts
+    let path = identity_file();
+    peer.set_identity(Identity::load_or_generate(&path)?);
+
+    // Take messages from friends, and print them and friends coming
+    // online as they arrive. HARBOR_STATUS is the status friends see
+    let friends = Friends::load(profile_path(FRIENDS_FILE))?;
+    peer.set_friends(friends.clone());
+    if let Ok(status) = env::var("HARBOR_STATUS") {
+        peer.set_status(&status);
+    }
+    let events = peer.subscribe();
+    thread::spawn(move || {
+        let name = |key: &_| friends.name_of(key).map_or(key.to_string(), str::to_string);
+        for event in events {
+            match event {
+                Event::Message { from, body, .. } => {
+                    println!("{}: {}", name(&from), String::from_utf8_lossy(&body))
+                }
+                Event::FriendOnline { from, status } => {
+                    println!("{} is online: {status}", name(&from))
+                }
+                _ => (),
+            }
+        }
+    });
+
+    // Keep stored values on disk across restarts, instead of in memory.
+    // Profiles always do
+    if let Some(dir) = store_dir() {
+        peer.set_store(Store::open(dir)?);
+    }
+
+    // Fetch from providers nearby first, placing them with a local GeoIP
+    // database
+    #[cfg(feature = "geoip")]
+    if let Ok(path) = env::var("HARBOR_GEOIP") {
+        peer.set_locator(Arc::new(harbor::region::GeoIp::open(path)?));
+    }
+
+    // Resolve bootstrap hostnames with a specific DNS server
+    if let Ok(server) = env::var("HARBOR_DNS") {
+        let server = DnsServer(server.parse()?);
+        peer.set_resolver(Arc::new(CachingResolver::new(server)));
+    }
+
+    // Serve the admin endpoint for scripts, to those who can read the
+    // token kept next to the identity
+    if let Ok(port) = env::var("HARBOR_ADMIN_PORT") {
+        let token = Path::new(&path).with_file_name(ADMIN_TOKEN_FILE);
+        AdminServer::start(peer.clone(), port.parse()?, alias_file(), token)?;
+    }
+
+    // If bootstrap peer, don't send pings. Nodes serving the control API
+    // leave pinging to the tools driving them
+    let send_pings = port != 3300;
+    #[cfg(feature = "rpc")]
+    let send_pings = send_pings && !rpc(&peer)?;
+    peer.start(send_pings)?;
+
+    Ok(())
+}
 
-    // If bootstrap peer, don't send pings
-    if port == 3300 {
-        peer.start(false)?;
+/// Serve the control API on localhost if HARBOR_RPC_PORT is set. Returns
+/// whether it is served. Profiles set their own port in their config, so
+/// tools pick a profile by the port they connect to
+#[cfg(feature = "rpc")]
+fn rpc(peer: &Peer) -> Result<bool, Box<dyn Error>> {
+    match env::var("HARBOR_RPC_PORT") {
+        Ok(port) => {
+            harbor::rpc::RpcServer::start(peer.clone(), port.parse()?, alias_file())?;
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+/// `harbor peers export <ip:port> [--multiaddr]`
+/// Print a running node's live peers in the bootstrap file format
+fn peers(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args.first().map(String::as_str) {
+        Some("export") => {
+            let node = args.get(1).ok_or("provide the node to export from")?;
+            let node = node.parse::<PeerId>()?;
+            let multiaddr = args.iter().any(|a| a == "--multiaddr");
+
+            let mut conn =
+                Peer::send_request_timeout(&node, Request::LivePeers, DIAL_TIMEOUT)?;
+            match Peer::recv_response(&mut conn)? {
+                Response::LivePeers(ids) => {
+                    peer::write_bootstrap(&mut io::stdout(), ids.iter(), multiaddr)?;
+                    Ok(())
+                }
+                res => Err(format!("unexpected response {res:?}").into()),
+            }
+        }
+        _ => Err("usage: harbor peers export <ip:port> [--multiaddr]".into()),
+    }
+}
+
+/// `harbor doctor [port]`
+/// Check that this machine can run a peer, and explain what to fix if not
+fn doctor(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let port = match args.first() {
+        Some(port) => port.parse::<u16>()?,
+        None => 3300,
+    };
+
+    let checks = doctor::run(port);
+    for check in checks.iter() {
+        println!("{check}");
+    }
+    if checks.iter().all(|c| c.passed) {
+        Ok(())
     } else {
-        peer.start(true)?;
+        Err("some checks failed".into())
+    }
+}
+
+/// `harbor stats <ip:port>` or `harbor stats --history [file]`
+/// Print a running node's traffic broken down by class, or the metrics
+/// history saved by the node run from this directory or profile
+fn stats(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor stats <ip:port> | harbor stats --history [file]";
+    let node = args.first().ok_or(usage)?;
+    if node == "--history" {
+        let path = args
+            .get(1)
+            .map_or_else(|| profile_path(METRICS_FILE), PathBuf::from);
+        let history = metrics::load_history(&path)
+            .map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        print!("{}", metrics::History(&history));
+        return Ok(());
+    }
+    let node = node.parse::<PeerId>()?;
+
+    let mut conn = Peer::send_request_timeout(&node, Request::Stats, DIAL_TIMEOUT)?;
+    match Peer::recv_response(&mut conn)? {
+        Response::Stats(stats) => {
+            print!("{stats}");
+            Ok(())
+        }
+        res => Err(format!("unexpected response {res:?}").into()),
     }
+}
 
+/// `harbor topology <ip:port> [--format dot|json] [--depth n]`
+/// Crawl the network from a node and print the connection graph
+fn topology(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor topology <ip:port> [--format dot|json] [--depth n]";
+    let node = args.first().ok_or(usage)?.parse::<PeerId>()?;
+    let flag = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+    };
+    let depth = match flag("--depth") {
+        Some(depth) => depth.parse::<usize>()?,
+        None => 2,
+    };
+
+    let topology = Topology::crawl(&node, depth);
+    match flag("--format").map(String::as_str) {
+        None | Some("dot") => print!("{}", topology.to_dot()),
+        Some("json") => println!("{}", serde_json::to_string_pretty(&topology)?),
+        Some(_) => return Err(usage.into()),
+    }
+    Ok(())
+}
+
+/// `harbor crawl <ip:port> [--format text|json] [--depth n]`
+/// Walk the network from a node and report what the reachable peers say
+/// about themselves
+fn crawl(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor crawl <ip:port> [--format text|json] [--depth n]";
+    let node = args.first().ok_or(usage)?.parse::<PeerId>()?;
+    let flag = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+    };
+    let depth = match flag("--depth") {
+        Some(depth) => depth.parse::<usize>()?,
+        None => 8,
+    };
+
+    let report = CrawlReport::crawl(&node, depth);
+    match flag("--format").map(String::as_str) {
+        None | Some("text") => print!("{report}"),
+        Some("json") => println!("{}", serde_json::to_string_pretty(&report)?),
+        Some(_) => return Err(usage.into()),
+    }
+    Ok(())
+}
+
+/// The store a node keeps on disk: the one HARBOR_STORE names, or its
+/// profile's. Nodes without either keep values in memory
+fn store_dir() -> Option<String> {
+    env::var("HARBOR_STORE").ok().or_else(|| {
+        env::var("HARBOR_PROFILE")
+            .ok()
+            .map(|_| profile_path("store").display().to_string())
+    })
+}
+
+/// `harbor fsck [dir]`
+/// Check a node's store, defaulting to the one HARBOR_STORE or the profile
+/// names, and clear out values a crash left half written
+fn fsck(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let dir = match args.first() {
+        Some(dir) => dir.clone(),
+        None => store_dir().ok_or("usage: harbor fsck <dir>")?,
+    };
+    let report = store::fsck(&dir)?;
+    for path in report.removed.iter() {
+        println!("removed half-written {}", path.display());
+    }
+    for path in report.unknown.iter() {
+        println!("skipped {}, which is not a stored value", path.display());
+    }
+    for (key, e) in report.unreadable.iter() {
+        println!("could not read {key}: {e}");
+    }
+    println!("{} values, {} bytes", report.values, report.bytes);
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(format!("{} values could not be read", report.unreadable.len()).into())
+    }
+}
+
+/// `harbor migrate-store [dir]`
+/// Bring a node's store, defaulting to the one HARBOR_STORE or the profile
+/// names, up to the layout this build uses
+fn migrate_store(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let dir = match args.first() {
+        Some(dir) => dir.clone(),
+        None => store_dir().ok_or("usage: harbor migrate-store <dir>")?,
+    };
+    match store::migrate(&dir)? {
+        STORE_LAYOUT => println!("{dir} is already in store layout {STORE_LAYOUT}"),
+        from => println!("migrated {dir} from store layout {from} to {STORE_LAYOUT}"),
+    }
+    Ok(())
+}
+
+/// `harbor conformance <ip:port>`
+/// Check that a running node speaks the protocol correctly
+fn conformance(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let node = args.first().ok_or("usage: harbor conformance <ip:port>")?;
+    let node = node.parse::<PeerId>()?;
+
+    let checks = conformance::run(&node);
+    for check in checks.iter() {
+        println!("{check}");
+    }
+    let failed = checks.iter().filter(|c| !c.passed).count();
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(format!("{failed} of {} checks failed", checks.len()).into())
+    }
+}
+
+/// `harbor decode <file|->`
+/// Pretty-print the harbor frames in a session recorded with
+/// HARBOR_RECORD, a pcap capture or a hexdump. Frames captured off the
+/// wire are encrypted, so only their sizes are shown
+fn decode(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let data = match args.first().map(String::as_str) {
+        Some("-") => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            data
+        }
+        Some(path) => fs::read(path)?,
+        None => {
+            return Err(
+                "usage: harbor decode <recorded session, pcap or hexdump file, or - for stdin>"
+                    .into(),
+            )
+        }
+    };
+
+    if let Ok(segments) = decode::parse_pcap(&data) {
+        for seg in segments {
+            let (src, dst) = (seg.src, seg.dst);
+            println!("{}:{} -> {}:{}", src.0, src.1, dst.0, dst.1);
+            println!("{}\n", decode::decode_frame(&seg.payload));
+        }
+        return Ok(());
+    }
+    match record::Session::read(&data[..]) {
+        Ok(session) if !session.frames.is_empty() => {
+            for frame in session.frames {
+                println!("{:?} {:?} at {}", frame.direction, frame.kind, frame.at);
+                println!("{}\n", decode::decode_frame(&frame.bytes));
+            }
+        }
+        _ => {
+            let bytes = decode::parse_hex(&String::from_utf8(data)?)?;
+            println!("{}", decode::decode_frame(&bytes));
+        }
+    }
+    Ok(())
+}
+
+/// Where the identity is kept. Set HARBOR_IDENTITY to run several nodes
+/// from one directory
+fn identity_file() -> PathBuf {
+    env::var("HARBOR_IDENTITY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| profile_path(IDENTITY_FILE))
+}
+
+/// Where aliases are kept. Set HARBOR_ALIASES to keep separate sets; each
+/// profile also keeps its own
+fn alias_file() -> String {
+    env::var("HARBOR_ALIASES")
+        .unwrap_or_else(|_| profile_path(ALIAS_FILE).display().to_string())
+}
+
+/// `harbor alias add <name> <key> | rm <name> | list`
+/// Manage local pet names for keys, usable wherever a key is
+fn alias(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor alias add <name> <key> | harbor alias rm <name> | harbor alias list";
+    let path = alias_file();
+    let mut aliases = Aliases::load(&path)?;
+    let arg = |i: usize| args.get(i).map(String::as_str).ok_or(usage);
+    match arg(0)? {
+        "add" => {
+            let (name, key) = (arg(1)?, aliases.resolve(arg(2)?));
+            if let Some(old) = aliases.add(name, key)? {
+                println!("{name} was {old}");
+            }
+        }
+        "rm" => {
+            let name = arg(1)?;
+            aliases.remove(name).ok_or(format!("no alias {name}"))?;
+        }
+        "list" => {
+            print!("{aliases}");
+            return Ok(());
+        }
+        _ => return Err(usage.into()),
+    }
+    aliases.save(&path)?;
+    Ok(())
+}
+
+/// `harbor friend add <name> <key> | rm <name> | list | me`
+/// Manage the identities this node takes messages from, or print the key
+/// to give friends
+fn friend(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor friend add <name> <key> | harbor friend rm <name> | harbor friend list | harbor friend me";
+    let path = profile_path(FRIENDS_FILE);
+    let mut friends = Friends::load(&path)?;
+    let arg = |i: usize| args.get(i).map(String::as_str).ok_or(usage);
+    match arg(0)? {
+        "add" => {
+            let (name, key) = (arg(1)?, arg(2)?.parse()?);
+            if let Some(old) = friends.add(name, key)? {
+                println!("{name} was {old}");
+            }
+        }
+        "rm" => {
+            let name = arg(1)?;
+            friends.remove(name).ok_or(format!("no friend {name}"))?;
+        }
+        "list" => {
+            print!("{friends}");
+            return Ok(());
+        }
+        "me" => {
+            let identity = Identity::load_or_generate(identity_file())?;
+            println!("{}", identity.public_key());
+            return Ok(());
+        }
+        _ => return Err(usage.into()),
+    }
+    friends.save(&path)?;
+    Ok(())
+}
+
+/// `harbor message <ip:port> <friend> <text>`
+/// Send a friend a message, through their node or a node that is friends
+/// with both of you, which holds it until they collect it
+fn message(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor message <ip:port> <friend> <text>";
+    let via = args.first().ok_or(usage)?.parse::<PeerId>()?;
+    let name = args.get(1).ok_or(usage)?;
+    let text = args
+        .get(2..)
+        .filter(|words| !words.is_empty())
+        .ok_or(usage)?;
+    let friends = Friends::load(profile_path(FRIENDS_FILE))?;
+    let to = friends.get(name).ok_or(format!("no friend {name}"))?;
+    let identity = Identity::load_or_generate(identity_file())?;
+
+    let message = Message::seal(&identity, to, text.join(" ").as_bytes())?;
+    let mut conn =
+        Peer::send_request_timeout(&via, Request::Message(message), DIAL_TIMEOUT)?;
+    match Peer::recv_response(&mut conn)? {
+        Response::Ok => Ok(()),
+        res => Err(format!("unexpected response {res:?}").into()),
+    }
+}
+
+/// `harbor get <ip:port> <key or alias>`
+/// Fetch a value from a running node and write it to stdout
+fn get(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "usage: harbor get <ip:port> <key or alias>";
+    let node = args.first().ok_or(usage)?.parse::<PeerId>()?;
+    let key: Key = Aliases::load(alias_file())?.resolve(args.get(1).ok_or(usage)?);
+
+    let mut conn = Peer::send_request_timeout(&node, Request::Get(key), DIAL_TIMEOUT)?;
+    match Peer::recv_response(&mut conn)? {
+        Response::Value(value) => Ok(io::stdout().write_all(&value)?),
+        res => Err(format!("unexpected response {res:?}").into()),
+    }
+}
+
+/// `harbor spec`
+/// Print a machine-readable description of the protocol as JSON
+fn spec() -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(&spec::generate())?);
+    Ok(())
+}
+
+/// Use TLS instead of Noise, for this node and the tools, when given a
+/// certificate, its key, and the roots peers' certificates chain up to
+#[cfg(feature = "tls")]
+fn tls() -> Result<(), Box<dyn Error>> {
+    let paths = ["HARBOR_TLS_CERT", "HARBOR_TLS_KEY", "HARBOR_TLS_ROOTS"].map(env::var);
+    if let [Ok(cert), Ok(key), Ok(roots)] = paths {
+        harbor::tls::enable(harbor::tls::TlsConfig::load(cert, key, roots)?);
+    }
     Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+    #[cfg(feature = "tls")]
+    tls()?;
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        let port = args[1].parse::<u16>()?;
-        return peer(port);
-    } else {
-        panic!("provide a port");
+    let mut args: Vec<String> = env::args().collect();
+    take_profile(&mut args)?;
+    match args.get(1).map(String::as_str) {
+        Some("peers") => peers(&args[2..]),
+        Some("doctor") => doctor(&args[2..]),
+        Some("stats") => stats(&args[2..]),
+        Some("topology") => topology(&args[2..]),
+        Some("crawl") => crawl(&args[2..]),
+        Some("conformance") => conformance(&args[2..]),
+        Some("fsck") => fsck(&args[2..]),
+        Some("migrate-store") => migrate_store(&args[2..]),
+        Some("decode") => decode(&args[2..]),
+        Some("spec") => spec(),
+        Some("alias") => alias(&args[2..]),
+        Some("friend") => friend(&args[2..]),
+        Some("message") => message(&args[2..]),
+        Some("get") => get(&args[2..]),
+        Some(port) => peer(port.parse::<u16>()?),
+        None => panic!("provide a port"),
     }
 }
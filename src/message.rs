@@ -0,0 +1,132 @@
+use crate::{
+    clock,
+    identity::{Identity, PublicKey},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use snow::Builder;
+use std::{convert::TryFrom, io};
+
+/// The Noise pattern message bodies are encrypted with. N is one way, to a
+/// recipient whose key the sender already knows, which is all a message
+/// that may sit at a relay until the recipient comes online can use
+pub const MESSAGE_PATTERN: &str = "Noise_N_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest body a message can carry: one Noise message, less the sender's
+/// ephemeral key and the authentication tag
+pub const MAX_MESSAGE_BODY: usize = 65535 - 32 - 16;
+
+/// A direct message from one identity to another. The body is encrypted to
+/// the recipient's key and the whole is signed by the sender, so a relay
+/// holding it can check who it is from and to, but can't read or alter it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct Message {
+    pub from: PublicKey,
+    pub to: PublicKey,
+
+    /// When it was sent, in milliseconds since the epoch by the sender's
+    /// clock
+    pub sent: i64,
+
+    /// The body, encrypted to `to`
+    pub body: Vec<u8>,
+
+    /// The sender's signature over all of the above
+    pub sig: Vec<u8>,
+}
+
+impl Message {
+    /// Encrypt `body` to `to` and sign it as `from`
+    pub fn seal(from: &Identity, to: &PublicKey, body: &[u8]) -> Result<Self, Error> {
+        if body.len() > MAX_MESSAGE_BODY {
+            return Err(Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message body is over {MAX_MESSAGE_BODY} bytes"),
+            )));
+        }
+        let recipient = to
+            .to_x25519()
+            .ok_or_else(|| Error::BadIdentity(format!("{to} is not a valid key")))?;
+        let mut hs = Builder::new(MESSAGE_PATTERN.parse().unwrap())
+            .remote_public_key(&recipient)
+            .build_initiator()
+            .map_err(sealing)?;
+        let mut sealed = vec![0; body.len() + 32 + 16];
+        let len = hs.write_message(body, &mut sealed).map_err(sealing)?;
+        sealed.truncate(len);
+
+        let mut message = Self {
+            from: from.public_key(),
+            to: *to,
+            sent: clock::now_millis(),
+            body: sealed,
+            sig: Vec::new(),
+        };
+        message.sig = from.sign(&message.signed()).to_vec();
+        Ok(message)
+    }
+
+    /// Whether the sender named in the message signed it
+    pub fn verify(&self) -> bool {
+        match <[u8; 64]>::try_from(&self.sig[..]) {
+            Ok(sig) => self.from.verify(&self.signed(), &sig),
+            Err(_) => false,
+        }
+    }
+
+    /// Decrypt the body, as the recipient
+    pub fn open(&self, identity: &Identity) -> Result<Vec<u8>, Error> {
+        let mut hs = Builder::new(MESSAGE_PATTERN.parse().unwrap())
+            .local_private_key(&identity.noise_secret())
+            .build_responder()
+            .map_err(sealing)?;
+        let mut body = vec![0; self.body.len()];
+        let len = hs
+            .read_message(&self.body, &mut body)
+            .map_err(|e| Error::Decode(format!("could not decrypt message: {e}")))?;
+        body.truncate(len);
+        Ok(body)
+    }
+
+    /// The bytes the signature covers
+    fn signed(&self) -> Vec<u8> {
+        let mut signed = b"harbor message".to_vec();
+        signed.extend_from_slice(self.from.as_bytes());
+        signed.extend_from_slice(self.to.as_bytes());
+        signed.extend_from_slice(&self.sent.to_be_bytes());
+        signed.extend_from_slice(&self.body);
+        signed
+    }
+}
+
+fn sealing(e: snow::Error) -> Error {
+    Error::IoError(io::Error::other(format!("noise: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let (alice, bob) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+        let message = Message::seal(&alice, &bob.public_key(), b"hi bob").unwrap();
+        assert!(message.verify());
+        assert!(!message.body.windows(6).any(|w| w == b"hi bob"));
+        assert_eq!(message.open(&bob).unwrap(), b"hi bob");
+        assert!(message.open(&alice).is_err());
+
+        // Relays can't swap the body or who it is for
+        let mut forged = message.clone();
+        forged.to = alice.public_key();
+        assert!(!forged.verify());
+        let mut forged = message;
+        forged.body[40] ^= 1;
+        assert!(!forged.verify());
+
+        assert!(
+            Message::seal(&alice, &bob.public_key(), &[0; MAX_MESSAGE_BODY + 1]).is_err()
+        );
+    }
+}
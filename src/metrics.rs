@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, prelude::*},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Broad kinds of traffic, so operators can tell protocol overhead apart
+/// from actually serving content
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+#[non_exhaustive]
+pub enum TrafficClass {
+    /// Liveness, identity and membership messages
+    Control,
+
+    /// Peer and key discovery
+    Gossip,
+
+    /// Listing and transferring stored content
+    Content,
+
+    /// Traffic forwarded on behalf of other peers
+    Relay,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 4] = [
+        TrafficClass::Control,
+        TrafficClass::Gossip,
+        TrafficClass::Content,
+        TrafficClass::Relay,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+static SENT: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+static RECEIVED: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+static ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static ACCEPT_ERRORS: AtomicU64 = AtomicU64::new(0);
+static SHED: AtomicU64 = AtomicU64::new(0);
+
+/// Count the outcome of accepting an incoming connection
+pub fn record_accept(ok: bool) {
+    let counter = if ok { &ACCEPTED } else { &ACCEPT_ERRORS };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an incoming connection turned away for being over the limits
+pub fn record_shed() {
+    SHED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count bytes sent over the network
+pub fn record_sent(class: TrafficClass, bytes: usize) {
+    SENT[class.index()].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Count bytes received from the network
+pub fn record_received(class: TrafficClass, bytes: usize) {
+    RECEIVED[class.index()].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Bytes sent and received for one class of traffic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct ClassTraffic {
+    pub class: TrafficClass,
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Bytes sent and received by this process since it started, broken down
+/// by traffic class, and how accepting connections has gone
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct TrafficStats {
+    pub classes: Vec<ClassTraffic>,
+
+    /// Incoming connections accepted
+    pub accepted: u64,
+
+    /// Failed attempts to accept an incoming connection
+    pub accept_errors: u64,
+
+    /// Incoming connections turned away for being over the inbound limits
+    pub shed: u64,
+}
+
+impl TrafficStats {
+    /// Take a snapshot of the traffic counters
+    pub fn snapshot() -> Self {
+        Self {
+            classes: TrafficClass::ALL
+                .iter()
+                .map(|&class| ClassTraffic {
+                    class,
+                    sent: SENT[class.index()].load(Ordering::Relaxed),
+                    received: RECEIVED[class.index()].load(Ordering::Relaxed),
+                })
+                .collect(),
+            accepted: ACCEPTED.load(Ordering::Relaxed),
+            accept_errors: ACCEPT_ERRORS.load(Ordering::Relaxed),
+            shed: SHED.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The fraction of attempts to accept a connection that failed
+    pub fn accept_error_rate(&self) -> f64 {
+        let attempts = self.accepted + self.accept_errors;
+        if attempts == 0 {
+            return 0.0;
+        }
+        self.accept_errors as f64 / attempts as f64
+    }
+}
+
+impl fmt::Display for TrafficStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<10}{:>14}{:>14}", "class", "sent", "received")?;
+        for t in self.classes.iter() {
+            writeln!(
+                f,
+                "{:<10}{:>14}{:>14}",
+                format!("{:?}", t.class),
+                t.sent,
+                t.received
+            )?;
+        }
+        writeln!(
+            f,
+            "\naccepted {} connections, {} errors ({:.1}%)",
+            self.accepted,
+            self.accept_errors,
+            self.accept_error_rate() * 100.0
+        )?;
+        writeln!(f, "shed {} connections over the inbound limits", self.shed)
+    }
+}
+
+/// The key numbers of a running peer at one moment, saved periodically so
+/// trends can be followed across restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Seconds since the epoch
+    pub at: i64,
+
+    /// Seconds the peer had been up
+    pub uptime: u64,
+
+    /// Number of peers in its PeerStore
+    pub peers: usize,
+
+    /// Total bytes sent and received since the peer started
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Snapshot {
+    /// Take a snapshot of this process's counters
+    pub fn take(uptime: u64, peers: usize) -> Self {
+        let stats = TrafficStats::snapshot();
+        Self {
+            at: chrono::Utc::now().timestamp(),
+            uptime,
+            peers,
+            sent: stats.classes.iter().map(|c| c.sent).sum(),
+            received: stats.classes.iter().map(|c| c.received).sum(),
+        }
+    }
+
+    /// Append this snapshot to a history file, one comma separated line
+    /// per snapshot
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(
+            file,
+            "{},{},{},{},{}",
+            self.at, self.uptime, self.peers, self.sent, self.received
+        )
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(',').map(str::trim);
+        let mut next = || fields.next()?.parse::<u64>().ok();
+        Some(Self {
+            at: next()? as i64,
+            uptime: next()?,
+            peers: next()? as usize,
+            sent: next()?,
+            received: next()?,
+        })
+    }
+}
+
+/// Every snapshot in a history file, oldest first. Unreadable lines are
+/// skipped
+pub fn load_history<P: AsRef<Path>>(path: P) -> io::Result<Vec<Snapshot>> {
+    let history = std::fs::read_to_string(path)?;
+    Ok(history.lines().filter_map(Snapshot::parse).collect())
+}
+
+/// Format a history as a table, marking where the peer restarted
+pub struct History<'a>(pub &'a [Snapshot]);
+
+impl fmt::Display for History<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<21}{:>10}{:>7}{:>14}{:>14}",
+            "time", "uptime", "peers", "sent", "received"
+        )?;
+        let mut last_uptime = 0;
+        for s in self.0.iter() {
+            if s.uptime < last_uptime {
+                writeln!(f, "-- restarted --")?;
+            }
+            last_uptime = s.uptime;
+            let at = chrono::NaiveDateTime::from_timestamp_opt(s.at, 0)
+                .map_or_else(|| s.at.to_string(), |t| t.to_string());
+            writeln!(
+                f,
+                "{:<21}{:>9}s{:>7}{:>14}{:>14}",
+                at, s.uptime, s.peers, s.sent, s.received
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let path =
+            std::env::temp_dir().join(format!("harbor-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let first = Snapshot::take(600, 3);
+        let second = Snapshot {
+            uptime: 60,
+            ..first.clone()
+        };
+        first.save(&path).unwrap();
+        second.save(&path).unwrap();
+
+        let history = load_history(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(history, vec![first, second]);
+        assert!(History(&history).to_string().contains("restarted"));
+    }
+}
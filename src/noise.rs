@@ -0,0 +1,656 @@
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{identity::PublicKey, peer::PeerId, FRAME_TIMEOUT, PROTOCOL_VERSION};
+use snow::{params::NoiseParams, Builder, HandshakeState, TransportState};
+use std::{
+    fmt,
+    io::{self, prelude::*},
+    net::TcpStream,
+    ops::{Deref, DerefMut},
+    time::Instant,
+};
+
+/// The Noise protocol every connection is encrypted with. XX lets both
+/// sides learn each other's static key during the handshake, so neither
+/// needs to know the other's key up front
+pub const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, including its authentication tag
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// A connection to a peer, with every byte each way encrypted using the
+/// keys agreed in a Noise handshake, or in a TLS one if `tls::enable` was
+/// called. Noise bytes go over the wire as Noise messages, each prefixed
+/// by its length as a big endian u16. Derefs to the underlying TcpStream
+/// for socket options and addresses
+pub struct Conn {
+    tcp: TcpStream,
+    channel: Channel,
+
+    /// When reads start failing, however the bytes are spread out
+    deadline: Option<Instant>,
+}
+
+/// What the other side of a connection proved about itself in the
+/// handshake
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Remote {
+    /// The X25519 static key it holds, from a Noise handshake
+    Key([u8; 32]),
+
+    /// The certificate it showed in a TLS handshake, which names the peer
+    /// it belongs to
+    #[cfg(feature = "tls")]
+    Cert(rustls::pki_types::CertificateDer<'static>),
+}
+
+enum Channel {
+    Noise {
+        state: Box<TransportState>,
+        remote: [u8; 32],
+
+        /// Decrypted bytes not read yet
+        buf: Vec<u8>,
+        pos: usize,
+    },
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::Connection>),
+}
+
+impl Conn {
+    /// Handshake with the peer `to` on a connection dialed to it
+    pub fn dial(tcp: TcpStream, to: &PeerId) -> io::Result<Self> {
+        Self::dial_as(tcp, to, None)
+    }
+
+    /// Handshake with the peer `to`, proving we hold `secret` if given and
+    /// the handshake is a Noise one, so `to` can tell who dialed
+    pub fn dial_as(
+        tcp: TcpStream,
+        to: &PeerId,
+        secret: Option<&[u8; 32]>,
+    ) -> io::Result<Self> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = tls::config() {
+            return tls::initiate(tcp, &config, to);
+        }
+        Self::initiate_as(tcp, to.key(), secret)
+    }
+
+    /// Handshake on an accepted connection, proving we hold `secret` if it
+    /// is a Noise one
+    pub fn accept(tcp: TcpStream, secret: &[u8; 32]) -> io::Result<Self> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = tls::config() {
+            return tls::respond(tcp, &config);
+        }
+        Self::respond(tcp, secret)
+    }
+
+    /// Handshake as the side that dialed, with a throwaway static key. If
+    /// `expect` is given, the other side must prove it holds that key
+    pub fn initiate(tcp: TcpStream, expect: Option<&PublicKey>) -> io::Result<Self> {
+        Self::initiate_as(tcp, expect, None)
+    }
+
+    /// Handshake as the side that dialed, with `secret` as the static key,
+    /// or a throwaway one if there is none
+    fn initiate_as(
+        tcp: TcpStream,
+        expect: Option<&PublicKey>,
+        secret: Option<&[u8; 32]>,
+    ) -> io::Result<Self> {
+        let mut hs = initiator(secret)?;
+        let mut msg = vec![0u8; MAX_MESSAGE];
+        let len = hs.write_message(&[], &mut msg).map_err(broken)?;
+        send(&tcp, &msg[..len])?;
+        hs.read_message(&recv(&tcp, None)?, &mut msg)
+            .map_err(broken)?;
+        let len = hs.write_message(&[], &mut msg).map_err(broken)?;
+        send(&tcp, &msg[..len])?;
+
+        let conn = Self::finish(tcp, hs)?;
+        check_remote(expect, conn.remote_key())?;
+        Ok(conn)
+    }
+
+    /// Handshake as the side that accepted, proving we hold `secret`. The
+    /// whole handshake has to arrive within FRAME_TIMEOUT
+    pub fn respond(tcp: TcpStream, secret: &[u8; 32]) -> io::Result<Self> {
+        let deadline = Some(Instant::now() + FRAME_TIMEOUT);
+        let mut hs = responder(secret)?;
+        let mut msg = vec![0u8; MAX_MESSAGE];
+        hs.read_message(&recv(&tcp, deadline)?, &mut msg)
+            .map_err(broken)?;
+        let len = hs.write_message(&[], &mut msg).map_err(broken)?;
+        send(&tcp, &msg[..len])?;
+        hs.read_message(&recv(&tcp, deadline)?, &mut msg)
+            .map_err(broken)?;
+        Self::finish(tcp, hs)
+    }
+
+    fn finish(tcp: TcpStream, hs: HandshakeState) -> io::Result<Self> {
+        let (state, remote) = transport_mode(hs)?;
+        Ok(Self {
+            tcp,
+            channel: Channel::Noise {
+                state,
+                remote,
+                buf: Vec::new(),
+                pos: 0,
+            },
+            deadline: None,
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn from_tls(tcp: TcpStream, tls: rustls::Connection) -> Self {
+        Self {
+            tcp,
+            channel: Channel::Tls(Box::new(tls)),
+            deadline: None,
+        }
+    }
+
+    /// The X25519 static key the other side proved it holds, for Noise
+    /// connections
+    pub fn remote_key(&self) -> Option<&[u8; 32]> {
+        match &self.channel {
+            Channel::Noise { remote, .. } => Some(remote),
+            #[cfg(feature = "tls")]
+            Channel::Tls(_) => None,
+        }
+    }
+
+    /// What the other side proved about itself in the handshake
+    pub fn remote(&self) -> Option<Remote> {
+        match &self.channel {
+            Channel::Noise { remote, .. } => Some(Remote::Key(*remote)),
+            #[cfg(feature = "tls")]
+            Channel::Tls(tls) => tls
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|cert| Remote::Cert(cert.clone().into_owned())),
+        }
+    }
+
+    /// Fail reads once `deadline` passes, or never with None. A read
+    /// timeout alone lets a client that trickles a byte at a time hold a
+    /// connection forever
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let (state, buf, pos) = match &mut self.channel {
+            Channel::Noise {
+                state, buf, pos, ..
+            } => (state, buf, pos),
+            #[cfg(feature = "tls")]
+            Channel::Tls(tls) => {
+                let mut io = Deadlined::new(&self.tcp, self.deadline);
+                return tls::read(tls, &mut io, out);
+            }
+        };
+        if *pos == buf.len() {
+            let msg = match recv_or_eof(&self.tcp, self.deadline)? {
+                Some(msg) => msg,
+                None => return Ok(0),
+            };
+            buf.resize(MAX_MESSAGE, 0);
+            let len = state.read_message(&msg, buf).map_err(broken)?;
+            buf.truncate(len);
+            *pos = 0;
+        }
+        let n = out.len().min(buf.len() - *pos);
+        out[..n].copy_from_slice(&buf[*pos..*pos + n]);
+        *pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let state = match &mut self.channel {
+            Channel::Noise { state, .. } => state,
+            #[cfg(feature = "tls")]
+            Channel::Tls(tls) => {
+                let mut io = Deadlined::new(&self.tcp, None);
+                return tls::write(tls, &mut io, buf);
+            }
+        };
+        let n = buf.len().min(MAX_MESSAGE - TAG_LEN);
+        let mut msg = vec![0u8; n + TAG_LEN];
+        let len = state.write_message(&buf[..n], &mut msg).map_err(broken)?;
+        send(&self.tcp, &msg[..len])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+impl Deref for Conn {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.tcp
+    }
+}
+
+impl DerefMut for Conn {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        &mut self.tcp
+    }
+}
+
+impl fmt::Debug for Conn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Conn")
+            .field("local", &self.tcp.local_addr().ok())
+            .field("remote", &self.tcp.peer_addr().ok())
+            .finish()
+    }
+}
+
+/// A Noise connection on a tokio socket, for serving and dialing from a
+/// runtime without a thread per connection. Same wire format as Conn, but
+/// there is no TLS side to it
+#[cfg(feature = "async")]
+pub struct AsyncConn {
+    tcp: tokio::net::TcpStream,
+    state: Box<TransportState>,
+    remote: [u8; 32],
+
+    /// Decrypted bytes not read yet
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(feature = "async")]
+impl AsyncConn {
+    /// Handshake with the peer `to` on a connection dialed to it, proving
+    /// we hold `secret` if given
+    pub async fn dial_as(
+        mut tcp: tokio::net::TcpStream,
+        to: &PeerId,
+        secret: Option<&[u8; 32]>,
+    ) -> io::Result<Self> {
+        let mut hs = initiator(secret)?;
+        let mut msg = vec![0u8; MAX_MESSAGE];
+        let len = hs.write_message(&[], &mut msg).map_err(broken)?;
+        send_async(&mut tcp, &msg[..len]).await?;
+        hs.read_message(&recv_async(&mut tcp).await?, &mut msg)
+            .map_err(broken)?;
+        let len = hs.write_message(&[], &mut msg).map_err(broken)?;
+        send_async(&mut tcp, &msg[..len]).await?;
+
+        let conn = Self::finish(tcp, hs)?;
+        check_remote(to.key(), Some(&conn.remote))?;
+        Ok(conn)
+    }
+
+    /// Handshake on an accepted connection, proving we hold `secret`. The
+    /// whole handshake has to arrive within FRAME_TIMEOUT
+    pub async fn accept(
+        mut tcp: tokio::net::TcpStream,
+        secret: &[u8; 32],
+    ) -> io::Result<Self> {
+        let mut hs = responder(secret)?;
+        let handshake = async {
+            let mut msg = vec![0u8; MAX_MESSAGE];
+            hs.read_message(&recv_async(&mut tcp).await?, &mut msg)
+                .map_err(broken)?;
+            let len = hs.write_message(&[], &mut msg).map_err(broken)?;
+            send_async(&mut tcp, &msg[..len]).await?;
+            hs.read_message(&recv_async(&mut tcp).await?, &mut msg)
+                .map_err(broken)
+        };
+        tokio::time::timeout(FRAME_TIMEOUT, handshake)
+            .await
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::TimedOut, "handshake took too long")
+            })??;
+        Self::finish(tcp, hs)
+    }
+
+    fn finish(tcp: tokio::net::TcpStream, hs: HandshakeState) -> io::Result<Self> {
+        let (state, remote) = transport_mode(hs)?;
+        Ok(Self {
+            tcp,
+            state,
+            remote,
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// What the other side proved about itself in the handshake
+    pub fn remote(&self) -> Remote {
+        Remote::Key(self.remote)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    /// Read some decrypted bytes, or 0 once the other side has hung up
+    pub async fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            let msg = match recv_or_eof_async(&mut self.tcp).await? {
+                Some(msg) => msg,
+                None => return Ok(0),
+            };
+            self.buf.resize(MAX_MESSAGE, 0);
+            let len = self
+                .state
+                .read_message(&msg, &mut self.buf)
+                .map_err(broken)?;
+            self.buf.truncate(len);
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+
+    pub async fn read_exact(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < out.len() {
+            match self.read(&mut out[filled..]).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        let mut msg = vec![0u8; MAX_MESSAGE];
+        while !buf.is_empty() {
+            let n = buf.len().min(MAX_MESSAGE - TAG_LEN);
+            let len = self
+                .state
+                .write_message(&buf[..n], &mut msg)
+                .map_err(broken)?;
+            send_async(&mut self.tcp, &msg[..len]).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl fmt::Debug for AsyncConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncConn")
+            .field("local", &self.tcp.local_addr().ok())
+            .field("remote", &self.tcp.peer_addr().ok())
+            .finish()
+    }
+}
+
+impl PublicKey {
+    /// The X25519 form of this key, the one a peer holding it uses in
+    /// handshakes
+    pub fn to_x25519(&self) -> Option<[u8; 32]> {
+        let key = ed25519_dalek::VerifyingKey::from_bytes(self.as_bytes()).ok()?;
+        Some(key.to_montgomery().to_bytes())
+    }
+}
+
+fn params() -> NoiseParams {
+    NOISE_PATTERN.parse().unwrap()
+}
+
+/// Handshake state for the side that dialed, with `secret` as the static
+/// key, or a throwaway one if there is none
+fn initiator(secret: Option<&[u8; 32]>) -> io::Result<HandshakeState> {
+    let prologue = PROTOCOL_VERSION.to_be_bytes();
+    let builder = Builder::new(params());
+    let throwaway = builder.generate_keypair().map_err(broken)?;
+    let secret = secret.map_or(&throwaway.private[..], |s| &s[..]);
+    builder
+        .local_private_key(secret)
+        .prologue(&prologue)
+        .build_initiator()
+        .map_err(broken)
+}
+
+fn responder(secret: &[u8; 32]) -> io::Result<HandshakeState> {
+    let prologue = PROTOCOL_VERSION.to_be_bytes();
+    Builder::new(params())
+        .local_private_key(secret)
+        .prologue(&prologue)
+        .build_responder()
+        .map_err(broken)
+}
+
+/// The keys for a finished handshake, and the static key the other side
+/// proved it holds
+fn transport_mode(hs: HandshakeState) -> io::Result<(Box<TransportState>, [u8; 32])> {
+    let mut remote = [0u8; 32];
+    remote.copy_from_slice(
+        hs.get_remote_static()
+            .ok_or_else(|| broken("no static key"))?,
+    );
+    Ok((Box::new(hs.into_transport_mode().map_err(broken)?), remote))
+}
+
+/// Fail unless the other side proved it holds `expect`, if given
+fn check_remote(expect: Option<&PublicKey>, remote: Option<&[u8; 32]>) -> io::Result<()> {
+    match expect {
+        Some(key) if key.to_x25519().as_ref() != remote => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("peer did not prove it holds the key {key}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn broken<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("noise: {e}"))
+}
+
+fn send(mut tcp: &TcpStream, msg: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + msg.len());
+    frame.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    frame.extend_from_slice(msg);
+    tcp.write_all(&frame)
+}
+
+fn recv(tcp: &TcpStream, deadline: Option<Instant>) -> io::Result<Vec<u8>> {
+    recv_or_eof(tcp, deadline)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed mid handshake",
+        )
+    })
+}
+
+/// Read one message, or None if the connection was closed cleanly before it
+fn recv_or_eof(
+    tcp: &TcpStream,
+    deadline: Option<Instant>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    if !fill(tcp, &mut len, deadline)? {
+        return Ok(None);
+    }
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    if !fill(tcp, &mut msg, deadline)? {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(msg))
+}
+
+/// Fill `buf` from the connection before `deadline`. Returns false if the
+/// connection was closed before any of it arrived
+fn fill(tcp: &TcpStream, buf: &mut [u8], deadline: Option<Instant>) -> io::Result<bool> {
+    let mut io = Deadlined::new(tcp, deadline);
+    let mut filled = 0;
+    while filled < buf.len() {
+        match io.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(feature = "async")]
+async fn send_async(tcp: &mut tokio::net::TcpStream, msg: &[u8]) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let mut frame = Vec::with_capacity(2 + msg.len());
+    frame.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    frame.extend_from_slice(msg);
+    tcp.write_all(&frame).await
+}
+
+#[cfg(feature = "async")]
+async fn recv_async(tcp: &mut tokio::net::TcpStream) -> io::Result<Vec<u8>> {
+    recv_or_eof_async(tcp).await?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed mid handshake",
+        )
+    })
+}
+
+/// Read one message like `recv_or_eof`. Callers put their own timeouts
+/// around it
+#[cfg(feature = "async")]
+async fn recv_or_eof_async(
+    tcp: &mut tokio::net::TcpStream,
+) -> io::Result<Option<Vec<u8>>> {
+    use tokio::io::AsyncReadExt;
+    let mut len = [0u8; 2];
+    if tcp.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+    tcp.read_exact(&mut len[1..]).await?;
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    tcp.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+/// Reads and writes a connection, with reads failing once a deadline has
+/// passed
+pub(crate) struct Deadlined<'a> {
+    tcp: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Deadlined<'a> {
+    pub(crate) fn new(tcp: &'a TcpStream, deadline: Option<Instant>) -> Self {
+        Self { tcp, deadline }
+    }
+}
+
+impl Read for Deadlined<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "frame took too long to arrive",
+                ));
+            }
+            self.tcp.set_read_timeout(Some(left))?;
+        }
+        self.tcp.read(buf)
+    }
+}
+
+impl Write for Deadlined<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tcp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_handshake() {
+        let identity = Identity::generate().unwrap();
+        let secret = identity.noise_secret();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (tcp, _) = listener.accept().unwrap();
+                let mut conn = Conn::respond(tcp, &secret).unwrap();
+                let mut buf = vec![0u8; 100_000];
+                if conn.read_exact(&mut buf).is_ok() {
+                    conn.write_all(&buf).unwrap();
+                }
+            }
+        });
+
+        // Bigger than one Noise message, echoed back
+        let tcp = TcpStream::connect(addr).unwrap();
+        let mut conn = Conn::initiate(tcp, Some(&identity.public_key())).unwrap();
+        let sent: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        conn.write_all(&sent).unwrap();
+        let mut got = vec![0u8; sent.len()];
+        conn.read_exact(&mut got).unwrap();
+        assert_eq!(got, sent);
+
+        // Someone else's key is caught
+        let tcp = TcpStream::connect(addr).unwrap();
+        let other = Identity::generate().unwrap().public_key();
+        assert!(Conn::initiate(tcp, Some(&other)).is_err());
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_conn() {
+        let identity = Identity::generate().unwrap();
+        let secret = identity.noise_secret();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A blocking Conn and an AsyncConn speak the same wire format
+        let client = thread::spawn(move || {
+            let tcp = TcpStream::connect(addr).unwrap();
+            let mut conn = Conn::initiate(tcp, Some(&identity.public_key())).unwrap();
+            let sent: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            conn.write_all(&sent).unwrap();
+            let mut got = vec![0u8; sent.len()];
+            conn.read_exact(&mut got).unwrap();
+            assert_eq!(got, sent);
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            listener.set_nonblocking(true).unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut conn = AsyncConn::accept(tcp, &secret).await.unwrap();
+            let mut buf = vec![0u8; 100_000];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+        client.join().unwrap();
+    }
+}
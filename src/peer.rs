@@ -1,33 +1,91 @@
 use crate::{
+    batch::{self, Batcher},
+    budget::{MemoryBudget, Reservation},
+    clock::{self, ClockSkew},
+    event::{self, Event, Subscribers},
+    friends::Friends,
+    greylist::Greylist,
+    group::{self, Membership, Publication},
+    hooks::{Decision, Hooks, NoHooks},
+    identity::{Identity, PublicKey},
+    inbound::{Admission, Inbound},
+    lifecycle::State,
+    message::Message,
+    metrics::{self, Snapshot, TrafficClass},
+    noise::{Conn, Remote},
     protocol::Protocol,
     protocol::*,
-    transport::Transport,
-    util, {Error, NetworkError, MAX_PEERS},
+    region::{Locator, Region},
+    resolve::{CachingResolver, Lookup, Resolver, SystemResolver},
+    routing::Point,
+    score::{DefaultScorer, PeerScorer},
+    seen::SeenCache,
+    store::Store,
+    trace::{self, TraceId},
+    transport::{self, SocketOptions, Transport},
+    util, Error, NetworkError, Reason, ACCEPT_ERROR_BACKOFF, ANCHOR_FILE,
+    ANCHOR_INTERVAL, BOOTSTRAP_FILE, DIAL_TIMEOUT, FRAME_TIMEOUT, GREYLIST_COOLDOWN,
+    GREYLIST_STRIKES, HANDLER_BUDGET, HEALTH_INTERVAL, K_BUCKET_SIZE, LOOKUP_PARALLELISM,
+    MAX_CLOCK_SKEW, MAX_GOSSIP_AGE, MAX_INBOUND, MAX_INBOUND_PER_SOURCE, MAX_PEERS,
+    MAX_PEERS_PER_SUBNET, MAX_PING_INTERVAL, MAX_REJOIN_BACKOFF, MAX_STATUS_LEN, MAX_TTS,
+    MEMORY_BUDGET, METRICS_FILE, METRICS_INTERVAL, MIN_PING_INTERVAL, PEER_CACHE_FILE,
+    PEER_CACHE_INTERVAL, PEER_CACHE_SIZE, PEER_LOCK_TIMEOUT, PROTOCOL_VERSION,
+    QUERY_FANOUT, QUERY_HOP_TIMEOUT, SEEN_CACHE_SIZE, SEEN_CACHE_TTL, VERIFY_SAMPLE,
 };
+#[cfg(feature = "async")]
+use crate::{noise::AsyncConn, transport::AsyncTransport};
 use chrono;
-use derivative::Derivative;
-use log::{info, warn};
+use futures::{executor, future};
+use log::{error, info, warn};
+use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    any::Any,
+    collections::{HashMap, HashSet, VecDeque},
     fmt,
-    io::prelude::*,
-    net::{IpAddr, Ipv4Addr, TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    fs::{self, File},
+    io::{self, prelude::*},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+pub use crate::peerstore::{PeerStore, PeerStoreEntry};
+
 /// A key for a file
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
 pub struct Key(String);
 
+impl Key {
+    pub fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// A unique identifier for peers on the network based on libp2p's
 /// multiaddr
-#[derive(Serialize, Deserialize, Hash, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
 pub struct PeerId {
     id: String,
     ip: Ipv4Addr,
     port: u16,
+
+    /// The public key the id was derived from, for peers with an Identity
+    key: Option<PublicKey>,
 }
 
 impl fmt::Debug for PeerId {
@@ -36,30 +94,48 @@ impl fmt::Debug for PeerId {
     }
 }
 
-impl std::cmp::PartialEq for PeerId {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
+impl std::hash::Hash for PeerId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
     }
 }
 
-impl std::cmp::PartialEq for PeerStoreEntry {
+impl std::cmp::PartialEq for PeerId {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
 }
 
 impl Eq for PeerId {}
-impl Eq for PeerStoreEntry {}
 
 impl PeerId {
+    /// The PeerId of a peer without an Identity, derived from its address
     pub fn new(ip: Ipv4Addr, port: u16) -> Self {
-        // TODO: once encryption is added, the hash will be of peer's pubkey
         let data = format!("{ip}:{port}");
         let hash = util::hash_sha256(data.as_bytes());
         Self {
             id: format!("/peer/{hash}/{ip}/{port}"),
             ip,
             port,
+            key: None,
+        }
+    }
+
+    /// The PeerId of a peer with an Identity, derived from its public key.
+    /// The hash stays the same wherever the peer moves
+    pub fn with_key(key: PublicKey, ip: Ipv4Addr, port: u16) -> Self {
+        let hash = util::hash_sha256(key.as_bytes());
+        Self {
+            id: format!("/peer/{hash}/{ip}/{port}"),
+            ip,
+            port,
+            key: Some(key),
         }
     }
 
@@ -67,8 +143,14 @@ impl PeerId {
         PeerId::new(ip, port)
     }
 
-    pub fn to_string(&self) -> String {
-        self.id.clone()
+    /// Return this PeerId in its full multiaddr form, followed by the
+    /// public key it was derived from if it has one, so it can be parsed
+    /// back and checked
+    pub fn to_multiaddr(&self) -> String {
+        match self.key {
+            Some(key) => format!("{}/{key}", self.id),
+            None => self.id.clone(),
+        }
     }
 
     /// Return this PeerId in the format ip:port
@@ -76,6 +158,11 @@ impl PeerId {
         format!("{}:{}", self.ip, self.port)
     }
 
+    /// Return the hash this PeerId was derived with
+    pub fn hash(&self) -> &str {
+        self.id.split('/').nth(2).unwrap_or_default()
+    }
+
     /// Return this PeerId's ip
     pub fn ip(&self) -> Ipv4Addr {
         self.ip
@@ -85,239 +172,3449 @@ impl PeerId {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// The public key this PeerId was derived from, if it has one
+    pub fn key(&self) -> Option<&PublicKey> {
+        self.key.as_ref()
+    }
+
+    /// Whether this PeerId's hash is the one derived from its public key,
+    /// or from its address if it has no key. PeerIds read off the wire may
+    /// claim any hash
+    pub fn verify_derivation(&self) -> bool {
+        let derived = match self.key {
+            Some(key) => PeerId::with_key(key, self.ip, self.port),
+            None => PeerId::new(self.ip, self.port),
+        };
+        derived.id == self.id
+    }
+
+    /// Return the /24 this PeerId's ip belongs to, or None for private and
+    /// loopback addresses, which are exempt from diversity limits
+    pub fn subnet(&self) -> Option<[u8; 3]> {
+        if self.ip.is_private() || self.ip.is_loopback() {
+            return None;
+        }
+        let [a, b, c, _] = self.ip.octets();
+        Some([a, b, c])
+    }
 }
 
-/// An entry in a PeerStore
-#[derive(Derivative, Debug, Serialize, Deserialize, Clone)]
-#[derivative(Hash)]
-pub struct PeerStoreEntry {
-    #[derivative(Hash = "ignore")]
-    last_seen: Option<chrono::NaiveDateTime>,
-    id: PeerId,
+/// Addresses and the PeerIds derived from them, for other implementations
+/// to check theirs against. These only change along with a deliberate
+/// change to how PeerIds are derived
+pub const DERIVATION_VECTORS: [(&str, &str); 4] = [
+    (
+        "127.0.0.1:3300",
+        "/peer/df17852b4ad37840de9c5bb99715ebe5/127.0.0.1/3300",
+    ),
+    (
+        "8.8.8.8:53",
+        "/peer/576966c288e0eaca3285a44e9fb82274/8.8.8.8/53",
+    ),
+    (
+        "10.0.0.1:1",
+        "/peer/5b05cd4545abc4a522f0956ef13fe7f5/10.0.0.1/1",
+    ),
+    (
+        "255.255.255.255:65535",
+        "/peer/144d2d00d5422ee05549dc898e6fe81d/255.255.255.255/65535",
+    ),
+];
+
+/// (public key, address, PeerId) triples for peers with an Identity. The
+/// key is the one from the first test vector of RFC 8032
+pub const KEYED_DERIVATION_VECTORS: [(&str, &str, &str); 1] = [(
+    "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+    "127.0.0.1:3300",
+    "/peer/21fe31dfa154a261626bf854046fd227/127.0.0.1/3300",
+)];
+
+impl std::str::FromStr for PeerId {
+    type Err = Error;
+
+    /// Parse a PeerId from either `ip:port` or its full multiaddr form
+    /// `/peer/<hash>/<ip>/<port>`, followed by `/<public key>` for PeerIds
+    /// derived from a key. The hash of a multiaddr must match
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPeerId(s.to_string());
+
+        if s.starts_with('/') {
+            let parts: Vec<&str> = s.split('/').collect();
+            if !(5..=6).contains(&parts.len()) || parts[1] != "peer" {
+                return Err(invalid());
+            }
+            let ip = parts[3].parse::<Ipv4Addr>().map_err(|_| invalid())?;
+            let port = parts[4].parse::<u16>().map_err(|_| invalid())?;
+            let id = match parts.get(5) {
+                Some(key) => {
+                    PeerId::with_key(key.parse().map_err(|_| invalid())?, ip, port)
+                }
+                None => PeerId::new(ip, port),
+            };
+            if id.id != parts[..5].join("/") {
+                return Err(invalid());
+            }
+            return Ok(id);
+        }
+
+        let (ip, port) = s.split_once(':').ok_or_else(invalid)?;
+        let ip = ip.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        Ok(PeerId::new(ip, port))
+    }
 }
 
-impl PeerStoreEntry {
-    pub fn new(id: PeerId) -> Self {
-        Self {
-            last_seen: None,
-            id,
+/// Parse the contents of a bootstrap file into a list of PeerIds. Blank
+/// lines and `#` comments are ignored. In strict mode the first malformed
+/// entry is an error; otherwise it is logged and skipped
+pub fn parse_bootstrap<I>(
+    lines: I,
+    strict: bool,
+    resolver: &dyn Resolver,
+) -> Result<Vec<PeerId>, Error>
+where
+    I: IntoIterator<Item = String>,
+{
+    let entries: Vec<(usize, String)> = lines
+        .into_iter()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.split('#').next().unwrap_or("").trim().into()))
+        .filter(|(_, entry): &(usize, String)| !entry.is_empty())
+        .collect();
+
+    // Look every hostname up at once, rather than one after another
+    let hosts: Vec<&str> = entries
+        .iter()
+        .filter(|(_, entry)| entry.parse::<PeerId>().is_err())
+        .filter_map(|(_, entry)| hostname(entry).map(|(host, _)| host))
+        .collect();
+    let lookups = executor::block_on(future::join_all(
+        hosts.iter().map(|host| resolver.lookup(host)),
+    ));
+    let resolved: HashMap<&str, io::Result<Lookup>> =
+        hosts.into_iter().zip(lookups).collect();
+
+    let mut ids = Vec::new();
+    for (n, entry) in entries.iter() {
+        match entry
+            .parse::<PeerId>()
+            .map(|id| vec![id])
+            .or_else(|e| resolve_host(entry, &resolved).ok_or(e))
+        {
+            Ok(found) => ids.extend(found),
+            Err(_) if strict => return Err(Error::BadBootstrapLine(*n, entry.clone())),
+            Err(e) => warn!("skipping bootstrap line {n}: {e}"),
+        }
+    }
+    Ok(ids)
+}
+
+/// Split a `hostname:port` bootstrap entry, if it names a host
+fn hostname(entry: &str) -> Option<(&str, u16)> {
+    let (host, port) = entry.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+    if !host.chars().any(|c| c.is_ascii_alphabetic()) {
+        return None; // A bad ip, not a name
+    }
+    Some((host, port))
+}
+
+/// Turn a `hostname:port` bootstrap entry into a PeerId for each address
+/// the name resolved to
+fn resolve_host(
+    entry: &str,
+    resolved: &HashMap<&str, io::Result<Lookup>>,
+) -> Option<Vec<PeerId>> {
+    let (host, port) = hostname(entry)?;
+    match resolved.get(host)? {
+        Ok(lookup) if !lookup.addrs.is_empty() => Some(
+            lookup
+                .addrs
+                .iter()
+                .map(|ip| PeerId::new(*ip, port))
+                .collect(),
+        ),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("could not resolve {host}: {e}");
+            None
+        }
+    }
+}
+
+/// Ask `via` to dial back whoever is asking on the given port. Returns
+/// whether the dial back succeeded
+pub fn dial_back(via: &PeerId, port: u16) -> NetworkResult<bool> {
+    let mut conn =
+        Peer::send_request_timeout(via, Request::DialBack { port }, DIAL_TIMEOUT)?;
+    conn.set_read_timeout(Some(DIAL_TIMEOUT * 3))?;
+    match Peer::recv_response(&mut conn)? {
+        Response::Ok => Ok(true),
+        Response::Err(e) => {
+            info!("{via:?} could not dial back port {port}: {e}");
+            Ok(false)
+        }
+        res => Err(NetworkError::Fail(format!(
+            "unexpected dial back response {res:?}"
+        ))),
+    }
+}
+
+/// Write the given peers one per line in the bootstrap file format, either
+/// as `ip:port` or as full multiaddr-form PeerIds, keys included. Returns
+/// the number of peers written
+pub fn write_bootstrap<'a, W, I>(
+    w: &mut W,
+    peers: I,
+    multiaddr: bool,
+) -> io::Result<usize>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a PeerId>,
+{
+    let mut count = 0;
+    for id in peers {
+        if multiaddr {
+            writeln!(w, "{}", id.to_multiaddr())?;
+        } else {
+            writeln!(w, "{}", id.as_socket())?;
         }
+        count += 1;
     }
+    Ok(count)
+}
+
+/// Save the most recently seen peers in the PeerStore to the peer cache
+/// file at `path`, so this node can rejoin the network even if its
+/// bootstrap hosts are gone. Returns the number of peers saved
+pub fn save_peer_cache<P: AsRef<Path>>(peers: &PeerStore, path: P) -> io::Result<usize> {
+    let seen = peers
+        .recent()
+        .take_while(|p| p.last_seen.is_some())
+        .take(PEER_CACHE_SIZE)
+        .map(|p| &p.id);
+
+    let mut file = File::create(&path)?;
+    writeln!(file, "{}", cache_header())?;
+    let count = write_bootstrap(&mut file, seen, true)?;
+    info!("saved {count} peers to {}", path.as_ref().display());
+    Ok(count)
 }
 
-pub type PeerStore = HashSet<PeerStoreEntry>;
+/// The first line of a peer cache, saying which version wrote it
+fn cache_header() -> String {
+    format!("# harbor peer cache, protocol version {PROTOCOL_VERSION}")
+}
 
 /// A peer on the network. This represents the peer running on this machine
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Peer {
     pub(crate) id: PeerId,
     max_peers: u8,
     pub_ip: Option<Ipv4Addr>, // Deprecated
     local: bool,
 
+    /// Fail on malformed bootstrap entries instead of skipping them
+    strict_bootstrap: bool,
+
+    /// Peers that are always kept in the PeerStore
+    anchors: HashSet<PeerId>,
+
     /// A map from PeerId to (ip, port) pairs
     pub(crate) peers: Arc<Mutex<PeerStore>>,
+
+    /// Channels to deliver events on
+    pub(crate) events: Subscribers,
+
+    /// Number of request handlers that have panicked
+    handler_panics: Arc<AtomicU64>,
+
+    /// Ranks known peers for eviction and query fan-out
+    scorer: Arc<dyn PeerScorer>,
+
+    /// Callbacks into the embedding application
+    hooks: Arc<dyn Hooks>,
+
+    /// When this peer was constructed
+    started: Instant,
+
+    /// Which phase of its lifecycle this peer is in
+    state: Arc<Mutex<State>>,
+
+    /// Places peers on the map, to prefer nearby providers
+    locator: Option<Arc<dyn Locator>>,
+
+    /// How to set up the listening socket
+    socket_opts: SocketOptions,
+
+    /// Number of accept loops still running
+    acceptors: Arc<AtomicUsize>,
+
+    /// Incoming connections being handled, to enforce the inbound limits
+    inbound: Arc<Inbound>,
+
+    /// Flooded requests handled recently
+    seen: Arc<Mutex<SeenCache>>,
+
+    /// Resolves hostnames in the bootstrap files
+    resolver: Arc<dyn Resolver>,
+
+    /// How far other peers' clocks are from ours
+    clock: Arc<Mutex<ClockSkew>>,
+
+    /// Peers ignored for now because they keep flapping or failing checks
+    greylist: Arc<Mutex<Greylist>>,
+
+    /// Peers in full k-buckets being pinged to see if a newcomer can take
+    /// their place
+    challenged: Arc<Mutex<HashSet<PeerId>>>,
+
+    /// The values stored on this peer
+    pub(crate) store: Arc<Mutex<Store>>,
+
+    /// The identities this peer takes and holds messages from
+    pub(crate) friends: Arc<Mutex<Friends>>,
+
+    /// Messages held for friends until they come to collect them
+    pub(crate) held: Arc<Mutex<HashMap<PublicKey, VecDeque<Message>>>>,
+
+    /// The status this peer advertises to friends
+    pub(crate) status: Arc<Mutex<String>>,
+
+    /// The status each friend that came online last advertised
+    pub(crate) statuses: Arc<Mutex<HashMap<PublicKey, String>>>,
+
+    /// Bytes this peer may hold for requests and responses in flight
+    memory: Arc<MemoryBudget>,
+
+    /// The keypair this peer's id is derived from, if it has one
+    identity: Option<Arc<Identity>>,
+
+    /// The static key this peer proves it holds in Noise handshakes. From
+    /// its identity if it has one, otherwise made up when it starts
+    noise_secret: [u8; 32],
+
+    /// Coalesces small requests headed for the same peer
+    batcher: Arc<Batcher>,
+
+    /// Where the hosts to bootstrap from are listed
+    bootstrap_file: PathBuf,
+
+    /// Where the anchor peers are listed
+    anchor_file: PathBuf,
+
+    /// Where the peers seen last run are saved
+    peer_cache: PathBuf,
+
+    /// Where metrics snapshots are appended
+    metrics_file: PathBuf,
 }
 
 impl Peer {
     /// Construct a new peer
     pub fn new(local: bool, port: u16) -> Result<Self, Error> {
+        let id = PeerId::from(util::get_local_ip()?, port);
+        let noise_secret = Identity::generate()?.noise_secret();
         Ok(Self {
-            id: PeerId::from(util::get_local_ip()?, port),
             max_peers: MAX_PEERS,
             pub_ip: None,
             local,
-            peers: Arc::new(Mutex::new(HashSet::new())),
+            strict_bootstrap: false,
+            anchors: HashSet::new(),
+            peers: Arc::new(Mutex::new(PeerStore::around(&id))),
+            id,
+            events: Arc::new(Mutex::new(Vec::new())),
+            handler_panics: Arc::new(AtomicU64::new(0)),
+            scorer: Arc::new(DefaultScorer),
+            hooks: Arc::new(NoHooks),
+            started: Instant::now(),
+            state: Arc::new(Mutex::new(State::Initializing)),
+            locator: None,
+            socket_opts: SocketOptions::default(),
+            acceptors: Arc::new(AtomicUsize::new(0)),
+            inbound: Arc::new(Inbound::new(MAX_INBOUND, MAX_INBOUND_PER_SOURCE)),
+            seen: Arc::new(Mutex::new(SeenCache::new(SEEN_CACHE_SIZE, SEEN_CACHE_TTL))),
+            clock: Arc::new(Mutex::new(ClockSkew::default())),
+            resolver: Arc::new(CachingResolver::new(SystemResolver)),
+            greylist: Arc::new(Mutex::new(Greylist::new(
+                GREYLIST_STRIKES,
+                GREYLIST_COOLDOWN,
+            ))),
+            challenged: Arc::new(Mutex::new(HashSet::new())),
+            store: Arc::new(Mutex::new(Store::new())),
+            friends: Arc::new(Mutex::new(Friends::default())),
+            held: Arc::new(Mutex::new(HashMap::new())),
+            status: Arc::new(Mutex::new(String::new())),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            memory: Arc::new(MemoryBudget::new(MEMORY_BUDGET)),
+            identity: None,
+            noise_secret,
+            batcher: Arc::new(Batcher::new(noise_secret)),
+            bootstrap_file: PathBuf::from(BOOTSTRAP_FILE),
+            anchor_file: PathBuf::from(ANCHOR_FILE),
+            peer_cache: PathBuf::from(PEER_CACHE_FILE),
+            metrics_file: PathBuf::from(METRICS_FILE),
         })
     }
 
-    /// Add a peer to this peer's list of known peers
+    /// Set whether a malformed bootstrap file entry aborts startup
+    pub fn set_strict_bootstrap(&mut self, strict: bool) {
+        self.strict_bootstrap = strict;
+    }
+
+    /// Set the options for the socket this peer listens on
+    pub fn set_socket_options(&mut self, opts: SocketOptions) {
+        self.inbound =
+            Arc::new(Inbound::new(opts.max_inbound, opts.max_inbound_per_source));
+        self.socket_opts = opts;
+    }
+
+    /// Set where the peers seen this run are saved, to rejoin from next time
+    pub fn set_peer_cache<P: AsRef<Path>>(&mut self, path: P) {
+        self.peer_cache = path.as_ref().to_path_buf();
+    }
+
+    /// Read the bootstrap and anchor lists from `dir`, and keep the peer
+    /// cache and metrics history there, instead of in the working directory
+    pub fn set_data_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        let dir = dir.as_ref();
+        self.bootstrap_file = dir.join(BOOTSTRAP_FILE);
+        self.anchor_file = dir.join(ANCHOR_FILE);
+        self.peer_cache = dir.join(PEER_CACHE_FILE);
+        self.metrics_file = dir.join(METRICS_FILE);
+    }
+
+    /// Set where metrics snapshots are appended
+    pub fn set_metrics_file<P: AsRef<Path>>(&mut self, path: P) {
+        self.metrics_file = path.as_ref().to_path_buf();
+    }
+
+    /// Keep this peer's values in `store` instead of in memory. Call before
+    /// starting the peer
+    pub fn set_store(&mut self, store: Store) {
+        self.store = Arc::new(Mutex::new(store));
+    }
+
+    /// Replace how hostnames in the bootstrap files are resolved
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Set how many bytes this peer may hold for requests and responses
+    /// in flight
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory = Arc::new(MemoryBudget::new(bytes));
+    }
+
+    /// Replace the built-in peer scoring
+    pub fn set_scorer(&mut self, scorer: Arc<dyn PeerScorer>) {
+        self.scorer = scorer;
+    }
+
+    /// Be known by an Identity, deriving this peer's id from its public key
+    /// instead of its address. Call before starting the peer
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.id = identity.peer_id(self.id.ip, self.id.port);
+        self.noise_secret = identity.noise_secret();
+        self.batcher = Arc::new(Batcher::new(self.noise_secret));
+        self.identity = Some(Arc::new(identity));
+
+        // Buckets are measured from our id, so re-sort the known peers
+        let mut peers = self.peers.lock();
+        let mut store = PeerStore::around(&self.id);
+        for entry in peers.iter() {
+            store.insert(entry.clone());
+        }
+        *peers = store;
+    }
+
+    /// The Identity this peer is known by, if it has one
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_deref()
+    }
+
+    /// Set the identities this peer takes and holds messages from. Takes
+    /// effect at once, on every clone of this peer
+    pub fn set_friends(&self, friends: Friends) {
+        *self.friends.lock() = friends;
+    }
+
+    /// Place peers on the map with a Locator, like a `region::GeoIp`
+    /// database, so providers nearby are fetched from first
+    pub fn set_locator(&mut self, locator: Arc<dyn Locator>) {
+        self.locator = Some(locator);
+    }
+
+    /// The region a peer's address is in, if there is a Locator and it
+    /// knows
+    pub fn region(&self, id: &PeerId) -> Option<Region> {
+        self.locator.as_ref()?.locate(IpAddr::V4(id.ip()))
+    }
+
+    /// Register callbacks for the application embedding this peer
+    pub fn set_hooks(&mut self, hooks: Arc<dyn Hooks>) {
+        self.hooks = hooks;
+    }
+
+    /// Subscribe to events emitted by this peer
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.events.lock().push(tx);
+        rx
+    }
+
+    /// Add a peer to this peer's list of known peers. If the PeerStore is
+    /// full, the least recently seen peer that is not an anchor is evicted
     pub fn add_peer(&mut self, new_peer: PeerId) -> bool {
         let peers = self.peers.clone();
-        let mut peers = peers.lock().unwrap();
+        let mut peers = peers.lock();
 
         // Cannot store ourself in the PeerStore
         if new_peer == self.id {
             return false;
         }
-        peers.insert(PeerStoreEntry::new(new_peer))
-    }
-
-    /// Start listening on this peer
-    /// TODO: Run a grpc server (async?) to run local client API to
-    /// interface with the node
-    pub fn start(mut self, send_pings: bool) -> Result<(), Error> {
-        self.bootstrap()?; // Bootstrap this peer
 
-        // This loop will run forever
-        // TODO: Handle incoming connections in a separate thread
-        let socket = TcpListener::bind(&self.id.as_socket())?;
-        info!("starting peer {:#?}", self);
-        info!("bound peer on socket {:?}", self.id.as_socket());
+        if peers.contains(&new_peer) {
+            return false;
+        }
 
-        // TODO: Delete, replace with RPC
-        if send_pings {
-            self.send_pings()?;
+        // A peer first known by its address alone is the same peer once its
+        // key is learned, so its entry is upgraded rather than kept twice
+        let plain = PeerId::new(new_peer.ip(), new_peer.port());
+        if new_peer.key().is_some() {
+            if let Some(mut entry) = peers.remove(&plain) {
+                info!("upgrading {plain:?} to {new_peer:?}");
+                entry.id = new_peer.clone();
+                peers.insert(entry);
+                drop(peers);
+                if self.anchors.remove(&plain) {
+                    self.anchors.insert(new_peer.clone());
+                }
+                self.hooks.on_peer_removed(&plain);
+                self.hooks.on_peer_added(&new_peer);
+                return true;
+            }
+        }
+        let anchor = self.anchors.contains(&new_peer);
+        if !anchor && self.greylist.lock().contains(&new_peer) {
+            info!("refusing {new_peer:?}: greylisted");
+            return false;
         }
 
-        loop {
-            info!("listening for incoming connections");
-            // Listen for new incoming connections (requests)
-            for stream in socket.incoming() {
-                self = self.handle_conn(stream?)?;
+        // Don't let one subnet take over the PeerStore
+        if let (false, Some(subnet)) = (anchor, new_peer.subnet()) {
+            if peers.subnet_len(Some(subnet)) >= MAX_PEERS_PER_SUBNET {
+                warn!("refusing {new_peer:?}: too many peers from its /24");
+                return false;
             }
         }
-    }
 
-    /// Read from the bootstrap file and add the bootstrap hosts to the PeerStore
-    fn bootstrap(&mut self) -> Result<i32, Error> {
-        let mut count = 0i32; // Number of bootstrapped peers
-
-        // Read each line from the bootstrap file
-        if let Ok(lines) = util::read_lines(crate::BOOTSTRAP_FILE) {
-            for line in lines {
-                // For each host
-                if let Ok(host) = line {
-                    // Parse the ip and port and construct a PeerId
-                    let data: Vec<String> =
-                        host.split(":").map(|s| s.to_string()).collect();
-                    if data.len() != 2 {
-                        continue;
-                    }
-                    let ip = data[0].parse::<Ipv4Addr>().unwrap();
-                    let port: u16 = data[1].parse().unwrap();
-                    let id = PeerId::from(ip, port);
+        // Like Kademlia, keep the peers already in a full k-bucket over
+        // newcomers, since peers that have been up longest tend to stay up.
+        // The newcomer only gets in if the peer heard from longest ago is
+        // found to be gone
+        if !anchor && peers.bucket_len(&new_peer) >= K_BUCKET_SIZE {
+            let stalest = peers
+                .in_bucket(&new_peer)
+                .filter(|p| !self.anchors.contains(&p.id))
+                .min_by_key(|p| p.last_seen)
+                .map(|p| p.id.clone());
+            drop(peers);
+            match stalest {
+                Some(stale) => {
+                    info!("{new_peer:?}'s k-bucket is full, checking on {stale:?}");
+                    self.challenge(stale, new_peer);
+                }
+                None => info!("refusing {new_peer:?}: its k-bucket is full of anchors"),
+            }
+            return false;
+        }
 
-                    count += self.add_peer(id) as i32;
+        // Make room for the new peer
+        let mut evicted = None;
+        if peers.len() >= self.max_peers as usize {
+            let victim = peers
+                .iter()
+                .filter(|p| !self.anchors.contains(&p.id))
+                .min_by_key(|p| self.scorer.score(p))
+                .map(|p| p.id.clone());
+            match victim {
+                Some(victim) => {
+                    info!("evicting {victim:?} to make room for {new_peer:?}");
+                    peers.remove(&victim);
+                    evicted = Some(victim);
+                }
+                None => {
+                    warn!("refusing {new_peer:?}: PeerStore is full");
+                    return false;
                 }
             }
         }
+        let added = peers.insert(PeerStoreEntry::new(new_peer.clone()));
+        drop(peers);
 
-        Ok(count)
+        // Don't call out to the application while holding the lock
+        if let Some(victim) = evicted {
+            self.hooks.on_peer_removed(&victim);
+        }
+        if added {
+            self.hooks.on_peer_added(&new_peer);
+        }
+        added
     }
 
-    /// Handle a new incoming connection (a request)
-    /// TOOD: convert this function into async
-    fn handle_conn(mut self, mut conn: TcpStream) -> Result<Self, Error> {
-        let peers = self.peers.clone();
-        thread::spawn(move || -> Result<Self, Error> {
-            let mut buf = vec![0u8; MAX_TRANSFER_SIZE];
-            let len = conn.read(&mut buf)?;
-            let request = bincode::deserialize::<Request>(&buf[0..len]).unwrap();
+    /// Ping `stale`, the peer heard from longest ago in a full k-bucket, in
+    /// the background. If it answers it stays, and `newcomer` is dropped.
+    /// Otherwise `newcomer` takes its place. Joins to whether it did
+    fn challenge(&self, stale: PeerId, newcomer: PeerId) -> thread::JoinHandle<bool> {
+        if !self.challenged.lock().insert(stale.clone()) {
+            return thread::spawn(|| false);
+        }
+        let mut peer = self.clone();
+        thread::spawn(move || {
+            let pong = peer.batcher.request(&stale, Request::Ping);
+            let answered = matches!(pong, Ok(Response::Pong));
+            peer.peers.lock().record_ping(&stale, answered);
+            let replaced =
+                !answered && peer.remove_peer(&stale) && peer.add_peer(newcomer);
+            peer.challenged.lock().remove(&stale);
+            replaced
+        })
+    }
 
-            info!("handling request {request:?} from {conn:?}");
+    /// Add peers another peer told us about, in the background. Only a
+    /// sample of the ones we don't know yet is considered, and each is
+    /// only added once it has answered an Identity request as the peer it
+    /// claims to be. Peers the sender last saw too long ago are skipped.
+    /// Joins to the number of peers added
+    pub fn learn_peers(&self, store: &PeerStore) -> thread::JoinHandle<usize> {
+        // Verifying is best effort, so skip it while memory is tight
+        if self.memory.under_pressure() {
+            return thread::spawn(|| 0);
+        }
+        // The sender stamped last_seen by its own clock, which the
+        // network's clock is the best guess at
+        let cutoff =
+            self.network_now() - chrono::Duration::from_std(MAX_GOSSIP_AGE).unwrap();
+        let candidates: Vec<PeerId> = {
+            let peers = self.peers.lock();
+            store
+                .recent()
+                .filter(|entry| entry.last_seen.is_none_or(|t| t >= cutoff))
+                .map(|entry| entry.id.clone())
+                .filter(|id| *id != self.id && !peers.contains(id))
+                .filter(|id| !self.greylist.lock().contains(id))
+                .take(VERIFY_SAMPLE)
+                .collect()
+        };
 
-            // Call the handlers defined in Protocol impl
-            match request {
-                Request::Ping => {
-                    self.handle_ping(&mut conn)?;
-                }
-                Request::Identity => {
-                    self.handle_identity(&mut conn)?;
-                }
-                Request::Join(id) => {
-                    self.handle_join(&mut conn, id)?;
-                }
-                Request::PeerStore => {
-                    self.handle_peerstore(&mut conn)?;
+        let mut peer = self.clone();
+        thread::spawn(move || {
+            let checks: Vec<_> = candidates
+                .into_iter()
+                .map(|id| {
+                    let batcher = peer.batcher.clone();
+                    thread::spawn(move || (Peer::verify_identity(&batcher, &id), id))
+                })
+                .collect();
+
+            let mut added = 0;
+            for check in checks {
+                match check.join() {
+                    Ok((Ok(()), id)) => {
+                        if peer.add_peer(id.clone()) {
+                            peer.mark_seen(&id);
+                            added += 1;
+                        }
+                    }
+                    Ok((Err(e), id)) => {
+                        info!("not adding {id:?}: {e}");
+                        peer.strike(&id);
+                    }
+                    Err(_) => (),
                 }
-                _ => todo!(),
             }
-
-            Ok(self)
+            added
         })
-        .join()
-        .unwrap()
     }
 
-    /// Handle a response
-    fn handle_response(&self, mut conn: TcpStream) -> Result<(), Error> {
-        info!("handling response from conn {conn:?}");
+    /// Count a strike against a peer. Once it has too many it is
+    /// greylisted, and dropped from the PeerStore unless it is an anchor
+    fn strike(&self, id: &PeerId) {
+        if !self.greylist.lock().strike(id) || self.anchors.contains(id) {
+            return;
+        }
+        warn!("greylisting {id:?} for {GREYLIST_COOLDOWN:?}");
+        self.remove_peer(id);
+    }
 
-        thread::spawn(move || -> Result<(), Error> {
-            let mut buf = Vec::new();
-            conn.read_to_end(&mut buf)?; // Can read to end because socket closes
+    /// Drop a peer from the PeerStore. Returns false if it wasn't there
+    pub(crate) fn remove_peer(&self, id: &PeerId) -> bool {
+        let removed = self.peers.lock().remove(id).is_some();
+        if removed {
+            self.hooks.on_peer_removed(id);
+        }
+        removed
+    }
 
-            let response = bincode::deserialize::<Response>(&buf[..]).unwrap();
-            info!("handling response {response:?} from conn {conn:?}");
+    /// The peers being ignored for now, and how long until each is
+    /// considered again
+    pub fn greylisted(&self) -> Vec<(PeerId, Duration)> {
+        self.greylist.lock().active()
+    }
 
-            // Call the handlers defined in Protocol impl
-            match &response {
-                Response::Pong => info!("got a pong from {conn:?}!"),
-                _ => todo!(),
-            };
-            Ok(())
-        })
-        .join()
-        .unwrap()
+    /// Check that a peer is reachable and is who its PeerId says: it must
+    /// answer with the same id, and that id must be the hash of its address.
+    /// The question goes through the batcher, like the rest of PEX
+    fn verify_identity(batcher: &Batcher, id: &PeerId) -> NetworkResult<()> {
+        if !id.verify_derivation() {
+            return Err(NetworkError::Fail("id does not match address".to_string()));
+        }
+        match batcher.request(id, Request::Identity)? {
+            Response::Identity(got) if got.id == id.id => Ok(()),
+            res => Err(NetworkError::Fail(format!(
+                "answered Identity with {res:?}"
+            ))),
+        }
     }
 
-    /// Attempt to find a route to the given PeerId
-    // TODO: Eventually make this recursive with a supplied depth??
-    fn router(&self, peer: PeerId) -> Option<PeerId> {
-        // If the desired peer is us, return ourself
-        if peer == self.id {
-            return Some(self.id.clone());
+    /// Add an anchor peer, which is kept in the PeerStore regardless of
+    /// eviction pressure and periodically re-verified
+    pub fn add_anchor(&mut self, anchor: PeerId) -> bool {
+        if anchor == self.id {
+            return false;
         }
+        self.anchors.insert(anchor.clone());
+        self.add_peer(anchor)
+    }
 
-        // If not, check if the desired peer is in our PeerStore, and
-        // return it
-        self.peers
-            .clone()
-            .lock()
-            .unwrap()
-            .get(&PeerStoreEntry::new(peer))
-            .cloned()
-            .map(|p| p.id)
+    /// Pick up to `n` peers to fan a query out to, spreading the picks
+    /// across as many subnets as possible
+    /// Ask up to QUERY_FANOUT known peers who holds `key`, each forwarding
+    /// the query up to `tts - 1` more hops, until `wanted` holders are
+    /// found or time runs out
+    pub(crate) fn query_holders(
+        &self,
+        key: &Key,
+        tts: u16,
+        wanted: usize,
+    ) -> Vec<PeerId> {
+        // Sending a query back where it came from is harmless, as the
+        // sender has already seen its trace
+        let targets = self.fanout_targets(QUERY_FANOUT);
+        let trace = trace::current();
+        let timeout = trace::time_left(QUERY_HOP_TIMEOUT * tts as u32);
+        if tts == 0 || timeout.is_zero() {
+            return Vec::new();
+        }
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = mpsc::channel();
+        for target in targets {
+            let (tx, key) = (tx.clone(), key.clone());
+            thread::spawn(move || {
+                let query = || {
+                    let req = Request::QueryKey { key, tts: tts - 1 };
+                    let mut conn = Peer::send_request_timeout(&target, req, timeout)?;
+                    Peer::recv_response(&mut conn)
+                };
+                let res = match trace {
+                    Some(trace) => trace::with_trace(trace, query),
+                    None => query(),
+                };
+                let _ = tx.send(res);
+            });
+        }
+        drop(tx);
+
+        // The channel closes once every forward has answered or given up.
+        // Forwards still going at the deadline are left to finish unheard
+        let mut holders = Vec::new();
+        while let Ok(res) =
+            rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if let Ok(Response::RespondKey { holding_id, .. }) = res {
+                holders.push(holding_id);
+                if holders.len() >= wanted {
+                    break;
+                }
+            }
+        }
+        holders
     }
 
-    /* Public functions define interface to Peer */
+    pub(crate) fn fanout_targets(&self, n: usize) -> Vec<PeerId> {
+        let peers = self.peers.lock();
 
-    /// Send a ping to all nodes in the peerstore
-    pub fn send_pings(&self) -> Result<(), Error> {
-        let inner_peers = self.peers.clone();
-        let peers = inner_peers.lock().unwrap();
-        for id in peers.iter().map(|peer| peer.id.clone()) {
-            self.send_ping(&id)?;
+        // Group peers by subnet, then take the best of each group in turn
+        let mut groups: Vec<Vec<PeerId>> = peers
+            .subnets()
+            .map(|subnet| {
+                let mut group: Vec<&PeerStoreEntry> = peers.in_subnet(subnet).collect();
+                group.sort_by_key(|p| self.scorer.score(p));
+                group.into_iter().map(|p| p.id.clone()).collect()
+            })
+            .collect();
+
+        let mut targets = Vec::new();
+        while targets.len() < n && groups.iter().any(|g| !g.is_empty()) {
+            for group in groups.iter_mut() {
+                if let Some(id) = group.pop() {
+                    if targets.len() < n {
+                        targets.push(id);
+                    }
+                }
+            }
         }
-        Ok(())
+        targets
     }
 
-    /// Send a ping request to a peer
-    pub fn send_ping(&self, to: &PeerId) -> Result<(), Error> {
-        let conn = Peer::send_request(&to, Request::Ping)?;
-        self.handle_response(conn)
+    /// Lock the PeerStore, giving up after a while so a stuck lock holder
+    /// cannot hang request handlers forever
+    pub(crate) fn lock_peers(&self) -> NetworkResult<MutexGuard<'_, PeerStore>> {
+        self.peers
+            .try_lock_for(trace::time_left(PEER_LOCK_TIMEOUT))
+            .ok_or_else(|| NetworkError::Fail("PeerStore is busy".to_string()))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Record that a known peer was just heard from
+    pub(crate) fn mark_seen(&self, id: &PeerId) {
+        self.peers.lock().touch(id);
+    }
 
-    #[test]
-    fn test_peer_id() {
-        let id1 = PeerId::from("127.0.0.1".parse().unwrap(), 3300);
-        println!("{id1:?}");
-        assert!(id1.ip() == id1.ip);
-        assert!(id1.port() == id1.port);
+    /// Start listening on this peer. Blocks until `stop` is called. Local
+    /// tools can drive it with `rpc::RpcServer` under the `rpc` feature
+    pub fn start(self, send_pings: bool) -> Result<(), Error> {
+        let node = self.clone();
+        let res = self.run(send_pings);
+        node.set_state(State::Stopped);
+        res
     }
 
-    #[test]
-    fn test_bootstrap() {
-        let mut peer = Peer::new(true, 3300).unwrap();
-        peer.bootstrap().unwrap();
-        println!("peer: {:#?}", peer);
+    /// Bootstrap, bind, then serve until `stop` is called
+    fn run(mut self, send_pings: bool) -> Result<(), Error> {
+        let mut sockets = match self.setup(send_pings)? {
+            Some(sockets) => sockets,
+            None => return Ok(()),
+        };
+
+        // Run an accept loop per socket, the last one on this thread
+        let last = sockets.pop().unwrap();
+        let others: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| {
+                let node = self.clone();
+                thread::spawn(move || node.serve(socket))
+            })
+            .collect();
+        let node = self.clone();
+        let mut res = self.serve(last);
+        for acceptor in others {
+            match acceptor.join() {
+                Ok(Err(e)) if res.is_ok() => res = Err(e),
+                Err(_) => error!("an accept loop panicked"),
+                _ => (),
+            }
+        }
+        node.shutdown()?;
+        res
     }
 
-    #[test]
-    fn add_peer() {
-        let mut peer = Peer::new(true, 9900).unwrap();
+    /// Bootstrap, bind, and start the background tasks, leaving the peer
+    /// Ready to accept on the returned sockets. Returns None if the peer
+    /// was stopped while bootstrapping
+    fn setup(&mut self, send_pings: bool) -> Result<Option<Vec<TcpListener>>, Error> {
+        self.set_state(State::Bootstrapping);
+        self.bootstrap()?; // Bootstrap this peer
+        if self.state() >= State::Draining {
+            return Ok(None);
+        }
 
-        peer.add_peer(PeerId::from("127.0.0.1".parse().unwrap(), 3300));
-        peer.add_peer(PeerId::from("127.0.0.1".parse().unwrap(), 3300));
-        peer.add_peer(PeerId::from("192.168.1.12".parse().unwrap(), 9954));
-        peer.add_peer(PeerId::from("192.168.1.82".parse().unwrap(), 9900));
+        let addr = SocketAddr::from((self.id.ip, self.id.port));
+        let sockets = transport::listen_all(addr, &self.socket_opts)?;
+        self.set_state(State::Listening);
+        info!("starting peer {:#?}", self);
+        info!("bound peer on socket {:?}", self.id.as_socket());
 
-        println!("{peer:#?}");
+        // Say hello to everyone known, unless a control API client will
+        if send_pings {
+            self.send_pings()?;
+        }
+
+        // Tell friends we are back, and pick up the messages they held
+        // while we were away
+        let node = self.clone();
+        thread::spawn(move || {
+            node.announce_presence();
+            node.collect_messages();
+        });
+
+        // Periodically persist the best known peers
+        let node = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(PEER_CACHE_INTERVAL);
+            if node.state() >= State::Draining {
+                break;
+            }
+            if let Err(e) = save_peer_cache(&node.peers.lock(), &node.peer_cache) {
+                warn!("could not save peer cache: {e}");
+            }
+        });
+
+        // Periodically snapshot metrics, to follow trends across restarts
+        let node = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(METRICS_INTERVAL);
+            if node.state() >= State::Draining {
+                break;
+            }
+            node.save_snapshot();
+        });
+
+        // Periodically re-verify anchor peers
+        let anchors: Vec<PeerId> = self.anchors.iter().cloned().collect();
+        let node = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(ANCHOR_INTERVAL);
+            if node.state() >= State::Draining {
+                break;
+            }
+            for anchor in anchors.iter() {
+                match node.probe_join(anchor) {
+                    Ok(id) => node.peers.lock().touch(&id),
+                    Err(e) => warn!("anchor peer {anchor:?} failed verification: {e}"),
+                }
+            }
+        });
+
+        // Notice if we fall off the network
+        let watcher = self.clone();
+        thread::spawn(move || watcher.watch_network());
+
+        self.acceptors.store(sockets.len(), Ordering::SeqCst);
+        self.set_state(State::Ready);
+        Ok(Some(sockets))
+    }
+
+    /// Save the peer cache and metrics once more on the way out
+    fn shutdown(&self) -> Result<(), Error> {
+        save_peer_cache(&self.peers.lock(), &self.peer_cache)?;
+        self.save_snapshot();
+        Ok(())
+    }
+
+    /// Ping peers as they come due, and watch for this peer losing contact
+    /// with every known peer. While disconnected, keep re-bootstrapping with
+    /// exponential backoff until the network is reachable again
+    fn watch_network(mut self) {
+        let mut up = true;
+        let mut wait = HEALTH_INTERVAL;
+        loop {
+            thread::sleep(wait);
+            if self.state() >= State::Draining {
+                break;
+            }
+
+            if up {
+                self.probe_peers(false);
+
+                // Still online as long as someone answered their last ping
+                let peers = self.peers.lock();
+                if peers.is_empty() || peers.iter().any(|p| p.failures == 0) {
+                    continue;
+                }
+                drop(peers);
+                warn!("lost contact with every peer, reconnecting");
+                event::emit(&self.events, Event::NetworkDown);
+                up = false;
+            }
+
+            match self.bootstrap() {
+                Ok(n) if n > 0 || self.probe_peers(true) > 0 => {
+                    info!("reconnected to the network");
+                    event::emit(&self.events, Event::NetworkUp);
+                    up = true;
+                    wait = HEALTH_INTERVAL;
+                }
+                _ => {
+                    wait = (wait * 2).min(MAX_REJOIN_BACKOFF);
+                    info!("still offline, retrying in {wait:?}");
+                }
+            }
+        }
+    }
+
+    /// Ping known peers in parallel, returning how many answered. Unless
+    /// `all` is set, only peers that are due a ping are pinged
+    fn probe_peers(&self, all: bool) -> usize {
+        let ids: Vec<PeerId> = {
+            let now = chrono::Utc::now().naive_utc();
+            let peers = self.peers.lock();
+            peers
+                .iter()
+                .filter(|p| all || p.ping_due(now))
+                .map(|p| p.id.clone())
+                .collect()
+        };
+        let probes: Vec<_> = ids
+            .into_iter()
+            .map(|id| {
+                let batcher = self.batcher.clone();
+                thread::spawn(move || {
+                    let sent = Instant::now();
+                    let pong = batcher.request(&id, Request::Ping);
+                    let answered = matches!(pong, Ok(Response::Pong));
+                    (id, answered, sent.elapsed())
+                })
+            })
+            .collect();
+
+        let mut alive = 0;
+        for probe in probes {
+            if let Ok((id, answered, rtt)) = probe.join() {
+                let mut peers = self.peers.lock();
+                let dropped = peers.get(&id).is_some_and(|p| p.failures > 0);
+                peers.record_ping(&id, answered);
+                if answered {
+                    peers.record_rtt(&id, rtt);
+                }
+                drop(peers);
+
+                // Dropping off and coming back is flapping
+                if answered && dropped {
+                    self.strike(&id);
+                }
+                alive += answered as usize;
+            }
+        }
+        alive
+    }
+
+    /// Accept and handle incoming connections until the peer is stopped
+    fn serve(self, socket: TcpListener) -> Result<(), Error> {
+        let acceptors = self.acceptors.clone();
+        let res = self.accept_loop(socket);
+        acceptors.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    fn accept_loop(self, socket: TcpListener) -> Result<(), Error> {
+        info!("listening for incoming connections");
+        // Listen for new incoming connections (requests)
+        for stream in socket.incoming() {
+            if self.state() >= State::Draining {
+                info!("draining, no longer accepting connections");
+                break;
+            }
+            // Accept errors are usually transient (a reset handshake, or out
+            // of file descriptors), so never let one take the peer down
+            match stream {
+                Ok(conn) => {
+                    metrics::record_accept(true);
+                    if let Err(e) = conn.set_nodelay(self.socket_opts.nodelay) {
+                        warn!("could not set nodelay: {e}");
+                    }
+                    let admitted = conn.peer_addr().ok().and_then(|a| self.admit(a.ip()));
+                    if let Some(admission) = admitted {
+                        let node = self.clone();
+                        thread::spawn(move || node.handle_conn(conn, admission));
+                    }
+                }
+                Err(e) => {
+                    metrics::record_accept(false);
+                    warn!("failed to accept a connection: {e}");
+                    thread::sleep(ACCEPT_ERROR_BACKOFF);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `start`, but serves on the tokio runtime it is awaited on. Each
+    /// connection's handshake, request and response go over async io, and
+    /// only the Protocol handler runs on a blocking task, since handlers
+    /// still call other peers through Transport. Resolves once `stop` is
+    /// called
+    #[cfg(feature = "async")]
+    pub async fn start_async(self, send_pings: bool) -> Result<(), Error> {
+        let node = self.clone();
+        let res = self.run_async(send_pings).await;
+        node.set_state(State::Stopped);
+        res
+    }
+
+    #[cfg(feature = "async")]
+    async fn run_async(mut self, send_pings: bool) -> Result<(), Error> {
+        // Bootstrapping dials and waits, so keep it off the runtime threads
+        let (node, sockets) = tokio::task::spawn_blocking(move || {
+            self.setup(send_pings).map(|sockets| (self, sockets))
+        })
+        .await
+        .map_err(io::Error::from)??;
+        let sockets = match sockets {
+            Some(sockets) => sockets,
+            None => return Ok(()),
+        };
+
+        let acceptors: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| tokio::spawn(node.clone().serve_async(socket)))
+            .collect();
+        let mut res = Ok(());
+        for acceptor in acceptors {
+            match acceptor.await {
+                Ok(Err(e)) if res.is_ok() => res = Err(e),
+                Err(_) => error!("an accept loop panicked"),
+                _ => (),
+            }
+        }
+        tokio::task::spawn_blocking(move || node.shutdown())
+            .await
+            .map_err(io::Error::from)??;
+        res
+    }
+
+    #[cfg(feature = "async")]
+    async fn serve_async(self, socket: TcpListener) -> Result<(), Error> {
+        let acceptors = self.acceptors.clone();
+        let res = self.accept_loop_async(socket).await;
+        acceptors.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    #[cfg(feature = "async")]
+    async fn accept_loop_async(self, socket: TcpListener) -> Result<(), Error> {
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::TcpListener::from_std(socket)?;
+        info!("listening for incoming connections");
+        loop {
+            let accepted = socket.accept().await;
+            if self.state() >= State::Draining {
+                info!("draining, no longer accepting connections");
+                break;
+            }
+            match accepted {
+                Ok((conn, _)) => {
+                    metrics::record_accept(true);
+                    if let Err(e) = conn.set_nodelay(self.socket_opts.nodelay) {
+                        warn!("could not set nodelay: {e}");
+                    }
+                    let admitted = conn.peer_addr().ok().and_then(|a| self.admit(a.ip()));
+                    if let Some(admission) = admitted {
+                        tokio::spawn(self.clone().handle_conn_async(conn, admission));
+                    }
+                }
+                Err(e) => {
+                    metrics::record_accept(false);
+                    warn!("failed to accept a connection: {e}");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a new connection against the inbound limits. If it is over
+    /// them, close it without so much as a handshake. Busy isn't sent, as
+    /// it could only go out after a handshake, and doing one for every
+    /// shed connection would cost the work shedding is there to save
+    fn admit(&self, source: IpAddr) -> Option<Admission> {
+        if let Some(admission) = self.inbound.admit(source) {
+            return Some(admission);
+        }
+        warn!("shedding a connection from {source}: over the inbound limits");
+        metrics::record_shed();
+        None
+    }
+
+    /// Append a snapshot of this peer's metrics to the metrics history
+    fn save_snapshot(&self) {
+        let snapshot = Snapshot::take(self.uptime().as_secs(), self.peers.lock().len());
+        if let Err(e) = snapshot.save(&self.metrics_file) {
+            warn!("could not save metrics snapshot: {e}");
+        }
+    }
+
+    /// Warn if this machine's clock looks far off from the network's
+    fn check_clock(&self) {
+        if let Some(offset) = self.clock_offset() {
+            let skew = offset.num_milliseconds().unsigned_abs();
+            if skew > MAX_CLOCK_SKEW.as_millis() as u64 {
+                warn!("this machine's clock is about {offset} off from its peers; check its time settings");
+            }
+        }
+    }
+
+    /// How far the network's clock is estimated to be ahead of this
+    /// machine's, from samples taken while joining peers
+    pub fn clock_offset(&self) -> Option<chrono::Duration> {
+        self.clock
+            .lock()
+            .estimate()
+            .map(chrono::Duration::milliseconds)
+    }
+
+    /// The current time by the network's clock. Use this when judging
+    /// timestamps and expiries set by other peers
+    pub fn network_now(&self) -> chrono::NaiveDateTime {
+        let now = chrono::Utc::now().naive_utc();
+        now + self.clock_offset().unwrap_or_else(chrono::Duration::zero)
+    }
+
+    /// Which phase of its lifecycle this peer is in
+    pub fn state(&self) -> State {
+        *self.state.lock()
+    }
+
+    /// Move this peer forward to a new phase, telling subscribers. Moving
+    /// backwards, or to the current phase, does nothing
+    fn set_state(&self, next: State) -> bool {
+        let mut state = self.state.lock();
+        if next <= *state {
+            return false;
+        }
+        info!("peer {:?} is {next}", self.id);
+        *state = next;
+        drop(state);
+        event::emit(&self.events, Event::StateChanged(next));
+        true
+    }
+
+    /// Ask a started peer to stop. It finishes the request it is handling,
+    /// saves its peer cache, and `start` returns
+    pub fn stop(&self) {
+        if !self.set_state(State::Draining) {
+            return;
+        }
+
+        // Wake the accept loops so they notice. Each one exits on the first
+        // connection it sees, and which loop gets a connection is up to the
+        // kernel, so keep dialing until they are all gone
+        let addr = SocketAddr::from((self.id.ip, self.id.port));
+        let deadline = Instant::now() + DIAL_TIMEOUT;
+        while self.acceptors.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            let _ = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Send a message to the identity `to`, through `via`: the peer `to`
+    /// is known by if it is online, or else a peer both are friends with,
+    /// to hold the message until `to` collects it
+    pub fn send_message(
+        &self,
+        via: &PeerId,
+        to: &PublicKey,
+        body: &[u8],
+    ) -> Result<(), Error> {
+        let identity = self.identity().ok_or_else(|| {
+            Error::BadIdentity("messages are sent from an identity".to_string())
+        })?;
+        let message = Message::seal(identity, to, body)?;
+        let mut conn = Peer::send_request(via, Request::Message(message))?;
+        match Peer::recv_response(&mut conn)? {
+            Response::Ok => Ok(()),
+            Response::Err(e) => Err(e.into()),
+            res => Err(NetworkError::Fail(format!("{via:?} answered {res:?}")).into()),
+        }
+    }
+
+    /// The live peers in the PeerStore known by a friend's key
+    fn friends_online(&self) -> Vec<PeerId> {
+        let friends = self.friends.lock();
+        let peers = self.peers.lock();
+        peers
+            .live()
+            .map(|entry| entry.id().clone())
+            .filter(|id| id.key().is_some_and(|k| friends.contains(k)))
+            .collect()
+    }
+
+    /// Set the status friends see, from the next time this peer tells them
+    /// it is online
+    pub fn set_status(&self, status: &str) {
+        *self.status.lock() = status.to_string();
+    }
+
+    /// The status each friend that came online last advertised
+    pub fn statuses(&self) -> HashMap<PublicKey, String> {
+        self.statuses.lock().clone()
+    }
+
+    /// Tell the friends in the PeerStore this peer is online, learning
+    /// their statuses in return. Each side gets an `Event::FriendOnline`.
+    /// Returns the number of friends told
+    pub fn announce_presence(&self) -> usize {
+        let key = match self.identity() {
+            Some(identity) => identity.public_key(),
+            None => return 0,
+        };
+        let status = self.status.lock().clone();
+
+        let mut told = 0;
+        for friend in self.friends_online() {
+            let request = Request::Presence {
+                from: key,
+                status: status.clone(),
+            };
+            let res = Peer::send_request_as(
+                &friend,
+                request,
+                DIAL_TIMEOUT,
+                Some(&self.noise_secret),
+            )
+            .and_then(|mut conn| Peer::recv_response(&mut conn));
+            match res {
+                // The friend proved it holds its key in the handshake
+                Ok(Response::Presence(status)) if status.len() <= MAX_STATUS_LEN => {
+                    let from = *friend.key().unwrap();
+                    self.statuses.lock().insert(from, status.clone());
+                    event::emit(&self.events, Event::FriendOnline { from, status });
+                    told += 1;
+                }
+                Ok(res) => warn!("could not tell {friend:?} we are online: {res:?}"),
+                Err(e) => warn!("could not tell {friend:?} we are online: {e}"),
+            }
+        }
+        told
+    }
+
+    /// Collect the messages friends in the PeerStore hold for this peer's
+    /// identity, delivering each as an `Event::Message`. Returns the number
+    /// of messages delivered
+    pub fn collect_messages(&self) -> usize {
+        let key = match self.identity() {
+            Some(identity) => identity.public_key(),
+            None => return 0,
+        };
+        let mut delivered = 0;
+        for holder in self.friends_online() {
+            let messages = Peer::send_request_as(
+                &holder,
+                Request::Collect(key),
+                DIAL_TIMEOUT,
+                Some(&self.noise_secret),
+            )
+            .and_then(|mut conn| Peer::recv_response(&mut conn));
+            let messages = match messages {
+                Ok(Response::Messages(messages)) => messages,
+                Ok(res) => {
+                    warn!("could not collect messages from {holder:?}: {res:?}");
+                    continue;
+                }
+                Err(e) => {
+                    warn!("could not collect messages from {holder:?}: {e}");
+                    continue;
+                }
+            };
+            for message in messages.into_iter().filter(|m| m.to == key) {
+                match self.handle_message(message) {
+                    Ok(Response::Ok) => delivered += 1,
+                    res => warn!("dropped a message {holder:?} held: {res:?}"),
+                }
+            }
+        }
+        delivered
+    }
+
+    /// The membership record this peer holds for a group it is in. Records
+    /// are kept in the store, under the group's namespace, so they last as
+    /// long as the values published there
+    pub fn membership(&self, group: &PublicKey) -> Option<Membership> {
+        let record = self.get(&group::key(group, "")).ok()??;
+        bincode::deserialize(&record).ok()
+    }
+
+    /// Take a membership record if it is signed by the group key and newer
+    /// than the one held. A peer only starts holding a group's record once
+    /// it is a member, and forgets the group once a record leaves it out
+    pub(crate) fn adopt_membership(
+        &self,
+        record: &Membership,
+    ) -> Result<(), NetworkError> {
+        let me = match self.identity() {
+            Some(identity) => identity.public_key(),
+            None => {
+                return Err(NetworkError::Rejected(
+                    Reason::Unsupported,
+                    "this peer has no identity".to_string(),
+                ))
+            }
+        };
+        if !record.verify() {
+            return Err(NetworkError::Rejected(
+                Reason::Unauthorized,
+                format!("not signed by group {}", record.group),
+            ));
+        }
+        match self.membership(&record.group) {
+            Some(held) if held.version >= record.version => {
+                return Err(NetworkError::Rejected(
+                    Reason::Duplicate,
+                    format!("already at version {}", held.version),
+                ))
+            }
+            None if !record.is_member(&me) => {
+                return Err(NetworkError::Rejected(
+                    Reason::Unauthorized,
+                    format!("this peer is not in group {}", record.group),
+                ))
+            }
+            _ => (),
+        }
+
+        let key = group::key(&record.group, "");
+        let stored = if record.is_member(&me) {
+            self.put(key, bincode::serialize(record)?).map(|_| ())
+        } else {
+            info!("left group {}", record.group);
+            self.delete(&key).map(|_| ())
+        };
+        stored.map_err(|e| NetworkError::Fail(format!("storing membership: {e}")))
+    }
+
+    /// Store a value a member published into a group this peer is in.
+    /// Returns whether it was new
+    pub(crate) fn adopt_publication(
+        &self,
+        publication: &Publication,
+    ) -> Result<bool, NetworkError> {
+        let unauthorized = |why| Err(NetworkError::Rejected(Reason::Unauthorized, why));
+        let membership = match self.membership(&publication.group) {
+            Some(membership) => membership,
+            None => {
+                return unauthorized(format!(
+                    "this peer is not in group {}",
+                    publication.group
+                ))
+            }
+        };
+        if publication.name.is_empty() {
+            return Err(NetworkError::Rejected(
+                Reason::Malformed,
+                "published values need a name".to_string(),
+            ));
+        }
+        if !publication.verify() || !membership.is_member(&publication.from) {
+            return unauthorized(format!(
+                "not published by a member of group {}",
+                publication.group
+            ));
+        }
+
+        let key = publication.key();
+        let fail = |e: Error| NetworkError::Fail(format!("storing {key}: {e}"));
+        if self.get(&key).map_err(fail)?.as_ref() == Some(&publication.value) {
+            return Ok(false);
+        }
+        self.put(key.clone(), publication.value.clone())
+            .map_err(fail)?;
+        event::emit(
+            &self.events,
+            Event::Published {
+                group: publication.group,
+                name: publication.name.clone(),
+                from: publication.from,
+            },
+        );
+        Ok(true)
+    }
+
+    /// Send a request to every member of a group in the PeerStore but this
+    /// peer. Returns the number that took it
+    pub(crate) fn push_to_members<F>(&self, group: &PublicKey, request: F) -> usize
+    where
+        F: Fn() -> Request,
+    {
+        let members = match self.membership(group) {
+            Some(membership) => membership.members,
+            None => return 0,
+        };
+        let online: Vec<PeerId> = {
+            let peers = self.peers.lock();
+            peers
+                .live()
+                .map(|entry| entry.id().clone())
+                .filter(|id| id.key().is_some_and(|k| members.contains(k)))
+                .filter(|id| *id != self.id)
+                .collect()
+        };
+
+        let mut took = 0;
+        for member in online {
+            let res = Peer::send_request(&member, request())
+                .and_then(|mut conn| Peer::recv_response(&mut conn));
+            match res {
+                Ok(Response::Ok) => took += 1,
+                Ok(res) => info!("{member:?} did not take {}: {res:?}", request().kind()),
+                Err(e) => warn!("could not reach {member:?}: {e}"),
+            }
+        }
+        took
+    }
+
+    /// Take a membership record for a group, and pass it on to the members
+    /// in the PeerStore, who pass it on in turn. The group's owner calls
+    /// this with each new record. Returns the number of members that took it
+    pub fn share_membership(&self, record: Membership) -> Result<usize, Error> {
+        self.adopt_membership(&record)?;
+        let group = record.group;
+        Ok(self.push_to_members(&group, || Request::Membership(record.clone())))
+    }
+
+    /// Publish a value under `name` in a group's namespace, and push it to
+    /// the members in the PeerStore, who pass it on to the members they
+    /// know. Returns the number of members that took it
+    pub fn publish(
+        &self,
+        group: &PublicKey,
+        name: &str,
+        value: Vec<u8>,
+    ) -> Result<usize, Error> {
+        let identity = self.identity().ok_or_else(|| {
+            Error::BadIdentity("values are published by an identity".to_string())
+        })?;
+        let publication = Publication::sign(identity, group, name, value);
+        self.adopt_publication(&publication)?;
+        Ok(self.push_to_members(group, || Request::Publish(publication.clone())))
+    }
+
+    /// Tell every known peer this peer is leaving the network, so they stop
+    /// routing to it, then stop. Returns the number of peers told
+    pub fn leave(&self) -> usize {
+        let peers: Vec<PeerId> = self.peers.lock().iter().map(|p| p.id.clone()).collect();
+        let notices: Vec<_> = peers
+            .into_iter()
+            .map(|to| {
+                let (me, secret) = (self.id.clone(), self.noise_secret);
+                thread::spawn(move || {
+                    let mut conn = Peer::send_request_as(
+                        &to,
+                        Request::Leave(me),
+                        DIAL_TIMEOUT,
+                        Some(&secret),
+                    )?;
+                    match Peer::recv_response(&mut conn)? {
+                        Response::Ok => Ok(()),
+                        res => {
+                            Err(NetworkError::Fail(format!("{to:?} answered {res:?}")))
+                        }
+                    }
+                })
+            })
+            .collect();
+        let told = notices
+            .into_iter()
+            .map(|notice| match notice.join() {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    warn!("could not tell a peer we are leaving: {e}");
+                    false
+                }
+                Err(_) => false,
+            })
+            .filter(|&told| told)
+            .count();
+        info!("told {told} peers we are leaving");
+        self.stop();
+        told
+    }
+
+    /// Read from the bootstrap file, dial every bootstrap host concurrently,
+    /// and add the hosts that answer a join request to the PeerStore.
+    /// Returns the number of live bootstrap peers acquired
+    fn bootstrap(&mut self) -> Result<i32, Error> {
+        // Read each host from the bootstrap file
+        let mut hosts = match util::read_lines(&self.bootstrap_file) {
+            Ok(lines) => parse_bootstrap(
+                lines.map_while(Result::ok),
+                self.strict_bootstrap,
+                &*self.resolver,
+            )?,
+            Err(_) => Vec::new(),
+        };
+
+        // Anchors are kept whether or not they answer right now
+        if let Ok(lines) = util::read_lines(&self.anchor_file) {
+            for anchor in parse_bootstrap(
+                lines.map_while(Result::ok),
+                self.strict_bootstrap,
+                &*self.resolver,
+            )? {
+                self.add_anchor(anchor.clone());
+                hosts.push(anchor);
+            }
+        }
+
+        // Also try the peers saved from the last run
+        if let Ok(lines) = util::read_lines(&self.peer_cache) {
+            let mut lines = lines.map_while(Result::ok).peekable();
+            match lines.peek() {
+                // Another version's entries may not mean the same thing
+                Some(first)
+                    if first.starts_with("# harbor peer cache")
+                        && *first != cache_header() =>
+                {
+                    warn!("ignoring a peer cache written by another protocol version")
+                }
+                _ => hosts.extend(parse_bootstrap(lines, false, &*self.resolver)?),
+            }
+        }
+
+        // Cannot bootstrap off of ourself, or dial anyone twice
+        let mut unique = HashSet::new();
+        hosts.retain(|id| *id != self.id && unique.insert(id.clone()));
+
+        // Give greylisted hosts a rest
+        hosts.retain(|id| !self.greylist.lock().contains(id));
+
+        // Probe each host in parallel
+        let probes: Vec<_> = hosts
+            .into_iter()
+            .map(|host| {
+                let node = self.clone();
+                thread::spawn(move || {
+                    // Sample the clock of every host that answers
+                    let res = node
+                        .probe_join(&host)
+                        .map(|id| (id, clock::sample(&host).ok()));
+                    (host, res)
+                })
+            })
+            .collect();
+
+        let mut count = 0i32; // Number of live bootstrapped peers
+        for probe in probes {
+            match probe.join() {
+                Ok((host, Ok((id, offset)))) => {
+                    if let Some(offset) = offset {
+                        self.clock.lock().add(offset);
+                    }
+                    self.add_peer(id.clone());
+                    self.mark_seen(&id);
+                    count += 1;
+                }
+                Ok((host, Err(e))) => {
+                    warn!("dropping unreachable bootstrap peer {host:?}: {e}");
+                    self.strike(&host);
+                }
+                Err(_) => error!("a bootstrap probe panicked"),
+            }
+        }
+        info!("acquired {count} live bootstrap peers");
+        self.check_clock();
+
+        Ok(count)
+    }
+
+    /// Ask a peer to add this one to its PeerStore, batched with anything
+    /// else headed its way. Any well-formed response means the peer is
+    /// alive. The peers it knows are asked for in the same batch, and the
+    /// ones that check out are added in the background. Returns the id to
+    /// store the peer under: its keyed id, if it has one at that address
+    fn probe_join(&self, to: &PeerId) -> NetworkResult<PeerId> {
+        let join = self.batcher.queue(to, Request::Join(self.id.clone()));
+        let identity = self.batcher.queue(to, Request::Identity);
+        let pex = self.batcher.queue(to, Request::PeerStore);
+        let response = batch::wait(join)?;
+        info!("bootstrap peer {to:?} answered join with {response:?}");
+        if let Ok(Response::PeerStore(store)) = batch::wait(pex) {
+            self.learn_peers(&store);
+        }
+        match batch::wait(identity) {
+            Ok(Response::Identity(id))
+                if id.key().is_some()
+                    && id.as_socket() == to.as_socket()
+                    && id.verify_derivation() =>
+            {
+                Ok(id)
+            }
+            _ => Ok(to.clone()),
+        }
+    }
+
+    /// Handle a new incoming connection (a request), holding its place in
+    /// the inbound limits until it is done. A handler that fails or panics
+    /// is logged, and the peer carries on
+    fn handle_conn(self, conn: TcpStream, admission: Admission) {
+        let remote = conn.peer_addr().ok();
+        let handler = self.clone();
+        match panic::catch_unwind(AssertUnwindSafe(|| handler.handle_request(conn))) {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("request from {remote:?} failed: {e}"),
+            Err(panic) => self.handler_panicked(remote, panic),
+        }
+        drop(admission);
+    }
+
+    /// Count a handler that panicked against the connection it was serving
+    fn handler_panicked(&self, remote: Option<SocketAddr>, panic: Box<dyn Any + Send>) {
+        let msg = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        error!("handler for request from {remote:?} panicked: {msg}");
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+        if let Some(addr) = remote {
+            self.penalize(addr.ip());
+        }
+    }
+
+    /// Handle a connection accepted on the runtime like `handle_conn`. With
+    /// TLS on, the connection is handed to `handle_conn` on a blocking task,
+    /// as AsyncConn only speaks Noise
+    #[cfg(feature = "async")]
+    async fn handle_conn_async(self, conn: tokio::net::TcpStream, admission: Admission) {
+        let remote = conn.peer_addr().ok();
+        #[cfg(feature = "tls")]
+        if crate::tls::config().is_some() {
+            let conn = match conn
+                .into_std()
+                .and_then(|c| c.set_nonblocking(false).map(|_| c))
+            {
+                Ok(conn) => conn,
+                Err(e) => return warn!("could not hand off {remote:?}: {e}"),
+            };
+            let _ =
+                tokio::task::spawn_blocking(move || self.handle_conn(conn, admission))
+                    .await;
+            return;
+        }
+        if let Err(e) = self.handle_request_async(conn).await {
+            warn!("request from {remote:?} failed: {e}");
+        }
+        drop(admission);
+    }
+
+    /// Handshake, read a request and answer it like `handle_request`, on
+    /// async io. The handler runs on a blocking task with the same budget
+    #[cfg(feature = "async")]
+    async fn handle_request_async(
+        self,
+        conn: tokio::net::TcpStream,
+    ) -> Result<(), Error> {
+        let mut conn = AsyncConn::accept(conn, &self.noise_secret).await?;
+        let read =
+            transport::read_frame_within_async(&mut conn, &self.memory, HANDLER_BUDGET)
+                .await
+                .map_err(NetworkError::from)
+                .and_then(|(buf, reservation)| {
+                    Ok((transport::parse_request(&buf)?, reservation))
+                });
+        let (envelope, _request_memory) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                if let Some((reason, detail)) = e.rejection() {
+                    Peer::refuse_async(&mut conn, reason, detail).await;
+                }
+                return Err(e.into());
+            }
+        };
+        let from = conn.peer_addr()?.ip();
+        let remote = conn.remote();
+        let Envelope { trace, request } = envelope;
+
+        info!("handling request {request:?} from {conn:?}");
+
+        // Gossip can wait while memory is tight
+        if request.class() == TrafficClass::Gossip && self.memory.under_pressure() {
+            info!("deferring {} from {from}: memory is tight", request.kind());
+            Peer::send_response_async(&mut conn, Response::Busy).await?;
+            return Ok(());
+        }
+
+        let kind = request.kind();
+        let budget = request.budget();
+        let started = Instant::now();
+        let deadline = started + budget;
+        let mut node = self.clone();
+        let handled = tokio::task::spawn_blocking(move || {
+            trace::with_deadline(deadline, || match trace {
+                Some(trace) => trace::with_trace(trace, || {
+                    info!("[trace {trace}] handling {kind} from {from}");
+                    node.dispatch(from, Some(&remote), request)
+                }),
+                None => node.dispatch(from, Some(&remote), request),
+            })
+        })
+        .await;
+        let response = match handled {
+            Ok(response) => response,
+            Err(e) if e.is_panic() => {
+                self.handler_panicked(conn.peer_addr().ok(), e.into_panic());
+                return Ok(());
+            }
+            Err(e) => return Err(io::Error::from(e).into()),
+        };
+        let elapsed = started.elapsed();
+        if elapsed > budget {
+            warn!(
+                "slow request: {kind} from {:?} took {elapsed:?} (budget {budget:?}), dropping it",
+                conn.peer_addr()
+            );
+            let detail = format!("{kind} ran over its {budget:?} budget");
+            Peer::refuse_async(&mut conn, Reason::TooSlow, detail.clone()).await;
+            return Err(NetworkError::Rejected(Reason::TooSlow, detail).into());
+        }
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                let (reason, detail) = e
+                    .rejection()
+                    .unwrap_or_else(|| (Reason::Internal, e.to_string()));
+                Peer::refuse_async(&mut conn, reason, detail).await;
+                return Err(e.into());
+            }
+        };
+        let size = bincode::serialized_size(&response)? as usize;
+        let _response_memory = match self.memory.reserve_async(size, budget).await {
+            Some(reservation) => reservation,
+            None => {
+                let detail =
+                    format!("no room in the memory budget for a {size} byte response");
+                Peer::refuse_async(&mut conn, Reason::Overloaded, detail.clone()).await;
+                return Err(NetworkError::Rejected(Reason::Overloaded, detail).into());
+            }
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        transport::within(
+            left.max(FRAME_TIMEOUT),
+            Peer::send_response_async(&mut conn, response),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Tell a requester why its request is being turned away like `refuse`
+    #[cfg(feature = "async")]
+    async fn refuse_async(conn: &mut AsyncConn, reason: Reason, detail: String) {
+        warn!(
+            "rejecting request from {:?}: {reason}: {detail}",
+            conn.peer_addr()
+        );
+        let response = Response::Err(NetworkError::Rejected(reason, detail));
+        let _ =
+            transport::within(FRAME_TIMEOUT, Peer::send_response_async(conn, response))
+                .await;
+    }
+
+    /// Handshake on a new connection, then read a request from it and
+    /// answer it
+    fn handle_request(mut self, conn: TcpStream) -> Result<(), Error> {
+        let mut conn = Conn::accept(conn, &self.noise_secret)?;
+        let (envelope, _request_memory) = match self.read_request(&mut conn) {
+            Ok(read) => read,
+            Err(e) => {
+                if let Some((reason, detail)) = e.rejection() {
+                    Peer::refuse(&mut conn, reason, detail);
+                }
+                return Err(e.into());
+            }
+        };
+        let from = conn.peer_addr()?.ip();
+        let remote = conn.remote();
+        let Envelope { trace, request } = envelope;
+
+        info!("handling request {request:?} from {conn:?}");
+
+        // Gossip can wait while memory is tight
+        if request.class() == TrafficClass::Gossip && self.memory.under_pressure() {
+            info!("deferring {} from {from}: memory is tight", request.kind());
+            Peer::send_response(&mut conn, Response::Busy)?;
+            return Ok(());
+        }
+
+        // Don't let a stalled peer hold the handler past its budget
+        let kind = request.kind();
+        let budget = request.budget();
+        conn.set_read_timeout(Some(budget))?;
+        conn.set_write_timeout(Some(budget))?;
+
+        // Handlers give up waiting on others once the budget is spent
+        let started = Instant::now();
+        let deadline = started + budget;
+        let response = trace::with_deadline(deadline, || match trace {
+            Some(trace) => trace::with_trace(trace, || {
+                info!("[trace {trace}] handling {kind} from {from}");
+                self.dispatch(from, remote.as_ref(), request)
+            }),
+            None => self.dispatch(from, remote.as_ref(), request),
+        });
+        let elapsed = started.elapsed();
+        if elapsed > budget {
+            warn!(
+                "slow request: {kind} from {:?} took {elapsed:?} (budget {budget:?}), dropping it",
+                conn.peer_addr()
+            );
+            let detail = format!("{kind} ran over its {budget:?} budget");
+            Peer::refuse(&mut conn, Reason::TooSlow, detail.clone());
+            return Err(NetworkError::Rejected(Reason::TooSlow, detail).into());
+        }
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                // Anything short of a rejection went wrong on our side. Only
+                // handlers that ran out of room say Overloaded themselves
+                let (reason, detail) = e
+                    .rejection()
+                    .unwrap_or_else(|| (Reason::Internal, e.to_string()));
+                Peer::refuse(&mut conn, reason, detail);
+                return Err(e.into());
+            }
+        };
+        let size = bincode::serialized_size(&response)? as usize;
+        let _response_memory = match self.memory.reserve(size, budget) {
+            Some(reservation) => reservation,
+            None => {
+                let detail =
+                    format!("no room in the memory budget for a {size} byte response");
+                Peer::refuse(&mut conn, Reason::Overloaded, detail.clone());
+                return Err(NetworkError::Rejected(Reason::Overloaded, detail).into());
+            }
+        };
+        Peer::send_response(&mut conn, response)?;
+        Ok(())
+    }
+
+    /// Read a request frame within the handler budget, and decode it
+    fn read_request(&self, conn: &mut Conn) -> NetworkResult<(Envelope, Reservation)> {
+        let (buf, reservation) =
+            transport::read_frame_within(conn, &self.memory, HANDLER_BUDGET)?;
+        Ok((transport::parse_request(&buf)?, reservation))
+    }
+
+    /// Tell a requester why its request is being turned away before
+    /// hanging up, if it is still listening
+    fn refuse(conn: &mut Conn, reason: Reason, detail: String) {
+        warn!(
+            "rejecting request from {:?}: {reason}: {detail}",
+            conn.peer_addr()
+        );
+        let _ = conn.set_write_timeout(Some(FRAME_TIMEOUT));
+        let _ = Peer::send_response(
+            conn,
+            Response::Err(NetworkError::Rejected(reason, detail)),
+        );
+    }
+
+    /// Count a misbehaving connection against any known peer at its address
+    fn penalize(&self, ip: IpAddr) {
+        let mut peers = self.peers.lock();
+        let offenders: Vec<PeerId> = peers
+            .iter()
+            .filter(|p| IpAddr::V4(p.id.ip) == ip)
+            .map(|p| p.id.clone())
+            .collect();
+        for id in offenders {
+            peers.update(&id, |entry| {
+                entry.streak = 0;
+                entry.failures += 1;
+            });
+        }
+    }
+
+    /// How long this peer has been up
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Number of request handlers that have panicked since this peer started
+    pub fn handler_panics(&self) -> u64 {
+        self.handler_panics.load(Ordering::Relaxed)
+    }
+
+    /// Call the handler defined in the Protocol impl for a request. `remote`
+    /// is the static key the sender proved it holds, on Noise connections
+    pub(crate) fn dispatch(
+        &mut self,
+        from: IpAddr,
+        remote: Option<&Remote>,
+        request: Request,
+    ) -> NetworkResult<Response> {
+        if self.hooks.on_request(from, &request) == Decision::Deny {
+            return Ok(Response::Err(NetworkError::Rejected(
+                Reason::Unauthorized,
+                format!("{} request denied", request.kind()),
+            )));
+        }
+
+        // Flooded requests must stay within the hop limit, and are handled
+        // once per trace however many paths they arrive by
+        if let Some(tts) = request.tts() {
+            if tts > MAX_TTS {
+                warn!(
+                    "dropping {} from {from}: tts {tts} is over {MAX_TTS}",
+                    request.kind()
+                );
+                self.penalize(from);
+                return Ok(Response::Err(NetworkError::Rejected(
+                    Reason::TooManyHops,
+                    format!("tts {tts} is over the max of {MAX_TTS}"),
+                )));
+            }
+            match trace::current() {
+                // Start a trace here so the requests this floods are tracked
+                None => {
+                    return trace::with_trace(TraceId::new(), || {
+                        self.dispatch(from, remote, request)
+                    })
+                }
+                Some(trace) if !self.seen.lock().insert(trace) => {
+                    info!(
+                        "dropping {} from {from}: trace {trace} already seen",
+                        request.kind()
+                    );
+                    return Ok(Response::Err(NetworkError::Rejected(
+                        Reason::Duplicate,
+                        "already seen".to_string(),
+                    )));
+                }
+                Some(_) => (),
+            }
+        }
+
+        match request {
+            Request::Ping => self.handle_ping(),
+            Request::Identity => self.handle_identity(),
+            Request::List => self.handle_list(),
+            Request::Get(key) => self.handle_get(key),
+            Request::Join(id) => self.handle_join(from, remote, id),
+            Request::QueryKey { key, tts } => self.handle_query_key(key, tts),
+            Request::PeerStore => self.handle_peerstore(),
+            Request::Batch(requests) => self.handle_batch(from, remote, requests),
+            Request::Stats => self.handle_stats(),
+            Request::Info => self.handle_info(),
+            Request::Time => self.handle_time(),
+            Request::DialBack { port } => self.handle_dial_back(from, port),
+            Request::FindNode(target) => self.handle_find_node(target),
+            Request::LivePeers => self.handle_live_peers(),
+            Request::Message(message) => self.handle_message(message),
+            Request::Collect(key) => self.handle_collect(remote, key),
+            Request::Membership(record) => self.handle_membership(record),
+            Request::Publish(publication) => self.handle_publish(publication),
+            Request::Presence { from, status } => {
+                self.handle_presence(remote, from, status)
+            }
+            Request::Leave(id) => self.handle_leave(from, remote, id),
+            request => Ok(Response::Err(NetworkError::Rejected(
+                Reason::Unsupported,
+                format!("{} requests are not handled", request.kind()),
+            ))),
+        }
+    }
+
+    /// Check that whoever sent a request is the peer it speaks for. Over
+    /// TLS, its certificate has to name the peer. Otherwise a peer with a
+    /// key has to have proven it holds the key in the Noise handshake, and
+    /// any other has to be sending from its own address
+    pub(crate) fn check_claim(
+        &self,
+        from: IpAddr,
+        remote: Option<&Remote>,
+        claimed: &PeerId,
+    ) -> Result<(), String> {
+        if !claimed.verify_derivation() {
+            return Err(format!(
+                "{claimed:?} is not derived from its key or address"
+            ));
+        }
+        match (claimed.key(), remote) {
+            #[cfg(feature = "tls")]
+            (_, Some(Remote::Cert(cert))) if crate::tls::names(cert, claimed) => Ok(()),
+            #[cfg(feature = "tls")]
+            (_, Some(Remote::Cert(_))) => {
+                Err(format!("the sender's certificate is not for {claimed:?}"))
+            }
+            (Some(key), Some(Remote::Key(remote)))
+                if key.to_x25519().as_ref() == Some(remote) =>
+            {
+                Ok(())
+            }
+            (Some(key), Some(Remote::Key(_))) => {
+                Err(format!("the sender does not hold {key}"))
+            }
+            _ if from == IpAddr::V4(claimed.ip()) => Ok(()),
+            _ => Err(format!("{from} can't speak for {claimed:?}")),
+        }
+    }
+
+    /// Attempt to find a route to the given PeerId
+    fn router(&self, peer: PeerId) -> Option<PeerId> {
+        // If the desired peer is us, return ourself
+        if peer == self.id {
+            return Some(self.id.clone());
+        }
+
+        // If not, check if the desired peer is in our PeerStore, and
+        // return it. Finding peers we don't know is up to `lookup`, which
+        // goes out to the network, so isn't done while routing a request
+        self.peers.lock().get(&peer).map(|entry| entry.id.clone())
+    }
+
+    /// Find the peers on the network closest to `target`, Kademlia style.
+    /// Starting from the closest known peers, ask the closest few not yet
+    /// asked for the peers they know closest to `target`, until a round
+    /// turns up nobody closer. Each round halves the distance at least,
+    /// so this takes O(log n) rounds. Peers that answer are added to the
+    /// PeerStore along the way. Returns up to K_BUCKET_SIZE peers, closest
+    /// first
+    pub fn lookup(&self, target: Point) -> Vec<PeerId> {
+        let distance = |id: &PeerId| target.distance(&Point::of_peer(id));
+        let mut shortlist: Vec<PeerId> = self
+            .peers
+            .lock()
+            .closest(&target, K_BUCKET_SIZE)
+            .into_iter()
+            .map(|entry| entry.id.clone())
+            .collect();
+        let mut asked = HashSet::new();
+        let mut answered = HashSet::new();
+
+        loop {
+            let round: Vec<PeerId> = shortlist
+                .iter()
+                .filter(|id| !asked.contains(*id))
+                .take(LOOKUP_PARALLELISM)
+                .cloned()
+                .collect();
+            if round.is_empty() {
+                break;
+            }
+            let queries: Vec<_> = round
+                .into_iter()
+                .map(|to| {
+                    asked.insert(to.clone());
+                    thread::spawn(move || {
+                        let req = Request::FindNode(target);
+                        let mut conn =
+                            Peer::send_request_timeout(&to, req, DIAL_TIMEOUT)?;
+                        match Peer::recv_response(&mut conn)? {
+                            Response::Nodes(nodes) => Ok((to, nodes)),
+                            res => Err(NetworkError::Fail(format!(
+                                "{to:?} answered {res:?}"
+                            ))),
+                        }
+                    })
+                })
+                .collect();
+
+            for query in queries {
+                let (from, nodes) = match query.join() {
+                    Ok(Ok(answer)) => answer,
+                    Ok(Err(e)) => {
+                        info!("lookup for {target} skipping a peer: {e}");
+                        continue;
+                    }
+                    Err(_) => continue,
+                };
+                self.clone().add_peer(from.clone());
+                self.mark_seen(&from);
+                answered.insert(from);
+                for node in nodes {
+                    if node != self.id
+                        && node.verify_derivation()
+                        && !shortlist.contains(&node)
+                    {
+                        shortlist.push(node);
+                    }
+                }
+            }
+            shortlist.sort_by_key(distance);
+            shortlist.truncate(K_BUCKET_SIZE);
+        }
+
+        shortlist.retain(|id| answered.contains(id));
+        shortlist
+    }
+
+    /* Public functions define interface to Peer */
+
+    /// Send a ping to all nodes in the peerstore
+    pub fn send_pings(&self) -> Result<(), Error> {
+        let ids: Vec<PeerId> = {
+            let peers = self.peers.lock();
+            peers.iter().map(|peer| peer.id.clone()).collect()
+        };
+        for id in ids.iter() {
+            self.send_ping(id)?;
+        }
+        Ok(())
+    }
+
+    /// Send a ping request to a peer, batched with anything else headed
+    /// its way
+    pub fn send_ping(&self, to: &PeerId) -> Result<(), Error> {
+        match self.batcher.request(to, Request::Ping)? {
+            Response::Pong => info!("got a pong from {to:?}"),
+            res => {
+                let msg = format!("{to:?} answered a ping with {res:?}");
+                return Err(NetworkError::Fail(msg).into());
+            }
+        }
+        self.mark_seen(to);
+        Ok(())
+    }
+
+    /// Ask a peer to add this one to its PeerStore, adding it to ours if it
+    /// answers. Returns whether it was new to our PeerStore
+    pub fn join(&mut self, to: &PeerId) -> Result<bool, Error> {
+        let id = self.probe_join(to)?;
+        let added = self.add_peer(id.clone());
+        self.mark_seen(&id);
+        Ok(added)
+    }
+
+    /// Ask another peer to dial this peer back, to check that this peer's
+    /// advertised address is reachable from the network. This peer must be
+    /// listening for the check to pass
+    pub fn check_reachable(&self, via: &PeerId) -> Result<bool, Error> {
+        Ok(dial_back(via, self.id.port())?)
+    }
+
+    /// Store a value on this peer, returning the one it replaced
+    pub fn put(&self, key: Key, value: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        let old = self.store.lock().put(key.clone(), value)?;
+        self.hooks.on_content_stored(&key);
+        Ok(old)
+    }
+
+    /// Look up a value stored on this peer
+    pub fn get(&self, key: &Key) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.store.lock().get(key)?)
+    }
+
+    /// Find a peer holding a key, looking up to `tts` hops away. Our own
+    /// lookups skip the hooks and checks inbound requests go through, but
+    /// are marked seen so copies forwarded back to us are dropped
+    pub fn find_key(&self, key: &Key, tts: u16) -> NetworkResult<Option<PeerId>> {
+        let trace = TraceId::new();
+        self.seen.lock().insert(trace);
+        let tts = tts.min(MAX_TTS);
+        match trace::with_trace(trace, || self.handle_query_key(key.clone(), tts))? {
+            Response::RespondKey { holding_id, .. } => Ok(Some(holding_id)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Find the peers holding a key, looking up to `tts` hops away, nearest
+    /// first as `rank_providers` orders them
+    pub fn find_providers(&self, key: &Key, tts: u16) -> Vec<PeerId> {
+        let trace = TraceId::new();
+        self.seen.lock().insert(trace);
+        let tts = tts.min(MAX_TTS);
+        let mut providers =
+            trace::with_trace(trace, || self.query_holders(key, tts, QUERY_FANOUT));
+        providers.retain(|id| *id != self.id);
+        providers.dedup();
+        self.rank_providers(&mut providers);
+        providers
+    }
+
+    /// Order providers so the ones to fetch from come first: those in a
+    /// region closer to this peer's, then those answering pings sooner.
+    /// Without a Locator, or where regions are unknown, only round trip
+    /// times count
+    pub fn rank_providers(&self, providers: &mut [PeerId]) {
+        let here = self.region(&self.id);
+        let peers = self.peers.lock();
+        providers.sort_by_cached_key(|id| {
+            let closeness = match (&here, self.region(id)) {
+                (Some(here), Some(there)) => here.closeness(&there),
+                _ => 0,
+            };
+            let rtt = peers.get(id).and_then(PeerStoreEntry::rtt);
+            (std::cmp::Reverse(closeness), rtt.is_none(), rtt)
+        });
+    }
+
+    /// Fetch the value stored under a key from the nearest peer holding
+    /// it, trying the others in turn if it fails
+    pub fn fetch(&self, key: &Key, tts: u16) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(value) = self.get(key)? {
+            return Ok(Some(value));
+        }
+        for provider in self.find_providers(key, tts) {
+            let res = Peer::send_request(&provider, Request::Get(key.clone()))
+                .and_then(|mut conn| Peer::recv_response(&mut conn));
+            match res {
+                Ok(Response::Value(value)) => return Ok(Some(value)),
+                Ok(res) => info!("{provider:?} did not give {key}: {res:?}"),
+                Err(e) => info!("could not fetch {key} from {provider:?}: {e}"),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remove a value stored on this peer, returning it
+    pub fn delete(&self, key: &Key) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.store.lock().delete(key)?)
+    }
+
+    /// Every known peer that is live, most recently seen first
+    pub fn live_peers(&self) -> Vec<PeerId> {
+        let peers = self.peers.lock();
+        peers.live().map(|entry| entry.id.clone()).collect()
+    }
+
+    /// Write this peer's live peers in the bootstrap file format, so they
+    /// can seed a new node
+    pub fn export_peers<W: Write>(
+        &self,
+        w: &mut W,
+        multiaddr: bool,
+    ) -> Result<usize, Error> {
+        Ok(write_bootstrap(w, self.live_peers().iter(), multiaddr)?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+
+    /// A peer for tests, keeping the files it writes in a temp dir of its
+    /// own rather than the working directory
+    pub(crate) fn test_peer(port: u16) -> Peer {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let n = DIRS.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("harbor-{}-{n}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut peer = Peer::new(true, port).unwrap();
+        peer.set_peer_cache(dir.join(PEER_CACHE_FILE));
+        peer.set_metrics_file(dir.join(METRICS_FILE));
+        peer
+    }
+
+    /// Run a peer on its own thread, returning once it is Ready
+    pub(crate) fn start_ready(peer: &Peer) -> thread::JoinHandle<Result<(), Error>> {
+        let events = peer.subscribe();
+        let node = peer.clone();
+        let handle = thread::spawn(move || node.start(false));
+        wait_ready(&events);
+        handle
+    }
+
+    /// Wait on a peer's events until it is Ready
+    pub(crate) fn wait_ready(events: &mpsc::Receiver<Event>) {
+        let timeout = Duration::from_secs(10);
+        while events.recv_timeout(timeout).unwrap() != Event::StateChanged(State::Ready) {
+        }
+    }
+
+    #[test]
+    fn test_peer_id() {
+        let id1 = PeerId::from("127.0.0.1".parse().unwrap(), 3300);
+        println!("{id1:?}");
+        assert!(id1.ip() == id1.ip);
+        assert!(id1.port() == id1.port);
+    }
+
+    #[test]
+    fn test_derivation_vectors() {
+        for (addr, expected) in DERIVATION_VECTORS {
+            let id = addr.parse::<PeerId>().unwrap();
+            assert_eq!(id.to_string(), expected);
+            assert!(id.verify_derivation());
+            assert_eq!(expected.parse::<PeerId>().unwrap(), id);
+        }
+
+        let forged = PeerId {
+            id: DERIVATION_VECTORS[0].1.to_string(),
+            ..DERIVATION_VECTORS[1].0.parse().unwrap()
+        };
+        assert!(!forged.verify_derivation());
+
+        for (key, addr, expected) in KEYED_DERIVATION_VECTORS {
+            let addr = addr.parse::<PeerId>().unwrap();
+            let id = PeerId::with_key(key.parse().unwrap(), addr.ip, addr.port);
+            assert_eq!(id.to_string(), expected);
+            assert!(id.verify_derivation());
+        }
+
+        // A key can't claim an address-derived id, or another key's
+        let stolen = PeerId {
+            key: Some(KEYED_DERIVATION_VECTORS[0].0.parse().unwrap()),
+            ..DERIVATION_VECTORS[0].0.parse().unwrap()
+        };
+        assert!(!stolen.verify_derivation());
+        assert!(stolen.to_multiaddr().parse::<PeerId>().is_err());
+    }
+
+    #[test]
+    fn test_write_bootstrap() {
+        let (key, addr, _) = KEYED_DERIVATION_VECTORS[0];
+        let addr = addr.parse::<PeerId>().unwrap();
+        let keyed = PeerId::with_key(key.parse().unwrap(), addr.ip, addr.port);
+        let plain = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+
+        // Both kinds of PeerId read back as themselves, key and all
+        let mut file = Vec::new();
+        write_bootstrap(&mut file, [&keyed, &plain], true).unwrap();
+        let lines = String::from_utf8(file).unwrap();
+        let ids = parse_bootstrap(lines.lines().map(String::from), true, &SystemResolver)
+            .unwrap();
+        assert_eq!(ids, [keyed.clone(), plain]);
+        assert_eq!(ids[0].key(), keyed.key());
+
+        // Without its key, a keyed PeerId can't be checked
+        assert!(keyed.to_string().parse::<PeerId>().is_err());
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let mut peer = test_peer(3300);
+        peer.bootstrap().unwrap();
+        println!("peer: {:#?}", peer);
+    }
+
+    #[test]
+    fn test_parse_bootstrap() {
+        let id = PeerId::from("10.0.0.2".parse().unwrap(), 4400);
+        let lines = vec![
+            "# bootstrap hosts".to_string(),
+            "".to_string(),
+            "10.0.0.1:3300 # primary".to_string(),
+            id.to_string(),
+            "10.0.0.300:3300".to_string(),
+            "/peer/deadbeef/10.0.0.3/3300".to_string(),
+            "seed.example:3300".to_string(),
+        ];
+
+        #[derive(Debug)]
+        struct Seeds;
+        impl Resolver for Seeds {
+            fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+                Box::pin(future::ready(Ok(Lookup {
+                    addrs: vec!["10.0.1.1".parse().unwrap(), "10.0.1.2".parse().unwrap()],
+                    ttl: Duration::from_secs(60),
+                })))
+            }
+        }
+
+        let ids = parse_bootstrap(lines.clone(), false, &Seeds).unwrap();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids[1], id);
+        assert_eq!(ids[3], "10.0.1.2:3300".parse().unwrap());
+
+        let err = parse_bootstrap(lines, true, &Seeds).unwrap_err();
+        assert!(matches!(err, Error::BadBootstrapLine(5, _)));
+    }
+
+    #[test]
+    fn add_peer() {
+        let mut peer = test_peer(9900);
+
+        peer.add_peer(PeerId::from("127.0.0.1".parse().unwrap(), 3300));
+        peer.add_peer(PeerId::from("127.0.0.1".parse().unwrap(), 3300));
+        peer.add_peer(PeerId::from("192.168.1.12".parse().unwrap(), 9954));
+        peer.add_peer(PeerId::from("192.168.1.82".parse().unwrap(), 9900));
+
+        println!("{peer:#?}");
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut peer = test_peer(9900);
+        let batch = vec![Request::Ping, Request::Identity, Request::Batch(vec![])];
+
+        let from = IpAddr::V4(peer.id.ip());
+        match peer.dispatch(from, None, Request::Batch(batch)).unwrap() {
+            Response::Batch(responses) => {
+                assert!(matches!(responses[0], Response::Pong));
+                assert!(
+                    matches!(&responses[1], Response::Identity(id) if *id == peer.id)
+                );
+                assert!(matches!(responses[2], Response::Err(_)));
+            }
+            res => panic!("expected a batch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_ping_interval() {
+        let id = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        let mut peers = PeerStore::new();
+        peers.insert(PeerStoreEntry::new(id.clone()));
+        let interval = |peers: &PeerStore| peers.iter().next().unwrap().ping_interval();
+
+        assert_eq!(interval(&peers), MIN_PING_INTERVAL);
+        peers.record_ping(&id, true);
+        peers.record_ping(&id, true);
+        assert_eq!(interval(&peers), MIN_PING_INTERVAL * 4);
+        for _ in 0..32 {
+            peers.record_ping(&id, true);
+        }
+        assert_eq!(interval(&peers), MAX_PING_INTERVAL);
+        peers.record_ping(&id, false);
+        assert_eq!(interval(&peers), MIN_PING_INTERVAL);
+    }
+
+    #[test]
+    fn test_live_peers() {
+        let mut peer = test_peer(9900);
+        let [live, unprobed, dead] =
+            [1, 2, 3].map(|n| PeerId::from(Ipv4Addr::new(10, 0, n, 1), 3300));
+        for id in [&live, &unprobed, &dead] {
+            peer.add_peer(id.clone());
+        }
+        peer.peers.lock().record_ping(&live, true);
+        peer.peers.lock().record_ping(&dead, true);
+        peer.peers.lock().record_ping(&dead, false);
+        assert_eq!(peer.live_peers(), vec![live.clone()]);
+
+        let from = IpAddr::V4(peer.id.ip());
+        assert!(matches!(
+            peer.dispatch(from, None, Request::LivePeers),
+            Ok(Response::LivePeers(ids)) if ids == [live.clone()]
+        ));
+        let mut out = Vec::new();
+        assert_eq!(peer.export_peers(&mut out, false).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap().trim(), "10.0.1.1:3300");
+    }
+
+    #[test]
+    fn test_anchor_eviction() {
+        let mut peer = test_peer(9900);
+        peer.max_peers = 2;
+
+        let anchor = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        assert!(peer.add_anchor(anchor.clone()));
+        for i in 2..6 {
+            assert!(
+                peer.add_peer(PeerId::from(format!("10.0.0.{i}").parse().unwrap(), 3300))
+            );
+        }
+
+        let peers = peer.peers.lock();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&anchor));
+    }
+
+    #[test]
+    fn test_upgrade_to_key() {
+        let mut peer = test_peer(9900);
+        let plain = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        assert!(peer.add_anchor(plain.clone()));
+        peer.mark_seen(&plain);
+
+        // Learning the key behind an address replaces the entry for it
+        let key = Identity::generate().unwrap().public_key();
+        let keyed = PeerId::with_key(key, plain.ip(), plain.port());
+        assert!(peer.add_peer(keyed.clone()));
+        let peers = peer.peers.lock();
+        assert_eq!(peers.len(), 1);
+        assert!(peers.get(&keyed).unwrap().last_seen().is_some());
+        assert!(peer.anchors.contains(&keyed));
+    }
+
+    #[test]
+    fn test_subnet_limit() {
+        let mut peer = test_peer(9900);
+
+        for i in 0..MAX_PEERS_PER_SUBNET + 2 {
+            let ok =
+                peer.add_peer(PeerId::from(format!("8.8.8.{i}").parse().unwrap(), 3300));
+            assert_eq!(ok, i < MAX_PEERS_PER_SUBNET);
+        }
+        assert!(peer.add_peer(PeerId::from("8.8.9.1".parse().unwrap(), 3300)));
+
+        let targets = peer.fanout_targets(2);
+        assert_eq!(targets.len(), 2);
+        assert_ne!(targets[0].subnet(), targets[1].subnet());
+    }
+
+    #[test]
+    fn test_hooks() {
+        #[derive(Debug, Default)]
+        struct Counter {
+            added: AtomicU64,
+            removed: AtomicU64,
+        }
+        impl Hooks for Counter {
+            fn on_peer_added(&self, _: &PeerId) {
+                self.added.fetch_add(1, Ordering::Relaxed);
+            }
+            fn on_peer_removed(&self, _: &PeerId) {
+                self.removed.fetch_add(1, Ordering::Relaxed);
+            }
+            fn on_request(&self, _: IpAddr, request: &Request) -> Decision {
+                match request {
+                    Request::Join(_) => Decision::Deny,
+                    _ => Decision::Allow,
+                }
+            }
+        }
+
+        let counter = Arc::new(Counter::default());
+        let mut peer = test_peer(9900);
+        peer.max_peers = 2;
+        peer.set_hooks(counter.clone());
+
+        for i in 1..4 {
+            peer.add_peer(PeerId::from(format!("10.0.0.{i}").parse().unwrap(), 3300));
+        }
+        assert_eq!(counter.added.load(Ordering::Relaxed), 3);
+        assert_eq!(counter.removed.load(Ordering::Relaxed), 1);
+
+        let joiner = PeerId::from("10.0.1.1".parse().unwrap(), 3300);
+        let res = peer.dispatch(joiner.ip().into(), None, Request::Join(joiner.clone()));
+        assert!(matches!(res, Ok(Response::Err(_))));
+        assert!(!peer.peers.lock().contains(&joiner));
+    }
+
+    #[test]
+    fn test_join_claims() {
+        let mut peer = test_peer(9900);
+        let plain = PeerId::from("10.0.1.1".parse().unwrap(), 3300);
+        let elsewhere: IpAddr = "10.0.1.2".parse().unwrap();
+        let refused = |res: NetworkResult<Response>| {
+            matches!(
+                res,
+                Ok(Response::Err(NetworkError::Rejected(
+                    Reason::Unauthorized,
+                    _
+                )))
+            )
+        };
+
+        // A peer without a key has to join from its own address
+        assert!(refused(peer.dispatch(
+            elsewhere,
+            None,
+            Request::Join(plain.clone())
+        )));
+        let res = peer.dispatch(plain.ip().into(), None, Request::Join(plain.clone()));
+        assert!(matches!(res, Ok(Response::Msg(_))));
+
+        // One with a key has to have shown it in the handshake, from
+        // wherever it dials
+        let identity = Identity::generate().unwrap();
+        let keyed = identity.peer_id("10.0.2.1".parse().unwrap(), 3300);
+        let stranger = Remote::Key(Identity::generate().unwrap().noise_secret());
+        let join = || Request::Join(keyed.clone());
+        assert!(refused(peer.dispatch(elsewhere, Some(&stranger), join())));
+        let proven = Remote::Key(identity.public_key().to_x25519().unwrap());
+        let res = peer.dispatch(elsewhere, Some(&proven), join());
+        assert!(matches!(res, Ok(Response::Msg(_))));
+        let peers = peer.peers.lock();
+        assert_eq!(peers.get(&keyed).unwrap().joined_with, Some(proven));
+    }
+
+    #[test]
+    fn test_batch_deadline() {
+        let mut peer = test_peer(9900);
+        let from: IpAddr = "10.0.1.1".parse().unwrap();
+        let batch = Request::Batch(vec![Request::Ping, Request::Ping]);
+        let res =
+            trace::with_deadline(Instant::now(), || peer.dispatch(from, None, batch));
+        let responses = match res {
+            Ok(Response::Batch(responses)) => responses,
+            other => panic!("expected a batch, got {:?}", other),
+        };
+        assert_eq!(responses.len(), 2);
+        for res in responses {
+            assert!(matches!(
+                res,
+                Response::Err(NetworkError::Rejected(Reason::TooSlow, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_panic_holding_peers() {
+        let mut peer = test_peer(9900);
+        let handler = peer.clone();
+        let res = thread::spawn(move || {
+            let _peers = handler.peers.lock();
+            panic!("handler died holding the PeerStore");
+        })
+        .join();
+        assert!(res.is_err());
+
+        // The PeerStore is still usable afterwards
+        assert!(peer.add_peer(PeerId::from("10.0.0.1".parse().unwrap(), 3300)));
+        assert_eq!(peer.lock_peers().unwrap().len(), 1);
+
+        // A held lock times out instead of blocking the handler forever
+        let _held = peer.peers.lock();
+        assert!(peer.lock_peers().is_err());
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let peer = test_peer(9912);
+        let events = peer.subscribe();
+        assert_eq!(peer.state(), State::Initializing);
+
+        let handle = start_ready(&peer);
+        wait_ready(&events);
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+        let rest: Vec<Event> = events.try_iter().collect();
+        assert_eq!(
+            rest,
+            vec![
+                Event::StateChanged(State::Draining),
+                Event::StateChanged(State::Stopped)
+            ]
+        );
+        assert_eq!(peer.state(), State::Stopped);
+    }
+
+    #[test]
+    fn test_reuse_port() {
+        let mut peer = test_peer(9913);
+        peer.set_socket_options(SocketOptions {
+            acceptors: 3,
+            reuse_port: true,
+            ..SocketOptions::default()
+        });
+        let handle = start_ready(&peer);
+
+        for _ in 0..6 {
+            let mut conn = Peer::send_request(&peer.id, Request::Ping).unwrap();
+            assert!(matches!(Peer::recv_response(&mut conn), Ok(Response::Pong)));
+        }
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+        assert_eq!(peer.acceptors.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_learn_peers() {
+        let live = test_peer(9914);
+        let handle = start_ready(&live);
+
+        // One real peer, one that is down, and one claiming an id that is
+        // not the hash of its address
+        let down = PeerId::from("127.0.0.1".parse().unwrap(), 9);
+        let forged = PeerId {
+            id: down.id.clone(),
+            ..live.id.clone()
+        };
+        let mut store = PeerStore::new();
+        for id in [live.id.clone(), down.clone(), forged] {
+            store.insert(PeerStoreEntry::new(id));
+        }
+
+        // A peer last seen long ago isn't worth checking, live or not
+        let mut stale = PeerStoreEntry::new(live.id.clone());
+        stale.last_seen =
+            Some(chrono::Utc::now().naive_utc() - chrono::Duration::days(1));
+        let mut old = PeerStore::new();
+        old.insert(stale);
+
+        let peer = test_peer(9915);
+        assert_eq!(peer.learn_peers(&old).join().unwrap(), 0);
+        assert_eq!(peer.learn_peers(&store).join().unwrap(), 1);
+        let peers = peer.peers.lock();
+        assert!(peers.contains(&live.id) && !peers.contains(&down));
+        assert!(peers.get(&live.id).unwrap().last_seen().is_some());
+        drop(peers);
+
+        live.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_probe_join() {
+        let (known, mut bootstrap) = (test_peer(9934), test_peer(9935));
+        let handles = [start_ready(&known), start_ready(&bootstrap)];
+        bootstrap.add_peer(known.id.clone());
+
+        // Joining one peer brings in the peers it knows, once checked
+        let peer = test_peer(9936);
+        assert_eq!(peer.probe_join(&bootstrap.id).unwrap(), bootstrap.id);
+        assert!(bootstrap.peers.lock().contains(&peer.id));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !peer.peers.lock().contains(&known.id) {
+            assert!(Instant::now() < deadline, "never learned the known peer");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        known.stop();
+        bootstrap.stop();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_start_async() {
+        let peer = test_peer(9916);
+        let events = peer.subscribe();
+        let node = peer.clone();
+        let handle = thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(node.start_async(false))
+        });
+        wait_ready(&events);
+
+        // A client that never sends its request doesn't hold up the next one
+        let stalled = TcpStream::connect(peer.id.as_socket()).unwrap();
+        let started = Instant::now();
+        let mut conn = Peer::send_request(&peer.id, Request::Ping).unwrap();
+        assert!(matches!(Peer::recv_response(&mut conn), Ok(Response::Pong)));
+        assert!(started.elapsed() < HANDLER_BUDGET);
+        drop(stalled);
+
+        // Many requests at once from one thread, over async io both ways
+        let client = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pings = (0..MAX_INBOUND_PER_SOURCE / 2).map(|_| async {
+            let mut conn =
+                Peer::send_request_async(&peer.id, Request::Ping, DIAL_TIMEOUT).await?;
+            Peer::recv_response_async(&mut conn).await
+        });
+        let pongs = client.block_on(future::join_all(pings));
+        assert!(pongs.iter().all(|res| matches!(res, Ok(Response::Pong))));
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+        assert_eq!(peer.state(), State::Stopped);
+    }
+
+    #[test]
+    fn test_greylist() {
+        let mut peer = test_peer(9900);
+        let flaky = PeerId::from("10.0.0.2".parse().unwrap(), 3300);
+        peer.add_peer(flaky.clone());
+        for _ in 0..GREYLIST_STRIKES {
+            peer.strike(&flaky);
+        }
+        assert!(!peer.peers.lock().contains(&flaky));
+        assert_eq!(peer.greylisted()[0].0, flaky);
+
+        // Greylisted peers can't come straight back
+        assert!(!peer.add_peer(flaky.clone()));
+        let mut store = PeerStore::new();
+        store.insert(PeerStoreEntry::new(flaky));
+        assert_eq!(peer.learn_peers(&store).join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_store() {
+        let mut peer = test_peer(9900);
+        let from = "10.0.0.1".parse().unwrap();
+        let key = Key::new("hello");
+        assert!(peer.put(key.clone(), b"world".to_vec()).unwrap().is_none());
+        assert_eq!(peer.get(&key).unwrap().unwrap(), b"world");
+
+        let res = peer.dispatch(from, None, Request::Get(key.clone()));
+        assert!(matches!(res, Ok(Response::Value(v)) if v == b"world"));
+        let res = peer.dispatch(from, None, Request::List);
+        assert!(matches!(res, Ok(Response::List(keys)) if keys == vec![key.clone()]));
+
+        peer.delete(&key).unwrap();
+        let res = peer.dispatch(from, None, Request::Get(key));
+        assert!(matches!(res, Ok(Response::Err(_))));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut peer = test_peer(9917);
+        peer.set_memory_budget(1000);
+        let handle = start_ready(&peer);
+        let timeout = Duration::from_secs(10);
+
+        // With most of the budget taken, gossip is put off but pings are not
+        let held = peer.memory.try_reserve(800).unwrap();
+        let mut conn = Peer::send_request(&peer.id, Request::PeerStore).unwrap();
+        assert!(matches!(Peer::recv_response(&mut conn), Ok(Response::Busy)));
+        let mut conn = Peer::send_request(&peer.id, Request::Ping).unwrap();
+        assert!(matches!(Peer::recv_response(&mut conn), Ok(Response::Pong)));
+        drop(held);
+
+        // Handlers give back what they reserved once they are done
+        let deadline = Instant::now() + timeout;
+        while peer.memory.used() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(peer.memory.used(), 0);
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_query_key() {
+        // a knows b, b knows c, and only c holds the key
+        let [mut a, b, c] = [9918, 9919, 9920].map(test_peer);
+        let key = Key::new("deep");
+        c.put(key.clone(), b"value".to_vec()).unwrap();
+        a.peers.lock().insert(PeerStoreEntry::new(b.id.clone()));
+        b.peers.lock().insert(PeerStoreEntry::new(c.id.clone()));
+
+        let handles = [start_ready(&b), start_ready(&c)];
+
+        // Our own lookups aren't inbound requests, so hooks don't see them
+        #[derive(Debug)]
+        struct DenyAll;
+        impl Hooks for DenyAll {
+            fn on_request(&self, _: IpAddr, _: &Request) -> Decision {
+                Decision::Deny
+            }
+        }
+        a.set_hooks(Arc::new(DenyAll));
+        assert_eq!(a.find_key(&key, 2).unwrap(), Some(c.id.clone()));
+        assert_eq!(a.find_key(&key, 1).unwrap(), None);
+        assert_eq!(c.find_key(&key, 0).unwrap(), Some(c.id.clone()));
+
+        for node in [b, c] {
+            node.stop();
+        }
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_slow_loris() {
+        let peer = test_peer(9921);
+        let handle = start_ready(&peer);
+        let timeout = Duration::from_secs(10);
+
+        // Promise a frame, then send it a byte at a time, each well within
+        // a read timeout but far too slowly overall
+        let conn = TcpStream::connect(peer.id.as_socket()).unwrap();
+        let mut conn = Conn::initiate(conn, None).unwrap();
+        conn.write_all(&100u32.to_le_bytes()).unwrap();
+        let started = Instant::now();
+        while conn.write_all(&[0]).is_ok() && started.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(300));
+        }
+        assert!(started.elapsed() < crate::FRAME_TIMEOUT * 2);
+
+        // Same for the handshake
+        let mut conn = TcpStream::connect(peer.id.as_socket()).unwrap();
+        conn.write_all(&32u16.to_be_bytes()).unwrap();
+        let started = Instant::now();
+        while conn.write_all(&[0]).is_ok() && started.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(300));
+        }
+        assert!(started.elapsed() < crate::FRAME_TIMEOUT * 2);
+
+        // Garbage is turned away with the reason why
+        let conn = TcpStream::connect(peer.id.as_socket()).unwrap();
+        let mut conn = Conn::initiate(conn, None).unwrap();
+        transport::write_frame(&mut conn, &[0xff, 0xff, 0xff, 0x7f]).unwrap();
+        assert!(matches!(
+            Peer::recv_response(&mut conn),
+            Ok(Response::Err(NetworkError::Rejected(Reason::Malformed, _)))
+        ));
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_conns() {
+        let peer = test_peer(9931);
+        let handle = start_ready(&peer);
+
+        // A connection that never says anything doesn't hold up the next
+        let _stalled = TcpStream::connect(peer.id.as_socket()).unwrap();
+        let started = Instant::now();
+        let mut conn = Peer::send_request(&peer.id, Request::Ping).unwrap();
+        assert!(matches!(Peer::recv_response(&mut conn), Ok(Response::Pong)));
+        assert!(started.elapsed() < crate::FRAME_TIMEOUT);
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_shed() {
+        let mut peer = test_peer(9932);
+        peer.set_socket_options(SocketOptions {
+            max_inbound: 1,
+            ..SocketOptions::default()
+        });
+        let handle = start_ready(&peer);
+        let timeout = Duration::from_secs(10);
+
+        // One connection stalls in the handshake, holding the only place
+        let _held = TcpStream::connect(peer.id.as_socket()).unwrap();
+        let shed = metrics::TrafficStats::snapshot().shed;
+
+        // The next is closed straight away, without waiting on a handshake
+        let mut conn = TcpStream::connect(peer.id.as_socket()).unwrap();
+        conn.set_read_timeout(Some(timeout)).unwrap();
+        let started = Instant::now();
+        let mut buf = [0u8; 1];
+        assert!(matches!(conn.read(&mut buf), Ok(0) | Err(_)));
+        assert!(started.elapsed() < crate::FRAME_TIMEOUT / 2);
+        assert!(metrics::TrafficStats::snapshot().shed > shed);
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_nested_batch() {
+        let peer = test_peer(9929);
+        let handle = start_ready(&peer);
+
+        // Batches nested far deeper than the stack could decode
+        let header = bincode::serialize(&Request::Batch(vec![Request::Ping])).unwrap();
+        let header = &header[..header.len() - 4];
+        let mut frame = vec![0]; // No trace
+        frame.extend(header.repeat(100_000));
+        frame.extend_from_slice(&bincode::serialize(&Request::Ping).unwrap());
+        assert!(frame.len() < MAX_TRANSFER_SIZE);
+        assert!(bincode::deserialize::<Envelope>(&frame).is_err());
+
+        let conn = TcpStream::connect(peer.id.as_socket()).unwrap();
+        let mut conn = Conn::initiate(conn, None).unwrap();
+        transport::write_frame(&mut conn, &frame).unwrap();
+        assert!(matches!(
+            Peer::recv_response(&mut conn),
+            Ok(Response::Err(NetworkError::Rejected(Reason::Malformed, msg))) if msg.contains("nested batch")
+        ));
+        assert!(matches!(
+            Peer::send_request(&peer.id, Request::Ping)
+                .and_then(|mut conn| Peer::recv_response(&mut conn)),
+            Ok(Response::Pong)
+        ));
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_flood_limits() {
+        let mut peer = test_peer(9900);
+        let from = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        peer.add_peer(from.clone());
+
+        let res = peer.dispatch(
+            from.ip().into(),
+            None,
+            Request::SyncPeers { tts: MAX_TTS + 1 },
+        );
+        assert!(matches!(
+            res,
+            Ok(Response::Err(NetworkError::Rejected(
+                Reason::TooManyHops,
+                _
+            )))
+        ));
+        assert_eq!(peer.peers.lock().get(&from).unwrap().failures(), 1);
+
+        // A request that already came by another path is dropped
+        let trace = TraceId::new();
+        peer.seen.lock().insert(trace);
+        let looped = Request::SyncPeers { tts: 1 };
+        let res =
+            trace::with_trace(trace, || peer.dispatch(from.ip().into(), None, looped));
+        assert!(
+            matches!(res, Ok(Response::Err(NetworkError::Rejected(Reason::Duplicate, msg))) if msg == "already seen")
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let peer = test_peer(9933);
+        let mut node = peer.clone();
+        let from = "10.0.0.1".parse().unwrap();
+        let unsupported = |res: &Response| {
+            matches!(
+                res,
+                Response::Err(NetworkError::Rejected(Reason::Unsupported, _))
+            )
+        };
+
+        // Turned away, on their own or in a batch, rather than panicking
+        let res = node
+            .dispatch(from, None, Request::SyncPeers { tts: 1 })
+            .unwrap();
+        assert!(unsupported(&res));
+        let batch = vec![Request::SyncPeers { tts: 1 }, Request::Ping];
+        match node.dispatch(from, None, Request::Batch(batch)) {
+            Ok(Response::Batch(res)) => {
+                assert!(unsupported(&res[0]));
+                assert!(matches!(res[1], Response::Pong));
+            }
+            res => panic!("expected a batch response, got {:?}", res),
+        }
+
+        // A handler that fails still tells the requester why
+        let handle = start_ready(&peer);
+        let held = peer.peers.lock();
+        let mut conn = Peer::send_request(&peer.id, Request::Info).unwrap();
+        match Peer::recv_response(&mut conn) {
+            Ok(Response::Err(e @ NetworkError::Rejected(Reason::Internal, _))) => {
+                assert!(!e.is_retryable())
+            }
+            res => panic!("expected an internal error, got {:?}", res),
+        }
+        drop(held);
+
+        peer.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_leave() {
+        let live = test_peer(9922);
+        let mut leaving = test_peer(9923);
+        let handle = start_ready(&live);
+        assert!(leaving.join(&live.id).unwrap());
+        assert!(live.peers.lock().contains(&leaving.id));
+
+        // Nobody else can make a peer leave, not even from the same host
+        // without the key it joined with
+        let other = "10.0.0.1".parse().unwrap();
+        let leave = || Request::Leave(leaving.id.clone());
+        let res = live.clone().dispatch(other, None, leave());
+        assert!(matches!(res, Ok(Response::Err(_))));
+        let host = leaving.id.ip().into();
+        let res = live
+            .clone()
+            .dispatch(host, Some(&Remote::Key([9; 32])), leave());
+        assert!(matches!(res, Ok(Response::Err(_))));
+        assert!(live.peers.lock().contains(&leaving.id));
+
+        assert_eq!(leaving.leave(), 1);
+        assert!(!live.peers.lock().contains(&leaving.id));
+
+        live.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_messages() {
+        // Carol holds messages between her friends alice and bob
+        let [alice, mut bob, carol] = [9939, 9940, 9941].map(|port| {
+            let mut peer = test_peer(port);
+            peer.set_identity(Identity::generate().unwrap());
+            peer
+        });
+        let key = |peer: &Peer| peer.identity().unwrap().public_key();
+        let mut friends = Friends::default();
+        for (name, peer) in [("alice", &alice), ("bob", &bob), ("carol", &carol)] {
+            friends.add(name, key(peer)).unwrap();
+        }
+        bob.set_friends(friends.clone());
+        carol.set_friends(friends);
+        let handles = [start_ready(&bob), start_ready(&carol)];
+        let events = bob.subscribe();
+
+        // Only friends may send messages
+        let mut stranger = test_peer(9942);
+        stranger.set_identity(Identity::generate().unwrap());
+        assert!(stranger.send_message(&bob.id, &key(&bob), b"hi").is_err());
+        alice.send_message(&bob.id, &key(&bob), b"hi").unwrap();
+        let timeout = Duration::from_secs(5);
+        match events.recv_timeout(timeout).unwrap() {
+            Event::Message { from, body, .. } => {
+                assert_eq!((from, body), (key(&alice), b"hi".to_vec()))
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // Carol holds one for bob, which only bob can collect
+        alice.send_message(&carol.id, &key(&bob), b"later").unwrap();
+        let from = IpAddr::V4(carol.id.ip());
+        let collect = || Request::Collect(key(&bob));
+        let stranger = Remote::Key(stranger.noise_secret);
+        let res = carol.clone().dispatch(from, Some(&stranger), collect());
+        assert!(matches!(res, Ok(Response::Err(_))));
+        assert!(bob.add_peer(carol.id.clone()));
+        bob.peers.lock().record_ping(&carol.id, true);
+        assert_eq!(bob.collect_messages(), 1);
+        match events.recv_timeout(timeout).unwrap() {
+            Event::Message { body, .. } => assert_eq!(body, b"later"),
+            other => panic!("{:?}", other),
+        }
+        assert!(carol.held.lock().is_empty());
+
+        for peer in [&bob, &carol] {
+            peer.stop();
+        }
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_presence() {
+        let [alice, mut bob] = [9943, 9944].map(|port| {
+            let mut peer = test_peer(port);
+            peer.set_identity(Identity::generate().unwrap());
+            peer
+        });
+        let key = |peer: &Peer| peer.identity().unwrap().public_key();
+        let mut friends = Friends::default();
+        friends.add("alice", key(&alice)).unwrap();
+        friends.add("bob", key(&bob)).unwrap();
+        alice.set_friends(friends.clone());
+        bob.set_friends(friends);
+        alice.set_status("writing");
+        bob.set_status("back");
+        let handle = start_ready(&alice);
+        let (alice_events, bob_events) = (alice.subscribe(), bob.subscribe());
+
+        // Bob comes online, and both sides hear about the other
+        assert!(bob.add_peer(alice.id.clone()));
+        bob.peers.lock().record_ping(&alice.id, true);
+        assert_eq!(bob.announce_presence(), 1);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            alice_events.recv_timeout(timeout).unwrap(),
+            Event::FriendOnline {
+                from: key(&bob),
+                status: "back".to_string()
+            }
+        );
+        assert_eq!(
+            bob_events.recv_timeout(timeout).unwrap(),
+            Event::FriendOnline {
+                from: key(&alice),
+                status: "writing".to_string()
+            }
+        );
+        assert_eq!(alice.statuses()[&key(&bob)], "back");
+
+        // Strangers, and anyone not holding the key they speak for, are
+        // not answered
+        let stranger = Identity::generate().unwrap();
+        let from = IpAddr::V4(alice.id.ip());
+        let presence = |from: PublicKey| Request::Presence {
+            from,
+            status: "hi".to_string(),
+        };
+        let remote = Remote::Key(stranger.noise_secret());
+        let res =
+            alice
+                .clone()
+                .dispatch(from, Some(&remote), presence(stranger.public_key()));
+        assert!(matches!(res, Ok(Response::Err(_))));
+        let res = alice
+            .clone()
+            .dispatch(from, Some(&remote), presence(key(&bob)));
+        assert!(matches!(res, Ok(Response::Err(_))));
+
+        alice.stop();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_groups() {
+        // alice knows bob, who knows carol, and all three are in the group
+        let [mut alice, mut bob, carol] = [9945, 9946, 9947].map(|port| {
+            let mut peer = test_peer(port);
+            peer.set_identity(Identity::generate().unwrap());
+            peer
+        });
+        let key = |peer: &Peer| peer.identity().unwrap().public_key();
+        let (bob_id, carol_id) = (bob.id.clone(), carol.id.clone());
+        for (from, to) in [(&mut alice, bob_id), (&mut bob, carol_id)] {
+            assert!(from.add_peer(to.clone()));
+            from.peers.lock().record_ping(&to, true);
+        }
+        let handles = [start_ready(&bob), start_ready(&carol)];
+        let events = carol.subscribe();
+        let owner = Identity::generate().unwrap();
+        let group = owner.public_key();
+        let everyone = vec![key(&alice), key(&bob), key(&carol)];
+
+        // Membership reaches carol through bob
+        assert!(alice.membership(&group).is_none());
+        let record = Membership::sign(&owner, 1, everyone.clone());
+        assert_eq!(alice.share_membership(record.clone()).unwrap(), 1);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while carol.membership(&group).is_none() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(alice.share_membership(record).is_err());
+
+        // So does what members publish
+        assert_eq!(alice.publish(&group, "notes", b"hi".to_vec()).unwrap(), 1);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            Event::Published {
+                group,
+                name: "notes".to_string(),
+                from: key(&alice)
+            }
+        );
+        assert_eq!(
+            carol.get(&group::key(&group, "notes")).unwrap().unwrap(),
+            b"hi"
+        );
+
+        // Outsiders can't publish, and members that are dropped forget the
+        // group
+        let stranger = Identity::generate().unwrap();
+        let publication = Publication::sign(&stranger, &group, "notes", b"bye".to_vec());
+        let from = IpAddr::V4(bob.id.ip());
+        let res = bob
+            .clone()
+            .dispatch(from, None, Request::Publish(publication));
+        assert!(matches!(res, Ok(Response::Err(_))));
+        let record = Membership::sign(&owner, 2, everyone[..2].to_vec());
+        let res = carol
+            .clone()
+            .dispatch(from, None, Request::Membership(record));
+        assert!(matches!(res, Ok(Response::Ok)));
+        assert!(carol.membership(&group).is_none());
+        assert!(alice.publish(&group, "notes", b"again".to_vec()).is_ok());
+
+        for peer in [&bob, &carol] {
+            peer.stop();
+        }
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[derive(Debug)]
+    struct Atlas(HashMap<IpAddr, Region>);
+
+    impl Locator for Atlas {
+        fn locate(&self, ip: IpAddr) -> Option<Region> {
+            self.0.get(&ip).cloned()
+        }
+    }
+
+    #[test]
+    fn test_rank_providers() {
+        let mut peer = test_peer(9900);
+        let region = |continent: &str, country: &str| Region {
+            continent: continent.to_string(),
+            country: Some(country.to_string()),
+        };
+        let [far, abroad, near, fast, slow] =
+            [1, 2, 3, 4, 5].map(|n| PeerId::from(Ipv4Addr::new(10, 0, n, 1), 3300));
+        let mut atlas = HashMap::new();
+        atlas.insert(IpAddr::V4(peer.id.ip()), region("EU", "DE"));
+        atlas.insert(IpAddr::V4(far.ip()), region("NA", "US"));
+        atlas.insert(IpAddr::V4(abroad.ip()), region("EU", "FR"));
+        atlas.insert(IpAddr::V4(near.ip()), region("EU", "DE"));
+        for (id, ms) in [(&far, 1), (&abroad, 5), (&near, 50), (&fast, 2), (&slow, 9)] {
+            peer.add_peer(id.clone());
+            peer.peers.lock().record_rtt(id, Duration::from_millis(ms));
+        }
+
+        // Without regions, only round trip times count
+        let mut providers = vec![slow.clone(), far.clone(), fast.clone()];
+        peer.rank_providers(&mut providers);
+        assert_eq!(providers, [far.clone(), fast.clone(), slow.clone()]);
+
+        // With them, the same country beats the same continent, which beats
+        // the rest, and round trip times break ties
+        peer.set_locator(Arc::new(Atlas(atlas)));
+        let mut providers = vec![
+            slow.clone(),
+            far.clone(),
+            fast.clone(),
+            abroad.clone(),
+            near.clone(),
+        ];
+        peer.rank_providers(&mut providers);
+        assert_eq!(providers, [near, abroad, far, fast, slow]);
+    }
+
+    #[test]
+    fn test_fetch() {
+        // a knows b and c, and only c holds the key
+        let mut a = test_peer(9948);
+        let [b, c] = [9949, 9950].map(test_peer);
+        let key = Key::new("report");
+        c.put(key.clone(), b"findings".to_vec()).unwrap();
+        let handles = [start_ready(&b), start_ready(&c)];
+        assert!(a.add_peer(b.id.clone()) && a.add_peer(c.id.clone()));
+
+        assert_eq!(a.find_providers(&key, 1), vec![c.id.clone()]);
+        assert_eq!(a.fetch(&key, 1).unwrap().unwrap(), b"findings");
+        assert_eq!(a.fetch(&Key::new("missing"), 1).unwrap(), None);
+
+        for peer in [&b, &c] {
+            peer.stop();
+        }
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        // a knows b, and b knows c, so a can only find c through b
+        let mut a = test_peer(9924);
+        let mut b = test_peer(9925);
+        let c = test_peer(9926);
+        assert!(b.add_peer(c.id.clone()));
+        let handles = [start_ready(&b), start_ready(&c)];
+        assert!(a.add_peer(b.id.clone()));
+
+        // Routing a request doesn't go out to the network
+        assert_eq!(a.router(c.id.clone()), None);
+        let found = a.lookup(Point::of_peer(&c.id));
+        assert_eq!(found.first(), Some(&c.id));
+        assert!(a.peers.lock().contains(&c.id));
+        assert_eq!(a.router(c.id.clone()), Some(c.id.clone()));
+
+        b.stop();
+        c.stop();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_full_bucket() {
+        let mut peer = test_peer(9937);
+        let live = test_peer(9938);
+        let handle = start_ready(&live);
+
+        // Nothing listens on these, so they stand in for peers gone away
+        let local = Point::of_peer(&peer.id);
+        let bucket = move |id: &PeerId| local.distance(&Point::of_peer(id)).bucket();
+        let mut dead = (20000..)
+            .map(|port| PeerId::from("127.0.0.1".parse().unwrap(), port))
+            .filter(|id| bucket(id) == bucket(&live.id));
+        let wait = |peer: &Peer| {
+            let start = Instant::now();
+            while !peer.challenged.lock().is_empty() {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        assert!(peer.add_peer(live.id.clone()));
+        for id in dead.by_ref().take(K_BUCKET_SIZE - 1) {
+            assert!(peer.add_peer(id.clone()));
+            peer.mark_seen(&id);
+        }
+
+        // The peer seen longest ago answers, so the newcomer is turned away
+        let newcomer = dead.next().unwrap();
+        assert!(!peer.add_peer(newcomer.clone()));
+        wait(&peer);
+        assert!(peer.peers.lock().contains(&live.id));
+        assert!(!peer.peers.lock().contains(&newcomer));
+
+        // Now the one seen longest ago is gone, and the newcomer replaces it
+        assert!(!peer.add_peer(newcomer.clone()));
+        wait(&peer);
+        let peers = peer.peers.lock();
+        assert!(peers.contains(&live.id) && peers.contains(&newcomer));
+        assert_eq!(peers.bucket_len(&newcomer), K_BUCKET_SIZE);
+        drop(peers);
+
+        live.clone().stop();
+        handle.join().unwrap().unwrap();
     }
 }
@@ -0,0 +1,500 @@
+use crate::{
+    noise::Remote, peer::PeerId, routing::Point, MAX_PING_INTERVAL, MIN_PING_INTERVAL,
+};
+use chrono::NaiveDateTime;
+use derivative::Derivative;
+use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    mem::size_of,
+    time::Duration,
+};
+
+/// An entry in a PeerStore
+#[derive(Derivative, Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+#[derivative(Hash)]
+pub struct PeerStoreEntry {
+    #[derivative(Hash = "ignore")]
+    pub(crate) last_seen: Option<NaiveDateTime>,
+    pub(crate) id: PeerId,
+
+    /// Number of pings in a row this peer has answered
+    #[derivative(Hash = "ignore")]
+    #[serde(skip)]
+    pub(crate) streak: u32,
+
+    /// Number of pings in a row this peer has failed to answer
+    #[derivative(Hash = "ignore")]
+    #[serde(skip)]
+    pub(crate) failures: u32,
+
+    /// When this peer is next due a ping
+    #[derivative(Hash = "ignore")]
+    #[serde(skip)]
+    pub(crate) next_ping: Option<NaiveDateTime>,
+
+    /// What this peer proved in the handshake it joined with, if it
+    /// joined us: its Noise static key, or its TLS certificate
+    #[derivative(Hash = "ignore")]
+    #[serde(skip)]
+    pub(crate) joined_with: Option<Remote>,
+
+    /// How long this peer takes to answer a ping, smoothed over the pings
+    /// it answered
+    #[derivative(Hash = "ignore")]
+    #[serde(skip)]
+    pub(crate) rtt: Option<Duration>,
+}
+
+impl std::cmp::PartialEq for PeerStoreEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for PeerStoreEntry {}
+
+impl PeerStoreEntry {
+    pub fn new(id: PeerId) -> Self {
+        Self {
+            last_seen: None,
+            id,
+            streak: 0,
+            failures: 0,
+            next_ping: None,
+            joined_with: None,
+            rtt: None,
+        }
+    }
+
+    /// How long to wait before pinging this peer again. Flaky peers are
+    /// pinged as often as allowed, and the interval doubles with every
+    /// ping in a row a peer answers
+    pub fn ping_interval(&self) -> Duration {
+        if self.failures > 0 {
+            return MIN_PING_INTERVAL;
+        }
+        MIN_PING_INTERVAL
+            .saturating_mul(1 << self.streak.min(16))
+            .min(MAX_PING_INTERVAL)
+    }
+
+    /// Whether this peer is due a ping
+    pub(crate) fn ping_due(&self, now: NaiveDateTime) -> bool {
+        self.next_ping.is_none_or(|t| t <= now)
+    }
+
+    /// Whether this peer is live: it has been heard from, and answered its
+    /// last ping
+    pub fn is_live(&self) -> bool {
+        self.last_seen.is_some() && self.failures == 0
+    }
+
+    /// Return the PeerId of this entry
+    pub fn id(&self) -> &PeerId {
+        &self.id
+    }
+
+    /// How long this peer takes to answer a ping, if it has answered any
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Return when this peer was last heard from
+    pub fn last_seen(&self) -> Option<NaiveDateTime> {
+        self.last_seen
+    }
+
+    /// Return how many pings in a row this peer has answered
+    pub fn streak(&self) -> u32 {
+        self.streak
+    }
+
+    /// Return how many pings in a row this peer has missed
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+type Subnet = Option<[u8; 3]>;
+
+/// Where an entry is held: the index of its k-bucket, and its id
+type Slot = (Option<usize>, String);
+
+/// The peers known to a peer. A store kept by a peer is its Kademlia
+/// routing table, holding each peer in the k-bucket for its XOR distance
+/// from the peer. Stores that aren't, like ones received from other peers,
+/// hold every entry in one bucket. Entries are also indexed by when they
+/// were last seen and by subnet, so "most recent n" queries don't need to
+/// walk or clone the whole store. Goes over the wire as a plain list of
+/// entries
+#[derive(Debug, Clone, Default)]
+pub struct PeerStore {
+    /// Entries by k-bucket, then by id
+    buckets: BTreeMap<Option<usize>, HashMap<String, PeerStoreEntry>>,
+
+    /// (last_seen, slot) pairs. Never seen peers sort first
+    by_seen: BTreeSet<(Option<NaiveDateTime>, Slot)>,
+
+    by_subnet: HashMap<Subnet, HashSet<Slot>>,
+
+    /// The point buckets are measured from, for stores kept by a peer
+    local: Option<Point>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A routing table for the peer `local`, which sorts the peers it holds
+    /// into k-buckets by their XOR distance from `local`
+    pub fn around(local: &PeerId) -> Self {
+        Self {
+            local: Some(Point::of_peer(local)),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(HashMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    pub fn contains(&self, id: &PeerId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id: &PeerId) -> Option<&PeerStoreEntry> {
+        self.buckets.get(&self.bucket(id))?.get(&id.to_string())
+    }
+
+    /// Add an entry. Returns false if the peer was already known
+    pub fn insert(&mut self, entry: PeerStoreEntry) -> bool {
+        let slot = (self.bucket(&entry.id), entry.id.to_string());
+        let bucket = self.buckets.entry(slot.0).or_default();
+        if bucket.contains_key(&slot.1) {
+            return false;
+        }
+        bucket.insert(slot.1.clone(), entry.clone());
+        self.index(slot, &entry);
+        true
+    }
+
+    /// Remove a peer, returning its entry if it was known
+    pub fn remove(&mut self, id: &PeerId) -> Option<PeerStoreEntry> {
+        let slot = (self.bucket(id), id.to_string());
+        let bucket = self.buckets.get_mut(&slot.0)?;
+        let entry = bucket.remove(&slot.1)?;
+        if bucket.is_empty() {
+            self.buckets.remove(&slot.0);
+        }
+        self.unindex(&slot, &entry);
+        Some(entry)
+    }
+
+    /// Change a known peer's entry in place, keeping the indexes up to date.
+    /// Returns false if the peer is not known
+    pub fn update<F>(&mut self, id: &PeerId, f: F) -> bool
+    where
+        F: FnOnce(&mut PeerStoreEntry),
+    {
+        let slot = (self.bucket(id), id.to_string());
+        let entry = match self
+            .buckets
+            .get_mut(&slot.0)
+            .and_then(|bucket| bucket.get_mut(&slot.1))
+        {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let seen = entry.last_seen;
+        f(entry);
+        if entry.last_seen != seen {
+            let last_seen = entry.last_seen;
+            self.by_seen.remove(&(seen, slot.clone()));
+            self.by_seen.insert((last_seen, slot));
+        }
+        true
+    }
+
+    /// Every known peer, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &PeerStoreEntry> {
+        self.buckets.values().flat_map(HashMap::values)
+    }
+
+    /// Known peers, most recently seen first. Peers never seen come last
+    pub fn recent(&self) -> impl Iterator<Item = &PeerStoreEntry> {
+        self.by_seen
+            .iter()
+            .rev()
+            .map(move |(_, slot)| self.at(slot))
+    }
+
+    /// Live peers, most recently seen first
+    pub fn live(&self) -> impl Iterator<Item = &PeerStoreEntry> {
+        self.recent().filter(|entry| entry.is_live())
+    }
+
+    /// A smaller store holding just the `n` most recently seen peers
+    pub fn take_recent(&self, n: usize) -> PeerStore {
+        let mut store = PeerStore::new();
+        for entry in self.recent().take(n) {
+            store.insert(entry.clone());
+        }
+        store
+    }
+
+    /// The subnets known peers are in. Private and loopback peers are
+    /// grouped under `None`
+    pub fn subnets(&self) -> impl Iterator<Item = Subnet> + '_ {
+        self.by_subnet.keys().copied()
+    }
+
+    /// Known peers in a subnet
+    pub fn in_subnet(&self, subnet: Subnet) -> impl Iterator<Item = &PeerStoreEntry> {
+        self.by_subnet
+            .get(&subnet)
+            .into_iter()
+            .flatten()
+            .map(move |slot| self.at(slot))
+    }
+
+    /// Number of known peers in a subnet
+    pub fn subnet_len(&self, subnet: Subnet) -> usize {
+        self.by_subnet.get(&subnet).map_or(0, HashSet::len)
+    }
+
+    /// Known peers in the k-bucket `id` would go in. Always empty for
+    /// stores not kept around a peer
+    pub fn in_bucket(&self, id: &PeerId) -> impl Iterator<Item = &PeerStoreEntry> {
+        self.bucket(id)
+            .and_then(|bucket| self.buckets.get(&Some(bucket)))
+            .into_iter()
+            .flat_map(HashMap::values)
+    }
+
+    /// Number of known peers in the k-bucket `id` would go in. Always 0
+    /// for stores not kept around a peer
+    pub fn bucket_len(&self, id: &PeerId) -> usize {
+        self.bucket(id)
+            .and_then(|bucket| self.buckets.get(&Some(bucket)))
+            .map_or(0, HashMap::len)
+    }
+
+    /// The `n` known peers closest to `target`, closest first. A routing
+    /// table only looks in as many buckets as it takes to find them
+    pub fn closest(&self, target: &Point, n: usize) -> Vec<&PeerStoreEntry> {
+        let distance =
+            |entry: &&PeerStoreEntry| target.distance(&Point::of_peer(&entry.id));
+        let mut buckets: Vec<_> = self.buckets.iter().collect();
+        if let Some(local) = self.local {
+            let offset = local.distance(target);
+            buckets
+                .sort_by_key(|(bucket, _)| bucket.map(|i| offset.nearest_in_bucket(i)));
+        }
+        let mut closest = Vec::with_capacity(n);
+        for (_, bucket) in buckets {
+            if closest.len() >= n {
+                break;
+            }
+            let mut entries: Vec<&PeerStoreEntry> = bucket.values().collect();
+            entries.sort_by_key(distance);
+            closest.extend(entries);
+        }
+        closest.truncate(n);
+        closest
+    }
+
+    fn bucket(&self, id: &PeerId) -> Option<usize> {
+        self.local?.distance(&Point::of_peer(id)).bucket()
+    }
+
+    fn at(&self, slot: &Slot) -> &PeerStoreEntry {
+        &self.buckets[&slot.0][&slot.1]
+    }
+
+    /// A rough count of the bytes this store takes up on the heap
+    pub fn memory_usage(&self) -> usize {
+        let key_len: usize = self.iter().map(|entry| entry.id.to_string().len()).sum();
+        let buckets = self
+            .buckets
+            .values()
+            .map(|bucket| {
+                size_of::<(Option<usize>, HashMap<String, PeerStoreEntry>)>()
+                    + bucket.capacity()
+                        * (size_of::<String>() + size_of::<PeerStoreEntry>())
+            })
+            .sum::<usize>()
+            + key_len * 4; // Each key is held by its bucket, every index and the PeerId
+        let by_seen = self.by_seen.len() * size_of::<(Option<NaiveDateTime>, Slot)>();
+        let by_subnet = self.by_subnet.capacity() * size_of::<(Subnet, HashSet<Slot>)>()
+            + self
+                .by_subnet
+                .values()
+                .map(|slots| slots.capacity() * size_of::<Slot>())
+                .sum::<usize>();
+        buckets + by_seen + by_subnet
+    }
+
+    /// Record that a known peer was just heard from
+    pub(crate) fn touch(&mut self, id: &PeerId) {
+        self.update(id, |entry| {
+            entry.last_seen = Some(chrono::Utc::now().naive_utc());
+        });
+    }
+
+    /// Record the outcome of pinging a known peer, and schedule its next
+    /// ping
+    pub(crate) fn record_ping(&mut self, id: &PeerId, answered: bool) {
+        self.update(id, |entry| {
+            let now = chrono::Utc::now().naive_utc();
+            if answered {
+                entry.last_seen = Some(now);
+                entry.streak += 1;
+                entry.failures = 0;
+            } else {
+                entry.streak = 0;
+                entry.failures += 1;
+            }
+            let interval = chrono::Duration::from_std(entry.ping_interval()).unwrap();
+            entry.next_ping = Some(now + interval);
+        });
+    }
+
+    /// Fold how long a known peer took to answer a ping into its round
+    /// trip time, weighting the new sample by an eighth like TCP does
+    pub(crate) fn record_rtt(&mut self, id: &PeerId, rtt: Duration) {
+        self.update(id, |entry| {
+            entry.rtt = Some(match entry.rtt {
+                Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+                None => rtt,
+            });
+        });
+    }
+
+    fn index(&mut self, slot: Slot, entry: &PeerStoreEntry) {
+        self.by_seen.insert((entry.last_seen, slot.clone()));
+        self.by_subnet
+            .entry(entry.id.subnet())
+            .or_default()
+            .insert(slot);
+    }
+
+    fn unindex(&mut self, slot: &Slot, entry: &PeerStoreEntry) {
+        self.by_seen.remove(&(entry.last_seen, slot.clone()));
+        let subnet = entry.id.subnet();
+        if let Some(slots) = self.by_subnet.get_mut(&subnet) {
+            slots.remove(slot);
+            if slots.is_empty() {
+                self.by_subnet.remove(&subnet);
+            }
+        }
+    }
+}
+
+impl Serialize for PeerStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Buckets don't know their total size, which bincode needs up front
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for entry in self.iter() {
+            seq.serialize_element(entry)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<PeerStoreEntry>::deserialize(deserializer)?;
+        let mut store = PeerStore::new();
+        for entry in entries {
+            store.insert(entry);
+        }
+        Ok(store)
+    }
+}
+
+/// Described as the list of entries it goes over the wire as
+#[cfg(feature = "tools")]
+impl schemars::JsonSchema for PeerStore {
+    fn schema_name() -> String {
+        "PeerStore".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        Vec::<PeerStoreEntry>::json_schema(gen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexes() {
+        let mut store = PeerStore::new();
+        let ids: Vec<PeerId> = (1..4)
+            .map(|i| PeerId::from(format!("8.8.8.{i}").parse().unwrap(), 3300))
+            .collect();
+        for id in ids.iter() {
+            assert!(store.insert(PeerStoreEntry::new(id.clone())));
+        }
+        assert!(!store.insert(PeerStoreEntry::new(ids[0].clone())));
+        assert_eq!(store.subnet_len(ids[0].subnet()), 3);
+
+        // The last peer seen comes first
+        store.touch(&ids[1]);
+        assert_eq!(store.recent().next().unwrap().id(), &ids[1]);
+        assert_eq!(store.take_recent(1).len(), 1);
+
+        store.remove(&ids[1]);
+        assert_eq!(store.recent().count(), 2);
+        assert_eq!(store.subnet_len(ids[0].subnet()), 2);
+        assert!(store.memory_usage() > 0);
+
+        // Same wire format as a list of entries
+        let bytes = bincode::serialize(&store).unwrap();
+        let entries: Vec<PeerStoreEntry> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(entries.len(), 2);
+        let back: PeerStore = bincode::deserialize(&bytes).unwrap();
+        assert!(back.contains(&ids[0]) && !back.contains(&ids[1]));
+    }
+
+    #[test]
+    fn test_buckets() {
+        let local = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        let mut store = PeerStore::around(&local);
+        let ids: Vec<PeerId> = (2..34)
+            .map(|i| PeerId::from(format!("10.0.0.{i}").parse().unwrap(), 3300))
+            .collect();
+        for id in ids.iter() {
+            store.insert(PeerStoreEntry::new(id.clone()));
+        }
+        assert_eq!(store.len(), ids.len());
+        assert!(store.buckets.len() > 1 && !store.buckets.contains_key(&None));
+        for id in ids.iter() {
+            assert!(store.in_bucket(id).any(|entry| entry.id() == id));
+        }
+        let n = store.bucket_len(&ids[0]);
+        store.remove(&ids[0]);
+        assert_eq!(store.bucket_len(&ids[0]), n - 1);
+        assert!(!store.contains(&ids[0]));
+
+        // Closest first, by XOR distance, the same as sorting every peer
+        for target in ids.iter().chain(Some(&local)) {
+            let target = Point::of_peer(target);
+            let mut all: Vec<&PeerStoreEntry> = store.iter().collect();
+            all.sort_by_key(|entry| target.distance(&Point::of_peer(entry.id())));
+            all.truncate(5);
+            let closest = store.closest(&target, 5);
+            assert_eq!(closest, all);
+        }
+        assert_eq!(store.closest(&Point::of_peer(&ids[5]), 1)[0].id(), &ids[5]);
+        assert_eq!(PeerStore::new().bucket_len(&ids[1]), 0);
+    }
+}
@@ -0,0 +1,20 @@
+//! The common types needed to run and talk to a node.
+//!
+//! ```
+//! use harbor::prelude::*;
+//! ```
+
+pub use crate::{
+    event::Event,
+    hooks::{Decision, Hooks},
+    lifecycle::State,
+    peer::{Peer, PeerId, PeerStore, PeerStoreEntry},
+    protocol::{NetworkResult, Protocol, Request, Response},
+    score::{DefaultScorer, PeerScorer},
+    trace::TraceId,
+    transport::Transport,
+    Error, NetworkError,
+};
+
+#[cfg(feature = "async")]
+pub use crate::transport::AsyncTransport;
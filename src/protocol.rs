@@ -1,19 +1,40 @@
-use crate::{peer::*, transport::Transport, Error, NetworkError};
-use log::warn;
-use serde::{Deserialize, Serialize};
+use crate::{
+    clock,
+    event::{self, Event},
+    group::{Membership, Publication},
+    identity::PublicKey,
+    lifecycle::State,
+    message::Message,
+    metrics::{TrafficClass, TrafficStats},
+    noise::Remote,
+    peer::*,
+    routing::Point,
+    trace::{self, TraceId},
+    transport::Transport,
+    Error, NetworkError, Reason, AGENT, DIAL_TIMEOUT, HANDLER_BUDGET, K_BUCKET_SIZE,
+    MAX_HELD_MESSAGES, MAX_PEERSTORE_RESPONSE, MAX_STATUS_LEN, QUERY_FANOUT,
+    QUERY_HOP_TIMEOUT,
+};
+use log::{info, warn};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::{
+    cell::Cell,
     io::prelude::*,
-    net::{Ipv4Addr, TcpStream},
+    net::{IpAddr, Ipv4Addr, TcpStream},
+    thread,
+    time::{Duration, Instant},
 };
 
 pub type NetworkResult<T> = Result<T, NetworkError>;
 
 /// The maximum size of data that can be in a request or response over
-/// the network
-pub const MAX_TRANSFER_SIZE: usize = 5096; // in bytes
+/// the network. Larger frames are refused before they are read
+pub const MAX_TRANSFER_SIZE: usize = 16 << 20; // in bytes
 
 /// Possible peer request types
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+#[non_exhaustive]
 pub enum Request {
     /// Ping this peer
     /// Responds with Response::Pong
@@ -48,11 +69,159 @@ pub enum Request {
     /// Sync this peer's peerstore with another peer's peerstore in the given tts
     SyncPeers { tts: u16 },
 
-    /// Remove the given peer from this peer's table of peers
+    /// Remove the given peer from this peer's table of peers. Sent by a
+    /// peer about itself as it leaves the network
+    /// Responds with Response::Ok
     Leave(PeerId),
+
+    /// Several requests for this peer coalesced into one message. Batches
+    /// cannot be nested
+    /// Responds with Response::Batch
+    Batch(
+        #[serde(deserialize_with = "unnested")]
+        #[cfg_attr(feature = "tools", schemars(with = "Vec<Request>"))]
+        Vec<Request>,
+    ),
+
+    /// Asks this peer to open a fresh connection back to the requester's
+    /// address on the given port, to check that it is reachable
+    /// Responds with Response::Ok or Response::Err
+    DialBack { port: u16 },
+
+    /// Ask for this peer's traffic counters
+    /// Responds with Response::Stats
+    Stats,
+
+    /// Ask this peer to describe itself
+    /// Responds with Response::Info
+    Info,
+
+    /// Ask this peer for the time
+    /// Responds with Response::Time
+    Time,
+
+    /// Ask for the peers this peer knows closest to a point
+    /// Responds with Response::Nodes
+    FindNode(Point),
+
+    /// Ask for every peer this peer knows that is live
+    /// Responds with Response::LivePeers
+    LivePeers,
+
+    /// A message for this peer's identity, or one to hold for a friend of
+    /// this peer's until they come to collect it
+    /// Responds with Response::Ok or Response::Err
+    Message(Message),
+
+    /// Ask for the messages this peer holds for the given key. Only the
+    /// holder of the key, proven in the Noise handshake, may collect them
+    /// Responds with Response::Messages
+    Collect(PublicKey),
+
+    /// Tells a friend the sender, holding the given key as proven in the
+    /// Noise handshake, is online, and what its status is
+    /// Responds with Response::Presence
+    Presence { from: PublicKey, status: String },
+
+    /// A group's membership record, newer than the one this peer may hold
+    /// Responds with Response::Ok or Response::Err
+    Membership(Membership),
+
+    /// A value a member published into a group this peer is in
+    /// Responds with Response::Ok or Response::Err
+    Publish(Publication),
+}
+
+impl Request {
+    /// The name of this request's type, for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Ping => "Ping",
+            Request::Identity => "Identity",
+            Request::List => "List",
+            Request::PeerStore => "PeerStore",
+            Request::Join(_) => "Join",
+            Request::QueryKey { .. } => "QueryKey",
+            Request::RespondKey { .. } => "RespondKey",
+            Request::Get(_) => "Get",
+            Request::SyncPeers { .. } => "SyncPeers",
+            Request::FindNode(_) => "FindNode",
+            Request::Leave(_) => "Leave",
+            Request::Batch(_) => "Batch",
+            Request::DialBack { .. } => "DialBack",
+            Request::Stats => "Stats",
+            Request::Info => "Info",
+            Request::Time => "Time",
+            Request::LivePeers => "LivePeers",
+            Request::Message(_) => "Message",
+            Request::Collect(_) => "Collect",
+            Request::Presence { .. } => "Presence",
+            Request::Membership(_) => "Membership",
+            Request::Publish(_) => "Publish",
+        }
+    }
+
+    /// How many more hops this request asks to be flooded, for requests
+    /// that are flooded
+    pub fn tts(&self) -> Option<u16> {
+        match self {
+            Request::QueryKey { tts, .. } | Request::SyncPeers { tts } => Some(*tts),
+            _ => None,
+        }
+    }
+
+    /// The class of traffic this request counts towards
+    pub fn class(&self) -> TrafficClass {
+        match self {
+            Request::PeerStore
+            | Request::LivePeers
+            | Request::FindNode(_)
+            | Request::QueryKey { .. }
+            | Request::RespondKey { .. }
+            | Request::SyncPeers { .. }
+            | Request::Batch(_) => TrafficClass::Gossip,
+            Request::List
+            | Request::Get(_)
+            | Request::Message(_)
+            | Request::Collect(_)
+            | Request::Publish(_) => TrafficClass::Content,
+            _ => TrafficClass::Control,
+        }
+    }
+
+    /// How long the handler for this request may take before it is
+    /// considered slow
+    pub fn budget(&self) -> Duration {
+        match self {
+            Request::Ping | Request::Identity => Duration::from_secs(1),
+            Request::DialBack { .. } => DIAL_TIMEOUT * 2,
+            Request::QueryKey { .. } | Request::SyncPeers { .. } => {
+                Duration::from_secs(10)
+            }
+            Request::Batch(requests) => requests
+                .iter()
+                .map(Request::budget)
+                .sum::<Duration>()
+                .max(HANDLER_BUDGET),
+            _ => HANDLER_BUDGET,
+        }
+    }
+}
+
+/// A request as it goes over the wire, with the trace it is part of
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct Envelope {
+    /// The trace spanning several peers the request is part of, if any.
+    /// Requests the handler sends on carry the same trace
+    pub trace: Option<TraceId>,
+
+    pub request: Request,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+#[non_exhaustive]
 pub enum Response {
     /// Respond with success
     Ok,
@@ -76,10 +245,119 @@ pub enum Response {
     /// Respond with this Peer's complete PeerStore
     /// Responds to Request::PeerStore
     PeerStore(PeerStore),
+
+    /// The responses to each request in a Request::Batch, in order
+    Batch(
+        #[serde(deserialize_with = "unnested")]
+        #[cfg_attr(feature = "tools", schemars(with = "Vec<Response>"))]
+        Vec<Response>,
+    ),
+
+    /// Respond with this peer's traffic counters
+    /// Responds to Request::Stats
+    Stats(TrafficStats),
+
+    /// Respond with a description of this peer
+    /// Responds to Request::Info
+    Info(NodeInfo),
+
+    /// Respond with this peer's time, in milliseconds since the epoch
+    /// Responds to Request::Time
+    Time(i64),
+
+    /// This peer is short on memory, and put off the gossip request it was
+    /// sent. Try again later. Connections over the inbound limits get no
+    /// answer at all: they are closed before the handshake
+    Busy,
+
+    /// Respond with the value stored under a key
+    /// Responds to Request::Get
+    Value(Vec<u8>),
+
+    /// Name the peer holding a key
+    /// Responds to Request::QueryKey
+    RespondKey { holding_id: PeerId, key: Key },
+
+    /// The peers this peer knows closest to a point, closest first
+    /// Responds to Request::FindNode
+    Nodes(Vec<PeerId>),
+
+    /// The live peers this peer knows, most recently seen first
+    /// Responds to Request::LivePeers
+    LivePeers(Vec<PeerId>),
+
+    /// The messages this peer held for the collector, oldest first
+    /// Responds to Request::Collect
+    Messages(Vec<Message>),
+
+    /// This peer's status, for a friend that came online
+    /// Responds to Request::Presence
+    Presence(String),
 }
 
-/// A general protocol for this framework
-/*
+thread_local! {
+    /// Whether this thread is decoding the contents of a batch
+    static IN_BATCH: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Decode the contents of a batch, refusing a batch nested inside it
+/// before going any deeper. Otherwise a frame of deeply nested batches
+/// would recurse until the stack overflows
+fn unnested<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    if IN_BATCH.with(|b| b.replace(true)) {
+        return Err(de::Error::custom("nested batch"));
+    }
+    let items = Vec::deserialize(d);
+    IN_BATCH.with(|b| b.set(false));
+    items
+}
+
+/// What a peer reports about itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct NodeInfo {
+    pub id: PeerId,
+
+    /// Name and version of the software the peer runs
+    pub agent: String,
+
+    /// Seconds the peer has been up
+    pub uptime: u64,
+
+    /// Number of peers in its PeerStore
+    pub peers: usize,
+
+    /// Roughly how many bytes its PeerStore takes up
+    pub peerstore_bytes: usize,
+
+    /// Which phase of its lifecycle it is in
+    pub state: State,
+
+    /// Number of peers it is ignoring for now
+    pub greylisted: usize,
+}
+
+impl Response {
+    /// The class of traffic this response counts towards
+    pub fn class(&self) -> TrafficClass {
+        match self {
+            Response::PeerStore(_)
+            | Response::Nodes(_)
+            | Response::LivePeers(_)
+            | Response::Batch(_) => TrafficClass::Gossip,
+            Response::List(_) | Response::Value(_) | Response::Messages(_) => {
+                TrafficClass::Content
+            }
+            _ => TrafficClass::Control,
+        }
+    }
+}
+
+/* Request handlers:
     Ping
     Identity
     List
@@ -89,67 +367,461 @@ pub enum Response {
     RespondKey
     Get
     SyncPeers
+    FindNode
     Leave
+    Batch
+    DialBack
+    Stats
+    Info
+    Time
+    LivePeers
+    Message
+    Collect
+    Presence
+    Membership
+    Publish
 */
 
-pub trait Protocol {
-    fn handle_ping(&self, conn: &mut TcpStream) -> NetworkResult<usize>;
-    fn handle_identity(&self, conn: &mut TcpStream) -> NetworkResult<usize>;
-    fn handle_list(&self, conn: &mut TcpStream) -> NetworkResult<usize>;
-    fn handle_peerstore(&self, conn: &mut TcpStream) -> NetworkResult<usize>;
+/// A general protocol for this framework
+pub trait Protocol: crate::sealed::Sealed {
+    fn handle_ping(&self) -> NetworkResult<Response>;
+    fn handle_identity(&self) -> NetworkResult<Response>;
+    fn handle_list(&self) -> NetworkResult<Response>;
+    fn handle_get(&self, key: Key) -> NetworkResult<Response>;
+    fn handle_peerstore(&self) -> NetworkResult<Response>;
     fn handle_join(
         &mut self,
-        conn: &mut TcpStream,
+        from: IpAddr,
+        remote: Option<&Remote>,
         new_peer: PeerId,
-    ) -> NetworkResult<usize>;
+    ) -> NetworkResult<Response>;
+    fn handle_query_key(&self, key: Key, tts: u16) -> NetworkResult<Response>;
     /* ... */
-    fn handle_leave(&self, conn: &mut TcpStream) -> NetworkResult<usize>;
+    fn handle_find_node(&self, target: Point) -> NetworkResult<Response>;
+    fn handle_leave(
+        &self,
+        from: IpAddr,
+        remote: Option<&Remote>,
+        leaving: PeerId,
+    ) -> NetworkResult<Response>;
+    fn handle_batch(
+        &mut self,
+        from: IpAddr,
+        remote: Option<&Remote>,
+        requests: Vec<Request>,
+    ) -> NetworkResult<Response>;
+    fn handle_dial_back(&self, from: IpAddr, port: u16) -> NetworkResult<Response>;
+    fn handle_stats(&self) -> NetworkResult<Response>;
+    fn handle_info(&self) -> NetworkResult<Response>;
+    fn handle_time(&self) -> NetworkResult<Response>;
+    fn handle_live_peers(&self) -> NetworkResult<Response>;
+    fn handle_message(&self, message: Message) -> NetworkResult<Response>;
+    fn handle_collect(
+        &self,
+        remote: Option<&Remote>,
+        key: PublicKey,
+    ) -> NetworkResult<Response>;
+    fn handle_presence(
+        &self,
+        remote: Option<&Remote>,
+        from: PublicKey,
+        status: String,
+    ) -> NetworkResult<Response>;
+    fn handle_membership(&self, record: Membership) -> NetworkResult<Response>;
+    fn handle_publish(&self, publication: Publication) -> NetworkResult<Response>;
 }
 
+/// Each handler returns the response to send back to the requesting peer
 impl Protocol for Peer {
     /// Handle an incoming Request::Ping
-    fn handle_ping(&self, conn: &mut TcpStream) -> NetworkResult<usize> {
-        Peer::send_response(conn, Response::Pong)
+    fn handle_ping(&self) -> NetworkResult<Response> {
+        Ok(Response::Pong)
     }
 
     /// Handle an incoming Request::Identity
-    fn handle_identity(&self, conn: &mut TcpStream) -> NetworkResult<usize> {
-        Peer::send_response(conn, Response::Identity(self.id.clone()))
+    fn handle_identity(&self) -> NetworkResult<Response> {
+        Ok(Response::Identity(self.id.clone()))
     }
 
     /// Return a list of keys stored on this peer
-    fn handle_list(&self, conn: &mut TcpStream) -> NetworkResult<usize> {
-        Peer::send_response(
-            conn,
-            Response::Err(NetworkError::Fail("no keys stored yet".to_string())),
+    fn handle_list(&self) -> NetworkResult<Response> {
+        Ok(Response::List(self.store.lock().list()))
+    }
+
+    /// Return the value stored under a key on this peer
+    fn handle_get(&self, key: Key) -> NetworkResult<Response> {
+        let value = self.store.lock().get(&key);
+        Ok(
+            match value.map_err(|e| NetworkError::Fail(format!("reading {key}: {e}")))? {
+                Some(value) => Response::Value(value),
+                None => {
+                    Response::Err(NetworkError::Fail(format!("no value for key {key}")))
+                }
+            },
         )
     }
 
-    /// Return this peer's entire PeerStore
-    fn handle_peerstore(&self, conn: &mut TcpStream) -> NetworkResult<usize> {
-        let peers = self.peers.clone();
-        let peers = peers.lock().unwrap();
-        Peer::send_response(conn, Response::PeerStore(peers.clone()))
+    /// Return the most recently seen peers in this peer's PeerStore
+    fn handle_peerstore(&self) -> NetworkResult<Response> {
+        let peers = self.lock_peers()?;
+        Ok(Response::PeerStore(
+            peers.take_recent(MAX_PEERSTORE_RESPONSE),
+        ))
     }
 
-    /// Request to join this peer's PeerStore
+    /// Request to join this peer's PeerStore. Only the peer itself may ask
+    /// for its id to be added, and the static key it asked with is kept, so
+    /// later requests speaking for it can be checked against it
     fn handle_join(
         &mut self,
-        conn: &mut TcpStream,
+        from: IpAddr,
+        remote: Option<&Remote>,
         new_peer: PeerId,
-    ) -> NetworkResult<usize> {
-        if self.add_peer(self.id.clone()) {
-            return Peer::send_response(
-                conn,
-                Response::Err(NetworkError::Fail("peer already joined".to_string())),
-            );
+    ) -> NetworkResult<Response> {
+        if let Err(why) = self.check_claim(from, remote, &new_peer) {
+            return Ok(Response::Err(NetworkError::Rejected(
+                Reason::Unauthorized,
+                why,
+            )));
+        }
+        if !self.add_peer(new_peer.clone()) {
+            return Ok(Response::Err(NetworkError::Fail(
+                "peer already joined".to_string(),
+            )));
+        }
+        self.mark_seen(&new_peer);
+        if let Some(remote) = remote {
+            self.peers
+                .lock()
+                .update(&new_peer, |entry| entry.joined_with = Some(remote.clone()));
+        }
+        Ok(Response::Msg("join success".to_string()))
+    }
+
+    /// Answer whether this peer, or a peer up to `tts` hops away, holds a
+    /// key. Queries are forwarded to a few known peers at once, and the
+    /// first to name a holder wins. Forwarded queries carry this query's
+    /// trace, so a peer reached by two paths only answers one of them
+    fn handle_query_key(&self, key: Key, tts: u16) -> NetworkResult<Response> {
+        if self.store.lock().contains(&key) {
+            return Ok(Response::RespondKey {
+                holding_id: self.id.clone(),
+                key,
+            });
+        }
+        let not_found = || {
+            Ok(Response::Err(NetworkError::Fail(format!(
+                "key {key} not found"
+            ))))
+        };
+        if tts == 0 {
+            return not_found();
+        }
+
+        match self.query_holders(&key, tts, 1).pop() {
+            Some(holding_id) => Ok(Response::RespondKey { holding_id, key }),
+            None => not_found(),
         }
-        Peer::send_response(conn, Response::Msg("join success".to_string()))
     }
 
     /* ... */
 
-    fn handle_leave(&self, conn: &mut TcpStream) -> NetworkResult<usize> {
-        Ok(0)
+    /// Name the peers this peer knows closest to `target`
+    fn handle_find_node(&self, target: Point) -> NetworkResult<Response> {
+        let peers = self.lock_peers()?;
+        let nodes = peers.closest(&target, K_BUCKET_SIZE);
+        Ok(Response::Nodes(
+            nodes.into_iter().map(|entry| entry.id().clone()).collect(),
+        ))
+    }
+
+    /// Forget a peer that is leaving the network. Only a peer can say it
+    /// is leaving, so the request must come from its own address
+    fn handle_leave(
+        &self,
+        from: IpAddr,
+        remote: Option<&Remote>,
+        leaving: PeerId,
+    ) -> NetworkResult<Response> {
+        let unauthorized = |why: String| {
+            Ok(Response::Err(NetworkError::Rejected(
+                Reason::Unauthorized,
+                why,
+            )))
+        };
+        if let Err(why) = self.check_claim(from, remote, &leaving) {
+            return unauthorized(why);
+        }
+
+        // It has to be the peer on file: at the same address, and holding
+        // the key it joined with, if it joined us
+        let known = self
+            .peers
+            .lock()
+            .get(&leaving)
+            .map(|entry| (entry.id.as_socket(), entry.joined_with.clone()));
+        match known {
+            Some((addr, _)) if addr != leaving.as_socket() => {
+                unauthorized(format!("{leaving:?} is known at {addr}"))
+            }
+            Some((_, Some(key))) if remote != Some(&key) => {
+                unauthorized(format!("{from} did not prove what {leaving:?} joined with"))
+            }
+            Some(_) => {
+                if self.remove_peer(&leaving) {
+                    info!("{leaving:?} left the network");
+                }
+                Ok(Response::Ok)
+            }
+            None => Ok(Response::Ok),
+        }
+    }
+
+    /// Handle each request in a batch in order, answering with a batch of
+    /// their responses. Batches cannot be nested
+    fn handle_batch(
+        &mut self,
+        from: IpAddr,
+        remote: Option<&Remote>,
+        requests: Vec<Request>,
+    ) -> NetworkResult<Response> {
+        let responses = requests
+            .into_iter()
+            .map(|req| match req {
+                Request::Batch(_) => Response::Err(NetworkError::Rejected(
+                    Reason::Malformed,
+                    "nested batch".to_string(),
+                )),
+                // Whatever is left once the batch's budget is spent is dropped
+                req if trace::time_left(HANDLER_BUDGET).is_zero() => {
+                    Response::Err(NetworkError::Rejected(
+                        Reason::TooSlow,
+                        format!("the batch ran out of time before {}", req.kind()),
+                    ))
+                }
+                req => self
+                    .dispatch(from, remote, req)
+                    .unwrap_or_else(Response::Err),
+            })
+            .collect();
+        Ok(Response::Batch(responses))
+    }
+
+    /// Return this peer's traffic counters
+    fn handle_stats(&self) -> NetworkResult<Response> {
+        Ok(Response::Stats(TrafficStats::snapshot()))
+    }
+
+    /// Describe this peer
+    fn handle_info(&self) -> NetworkResult<Response> {
+        let peers = self.lock_peers()?;
+        Ok(Response::Info(NodeInfo {
+            id: self.id.clone(),
+            agent: AGENT.to_string(),
+            uptime: self.uptime().as_secs(),
+            peers: peers.len(),
+            peerstore_bytes: peers.memory_usage(),
+            state: self.state(),
+            greylisted: self.greylisted().len(),
+        }))
+    }
+
+    /// Tell the requester what time it is here
+    fn handle_time(&self) -> NetworkResult<Response> {
+        Ok(Response::Time(clock::now_millis()))
+    }
+
+    /// Name every live peer in this peer's PeerStore. The PeerStore holds
+    /// at most MAX_PEERS, so they all fit in one transfer
+    fn handle_live_peers(&self) -> NetworkResult<Response> {
+        let peers = self.lock_peers()?;
+        Ok(Response::LivePeers(
+            peers.live().map(|entry| entry.id().clone()).collect(),
+        ))
+    }
+
+    /// Take a message for this peer's identity from a friend, or hold one
+    /// sent from one friend to another. Messages are only ever between
+    /// friends, so strangers can't fill the inbox or use this peer as a
+    /// mailbox
+    fn handle_message(&self, message: Message) -> NetworkResult<Response> {
+        let refuse =
+            |reason, why: String| Ok(Response::Err(NetworkError::Rejected(reason, why)));
+        let identity = match self.identity() {
+            Some(identity) => identity,
+            None => {
+                return refuse(
+                    Reason::Unsupported,
+                    "this peer has no identity".to_string(),
+                )
+            }
+        };
+        if !message.verify() {
+            return refuse(
+                Reason::Unauthorized,
+                format!("not signed by {}", message.from),
+            );
+        }
+        let friends = self.friends.lock();
+        if !friends.contains(&message.from) {
+            return refuse(
+                Reason::Unauthorized,
+                format!("{} is not a friend", message.from),
+            );
+        }
+
+        if message.to == identity.public_key() {
+            drop(friends);
+            let body = match message.open(identity) {
+                Ok(body) => body,
+                Err(e) => return refuse(Reason::Malformed, e.to_string()),
+            };
+            info!("message from {}", message.from);
+            event::emit(
+                &self.events,
+                Event::Message {
+                    from: message.from,
+                    sent: message.sent,
+                    body,
+                },
+            );
+            return Ok(Response::Ok);
+        }
+
+        if !friends.contains(&message.to) {
+            return refuse(
+                Reason::Unauthorized,
+                format!("{} is not a friend", message.to),
+            );
+        }
+        drop(friends);
+        let mut held = self.held.lock();
+        let queue = held.entry(message.to).or_default();
+        if queue.len() >= MAX_HELD_MESSAGES {
+            return refuse(
+                Reason::Overloaded,
+                format!(
+                    "already holding {MAX_HELD_MESSAGES} messages for {}",
+                    message.to
+                ),
+            );
+        }
+        info!("holding a message from {} for {}", message.from, message.to);
+        queue.push_back(message);
+        Ok(Response::Ok)
+    }
+
+    /// Hand over the messages held for a key to whoever holds it
+    fn handle_collect(
+        &self,
+        remote: Option<&Remote>,
+        key: PublicKey,
+    ) -> NetworkResult<Response> {
+        match remote {
+            Some(Remote::Key(remote)) if key.to_x25519().as_ref() == Some(remote) => {
+                let messages = self.held.lock().remove(&key).unwrap_or_default();
+                Ok(Response::Messages(messages.into()))
+            }
+            _ => Ok(Response::Err(NetworkError::Rejected(
+                Reason::Unauthorized,
+                format!("the collector did not prove it holds {key}"),
+            ))),
+        }
+    }
+
+    /// Note that a friend came online, and answer with this peer's status.
+    /// Only friends are answered, so only mutual friends see each other
+    fn handle_presence(
+        &self,
+        remote: Option<&Remote>,
+        from: PublicKey,
+        status: String,
+    ) -> NetworkResult<Response> {
+        let refuse =
+            |reason, why: String| Ok(Response::Err(NetworkError::Rejected(reason, why)));
+        match remote {
+            Some(Remote::Key(remote)) if from.to_x25519().as_ref() == Some(remote) => (),
+            _ => {
+                return refuse(
+                    Reason::Unauthorized,
+                    format!("the sender did not prove it holds {from}"),
+                )
+            }
+        }
+        if !self.friends.lock().contains(&from) {
+            return refuse(Reason::Unauthorized, format!("{from} is not a friend"));
+        }
+        if status.len() > MAX_STATUS_LEN {
+            return refuse(
+                Reason::TooLarge,
+                format!("status is over {MAX_STATUS_LEN} bytes"),
+            );
+        }
+
+        info!("friend {from} is online");
+        self.statuses.lock().insert(from, status.clone());
+        event::emit(&self.events, Event::FriendOnline { from, status });
+        Ok(Response::Presence(self.status.lock().clone()))
+    }
+
+    /// Take a newer membership record for a group, and pass it on to the
+    /// members this peer knows. Stale copies are refused, which stops it
+    /// going round
+    fn handle_membership(&self, record: Membership) -> NetworkResult<Response> {
+        if let Err(e) = self.adopt_membership(&record) {
+            return Ok(Response::Err(e));
+        }
+        info!("group {} is at version {}", record.group, record.version);
+        let node = self.clone();
+        thread::spawn(move || {
+            let group = record.group;
+            node.push_to_members(&group, || Request::Membership(record.clone()))
+        });
+        Ok(Response::Ok)
+    }
+
+    /// Store a value a member published into a group this peer is in, and
+    /// pass it on to the members this peer knows if it is new here
+    fn handle_publish(&self, publication: Publication) -> NetworkResult<Response> {
+        match self.adopt_publication(&publication) {
+            Ok(true) => {
+                let node = self.clone();
+                thread::spawn(move || {
+                    let group = publication.group;
+                    node.push_to_members(&group, || Request::Publish(publication.clone()))
+                });
+                Ok(Response::Ok)
+            }
+            Ok(false) => Ok(Response::Ok),
+            Err(e) => Ok(Response::Err(e)),
+        }
+    }
+
+    /// Dial the requester back on a fresh connection and ping it
+    fn handle_dial_back(&self, from: IpAddr, port: u16) -> NetworkResult<Response> {
+        let ip = match from {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => {
+                return Ok(Response::Err(NetworkError::Fail(format!(
+                    "cannot dial back ipv6 address {ip}"
+                ))))
+            }
+        };
+
+        let requester = PeerId::new(ip, port);
+        let timeout = trace::time_left(DIAL_TIMEOUT);
+        let pong = Peer::send_request_timeout(&requester, Request::Ping, timeout)
+            .and_then(|mut conn| Peer::recv_response(&mut conn));
+        match pong {
+            Ok(Response::Pong) => Ok(Response::Ok),
+            Ok(res) => {
+                warn!("dial back to {requester:?} answered with {res:?}");
+                Ok(Response::Err(NetworkError::NoRoute(requester)))
+            }
+            Err(e) => {
+                warn!("dial back to {requester:?} failed: {e}");
+                Ok(Response::Err(NetworkError::NoRoute(requester)))
+            }
+        }
     }
 }
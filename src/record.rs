@@ -0,0 +1,179 @@
+use crate::{
+    noise::Conn,
+    peer::{Peer, PeerId},
+    protocol::{Envelope, NetworkResult, Request, Response},
+    trace,
+    transport::{self, Transport},
+    Error, DIAL_TIMEOUT,
+};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    path::Path,
+};
+
+/// Where the frames of the current session are being recorded, if anywhere
+static RECORDER: Mutex<Option<File>> = Mutex::new(None);
+
+/// What a recorded frame carried
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Request,
+    Response,
+}
+
+/// Whether a recorded frame was sent or received by this process
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single message that went over the wire
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub direction: Direction,
+
+    /// Milliseconds since the epoch when the frame was recorded
+    pub at: i64,
+
+    /// The frame exactly as it went over the wire
+    pub bytes: Vec<u8>,
+}
+
+/// Start recording every frame sent or received by this process to the
+/// file at `path`, replacing any recording already in progress
+pub fn start_recording<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let file = File::create(path.as_ref())?;
+    *RECORDER.lock() = Some(file);
+    info!("recording session to {:?}", path.as_ref());
+    Ok(())
+}
+
+/// Stop recording frames
+pub fn stop_recording() {
+    *RECORDER.lock() = None;
+}
+
+/// Record a frame if a recording is in progress. Recording failures are
+/// logged rather than failing the request being recorded
+pub(crate) fn record(kind: FrameKind, direction: Direction, bytes: &[u8]) {
+    let mut recorder = RECORDER.lock();
+    if let Some(file) = recorder.as_mut() {
+        let frame = Frame {
+            kind,
+            direction,
+            at: chrono::Utc::now().timestamp_millis(),
+            bytes: bytes.to_vec(),
+        };
+        if let Err(e) = write_frame(file, &frame) {
+            warn!("could not record frame, stopping recording: {e}");
+            *recorder = None;
+        }
+    }
+}
+
+/// Frames are stored as a little endian u32 length followed by the
+/// bincode encoded frame
+fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<(), Error> {
+    let ser = bincode::serialize(frame)?;
+    w.write_all(&(ser.len() as u32).to_le_bytes())?;
+    w.write_all(&ser)?;
+    Ok(())
+}
+
+/// A recorded session
+#[derive(Debug, Default)]
+pub struct Session {
+    pub frames: Vec<Frame>,
+}
+
+impl Session {
+    /// Load a session recorded with `start_recording`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a session recorded with `start_recording`
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut frames = Vec::new();
+        let mut len = [0u8; 4];
+        loop {
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            // Grow the buffer as bytes arrive, in case this isn't a recording
+            let len = u32::from_le_bytes(len) as usize;
+            let mut buf = Vec::new();
+            reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() < len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            frames.push(bincode::deserialize(&buf)?);
+        }
+        Ok(Self { frames })
+    }
+
+    /// Decode every recorded request, in order
+    pub fn requests(&self) -> Vec<Request> {
+        self.frames
+            .iter()
+            .filter(|f| f.kind == FrameKind::Request)
+            .filter_map(|f| bincode::deserialize::<Envelope>(&f.bytes).ok())
+            .map(|envelope| envelope.request)
+            .collect()
+    }
+
+    /// Resend every recorded request, byte for byte, to the peer at
+    /// `target`, returning what it answered to each
+    pub fn replay(&self, target: &PeerId) -> Vec<NetworkResult<Response>> {
+        self.frames
+            .iter()
+            .filter(|f| f.kind == FrameKind::Request)
+            .map(|f| replay_frame(target, &f.bytes))
+            .collect()
+    }
+}
+
+fn replay_frame(target: &PeerId, bytes: &[u8]) -> NetworkResult<Response> {
+    let addr = SocketAddr::from((target.ip(), target.port()));
+    let conn = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)?;
+    conn.set_read_timeout(Some(DIAL_TIMEOUT))?;
+    let mut conn = Conn::dial(conn, target)?;
+    transport::write_frame(&mut conn, bytes)?;
+    Peer::recv_response(&mut conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_session() {
+        let path = std::env::temp_dir().join("harbor_test_session.bin");
+        start_recording(&path).unwrap();
+        record(
+            FrameKind::Request,
+            Direction::Sent,
+            &bincode::serialize(&trace::envelope(Request::Ping)).unwrap(),
+        );
+        record(
+            FrameKind::Response,
+            Direction::Received,
+            &bincode::serialize(&Response::Pong).unwrap(),
+        );
+        stop_recording();
+
+        let session = Session::load(&path).unwrap();
+        assert_eq!(session.frames.len(), 2);
+        assert!(matches!(session.requests()[..], [Request::Ping]));
+        std::fs::remove_file(path).unwrap();
+    }
+}
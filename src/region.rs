@@ -0,0 +1,74 @@
+use crate::Error;
+use std::{fmt, net::IpAddr};
+
+/// A coarse place on the map a peer's address is in
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Region {
+    /// Two letter continent code, like "EU"
+    pub continent: String,
+
+    /// ISO 3166 country code, like "DE", when known
+    pub country: Option<String>,
+}
+
+impl Region {
+    /// How close two regions are: 2 in the same country, 1 on the same
+    /// continent, 0 otherwise
+    pub fn closeness(&self, other: &Region) -> u8 {
+        match (&self.country, &other.country) {
+            (Some(a), Some(b)) if a == b => 2,
+            _ if self.continent == other.continent => 1,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.country {
+            Some(country) => write!(f, "{}/{country}", self.continent),
+            None => write!(f, "{}", self.continent),
+        }
+    }
+}
+
+/// Tells which region an address is in. Implement this to place peers from
+/// something other than a GeoIP database
+pub trait Locator: fmt::Debug + Send + Sync {
+    fn locate(&self, ip: IpAddr) -> Option<Region>;
+}
+
+/// A local MaxMind GeoIP2 or GeoLite2 database, Country or City
+#[cfg(feature = "geoip")]
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|e| Error::Decode(format!("bad GeoIP database: {e}")))?;
+        Ok(Self { reader })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl Locator for GeoIp {
+    fn locate(&self, ip: IpAddr) -> Option<Region> {
+        let found: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        Some(Region {
+            continent: found.continent?.code?.to_string(),
+            country: found.country.and_then(|c| c.iso_code).map(str::to_string),
+        })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
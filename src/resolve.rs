@@ -0,0 +1,261 @@
+use crate::{DEFAULT_DNS_TTL, DIAL_TIMEOUT};
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData};
+use futures::{channel::oneshot, future::BoxFuture};
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The addresses a hostname resolved to, and how long they may be cached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    pub addrs: Vec<Ipv4Addr>,
+    pub ttl: Duration,
+}
+
+/// Turns hostnames into addresses without blocking the caller. Implement
+/// this to plug in a different way of resolving, like DNS-over-HTTPS
+pub trait Resolver: fmt::Debug + Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>>;
+}
+
+/// Run a blocking lookup on its own thread, so whoever awaits it can get
+/// on with other work, whatever runtime (if any) it is on
+fn off_thread<F>(f: F) -> BoxFuture<'static, io::Result<Lookup>>
+where
+    F: FnOnce() -> io::Result<Lookup> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || tx.send(f()));
+    Box::pin(async move {
+        rx.await
+            .unwrap_or_else(|_| Err(io::Error::other("the lookup thread died")))
+    })
+}
+
+/// Resolve with the operating system's resolver. It does not report TTLs,
+/// so every lookup is assumed to be good for `DEFAULT_DNS_TTL`
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        let host = host.to_string();
+        off_thread(move || {
+            let addrs = (host.as_str(), 0)
+                .to_socket_addrs()?
+                .filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(*addr.ip()),
+                    SocketAddr::V6(_) => None,
+                })
+                .collect();
+            Ok(Lookup {
+                addrs,
+                ttl: DEFAULT_DNS_TTL,
+            })
+        })
+    }
+}
+
+/// Resolve by asking a specific DNS server over UDP
+#[derive(Debug)]
+pub struct DnsServer(pub SocketAddr);
+
+impl Resolver for DnsServer {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        let (server, host) = (self.0, host.to_string());
+        off_thread(move || query(server, &host))
+    }
+}
+
+/// Ask `server` for the A records of `host`. Each query gets a random id,
+/// and only an answer from the server to that id and question is taken, so
+/// an off-path attacker has to guess the id and our port to forge one
+fn query(server: SocketAddr, host: &str) -> io::Result<Lookup> {
+    let bad = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut id = [0u8; 2];
+    getrandom::getrandom(&mut id).map_err(io::Error::other)?;
+    let id = u16::from_le_bytes(id);
+    let mut query = Builder::new_query(id, true);
+    query.add_question(host, false, QueryType::A, QueryClass::IN);
+    let query = query
+        .build()
+        .map_err(|_| bad(format!("hostname {host} is too long")))?;
+
+    // Connected, the socket only hears from the server
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(server)?;
+    socket.send(&query)?;
+
+    // Anything that isn't the answer is dropped, and we keep listening
+    let deadline = Instant::now() + DIAL_TIMEOUT;
+    let mut buf = [0; 512];
+    let packet = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(left))?;
+        let len = socket.recv(&mut buf)?;
+        match Packet::parse(&buf[..len]) {
+            Ok(packet) if answers(&packet, id, host) => break packet,
+            Ok(_) => warn!("dropping a DNS answer that doesn't match our query"),
+            Err(e) => warn!("dropping a bad DNS answer: {e}"),
+        }
+    };
+
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for answer in packet.answers.iter() {
+        if let RData::A(a) = answer.data {
+            addrs.push(a.0);
+            ttl = ttl.min(answer.ttl);
+        }
+    }
+    let ttl = match addrs.is_empty() {
+        true => DEFAULT_DNS_TTL,
+        false => Duration::from_secs(ttl as u64),
+    };
+    Ok(Lookup { addrs, ttl })
+}
+
+/// Whether `packet` answers the A query with `id` for `host`
+fn answers(packet: &Packet, id: u16, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    !packet.header.query
+        && packet.header.id == id
+        && matches!(&packet.questions[..], [q]
+            if q.qtype == QueryType::A
+                && q.qclass == QueryClass::IN
+                && q.qname.to_string().eq_ignore_ascii_case(host))
+}
+
+/// Remember lookups from another resolver for as long as their TTL allows
+#[derive(Debug)]
+pub struct CachingResolver<R> {
+    inner: R,
+    cache: Mutex<HashMap<String, (Lookup, Instant)>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async move {
+            if let Some((lookup, expires)) = self.cache.lock().get(host) {
+                if Instant::now() < *expires {
+                    return Ok(lookup.clone());
+                }
+            }
+            let lookup = self.inner.lookup(host).await?;
+            info!("resolved {host} to {:?}", lookup.addrs);
+            let expires = Instant::now() + lookup.ttl;
+            self.cache
+                .lock()
+                .insert(host.to_string(), (lookup.clone(), expires));
+            Ok(lookup)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct Counting(AtomicUsize);
+
+    impl Resolver for Counting {
+        fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+            let n = self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(future::ready(Ok(Lookup {
+                addrs: vec![Ipv4Addr::new(10, 0, 0, n as u8)],
+                ttl: if host == "short" {
+                    Duration::ZERO
+                } else {
+                    Duration::from_secs(60)
+                },
+            })))
+        }
+    }
+
+    #[test]
+    fn test_caching_resolver() {
+        let resolver = CachingResolver::new(Counting::default());
+        let lookup = |host| block_on(resolver.lookup(host)).unwrap();
+        let first = lookup("seed");
+        assert_eq!(lookup("seed"), first);
+
+        // An expired answer is looked up again
+        let short = lookup("short");
+        assert_ne!(lookup("short"), short);
+        assert_eq!(resolver.inner.0.load(Ordering::Relaxed), 3);
+    }
+
+    /// An answer to `query` giving `host` the address `ip`
+    fn answer(query: &[u8], ip: [u8; 4]) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] |= 0x80; // A response
+        answer[7] = 1; // With one answer
+        answer.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        answer.extend_from_slice(&ip);
+        answer
+    }
+
+    #[test]
+    fn test_dns_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let resolver = DnsServer(addr);
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let answering = thread::spawn(move || {
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let mut buf = [0; 512];
+                let (len, client) = server.recv_from(&mut buf).unwrap();
+                let query = &buf[..len];
+                ids.push(u16::from_be_bytes([query[0], query[1]]));
+
+                // A forgery from somewhere else, even knowing the id, then
+                // answers to another id and another name from the server
+                spoofer
+                    .send_to(&answer(query, [6, 6, 6, 6]), client)
+                    .unwrap();
+                let mut other_id = answer(query, [6, 6, 6, 7]);
+                other_id[0] ^= 0xff;
+                server.send_to(&other_id, client).unwrap();
+                let mut other_name = answer(query, [6, 6, 6, 8]);
+                other_name[14] = b'x';
+                server.send_to(&other_name, client).unwrap();
+                server
+                    .send_to(&answer(query, [10, 0, 0, 1]), client)
+                    .unwrap();
+            }
+            ids
+        });
+
+        for _ in 0..2 {
+            let lookup = block_on(resolver.lookup("seed.example")).unwrap();
+            assert_eq!(lookup.addrs, [Ipv4Addr::new(10, 0, 0, 1)]);
+            assert_eq!(lookup.ttl, Duration::from_secs(60));
+        }
+        // Every query gets a fresh id
+        let ids = answering.join().unwrap();
+        assert_ne!(ids[0], ids[1]);
+    }
+}
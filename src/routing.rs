@@ -0,0 +1,93 @@
+use crate::{
+    peer::{Key, PeerId},
+    util,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A position in the space PeerIds and keys are spread over, taken from
+/// their hashes. How close two points are is their XOR distance
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct Point(u128);
+
+/// The XOR distance between two points. Points sharing a longer prefix
+/// are closer
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Distance(u128);
+
+impl Point {
+    /// Where a peer sits, from the hash in its id. Forged ids whose hash
+    /// isn't hex still get a point, from hashing the whole id
+    pub fn of_peer(id: &PeerId) -> Self {
+        match u128::from_str_radix(id.hash(), 16) {
+            Ok(point) => Self(point),
+            Err(_) => Self::of_bytes(id.to_string().as_bytes()),
+        }
+    }
+
+    /// Where a key sits, from the hash of its name
+    pub fn of_key(key: &Key) -> Self {
+        Self::of_bytes(key.to_string().as_bytes())
+    }
+
+    fn of_bytes(bytes: &[u8]) -> Self {
+        Self(u128::from_str_radix(&util::hash_sha256(bytes), 16).unwrap())
+    }
+
+    pub fn distance(&self, other: &Point) -> Distance {
+        Distance(self.0 ^ other.0)
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl Distance {
+    /// Which k-bucket a peer this far away goes in: the index of the
+    /// highest differing bit, so bucket `i` covers distances in
+    /// [2^i, 2^(i+1)). A point is no distance from itself, and has no bucket
+    pub fn bucket(&self) -> Option<usize> {
+        match self.0 {
+            0 => None,
+            d => Some(127 - d.leading_zeros() as usize),
+        }
+    }
+
+    /// Where this is the distance from a peer to a target, how close to
+    /// the target the peers in the peer's bucket `i` can be. They are all
+    /// within the 2^i distances from there, and no two buckets overlap, so
+    /// visiting buckets in this order visits peers nearest first
+    pub(crate) fn nearest_in_bucket(&self, i: usize) -> Distance {
+        Distance(((self.0 >> i) ^ 1) << i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        let a = PeerId::from("10.0.0.1".parse().unwrap(), 3300);
+        let b = PeerId::from("10.0.0.2".parse().unwrap(), 3300);
+        let (pa, pb) = (Point::of_peer(&a), Point::of_peer(&b));
+        assert_eq!(pa.to_string(), a.hash());
+        assert_eq!(pa.distance(&pb), pb.distance(&pa));
+        assert_eq!(pa.distance(&pa).bucket(), None);
+
+        assert_eq!(Distance(1).bucket(), Some(0));
+        assert_eq!(Distance(0b1010).bucket(), Some(3));
+        assert_eq!(Distance(u128::MAX).bucket(), Some(127));
+        assert!(Distance(0b0111) < Distance(0b1000));
+
+        // 0b1010 from the target, peers in bucket 3 are 0b0xxx from it, and
+        // in bucket 1 0b100x
+        assert_eq!(Distance(0b1010).nearest_in_bucket(3), Distance(0));
+        assert_eq!(Distance(0b1010).nearest_in_bucket(1), Distance(0b1000));
+        assert_eq!(Distance(0b1010).nearest_in_bucket(2), Distance(0b1100));
+    }
+}
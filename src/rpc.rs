@@ -0,0 +1,347 @@
+// tonic answers with a Status, however large it is
+#![allow(clippy::result_large_err)]
+
+use crate::{
+    alias::Aliases,
+    lifecycle::State,
+    peer::{Key, Peer, PeerId},
+    Error,
+};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use tonic::{transport::server::TcpIncoming, Request, Response, Status};
+
+mod generated {
+    tonic::include_proto!("harbor.Control");
+}
+
+pub use generated::control_client::ControlClient;
+use generated::control_server::{Control, ControlServer};
+
+/// How often the server checks whether its peer is stopping
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/* Messages, mirroring proto/harbor.proto */
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PingRequest {
+    /// The peer to ping. Empty pings every known peer
+    #[prost(string, tag = "1")]
+    pub peer: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PingReply {
+    /// The peers that answered
+    #[prost(string, repeated, tag = "1")]
+    pub answered: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPeersRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPeersReply {
+    #[prost(string, repeated, tag = "1")]
+    pub peers: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PutReply {
+    /// Whether a value was already stored under the key
+    #[prost(bool, tag = "1")]
+    pub replaced: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetReply {
+    #[prost(bytes = "vec", tag = "1")]
+    pub value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct JoinRequest {
+    #[prost(string, tag = "1")]
+    pub peer: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct JoinReply {
+    /// Whether the peer was new to the node's PeerStore
+    #[prost(bool, tag = "1")]
+    pub added: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetGreylistRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Greylisted {
+    #[prost(string, tag = "1")]
+    pub peer: String,
+
+    /// Seconds until the peer is considered again
+    #[prost(uint64, tag = "2")]
+    pub seconds_left: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetGreylistReply {
+    #[prost(message, repeated, tag = "1")]
+    pub peers: Vec<Greylisted>,
+}
+
+/// The gRPC control API of a running peer, served on localhost so local
+/// tools can drive the node
+#[derive(Debug)]
+pub struct RpcServer {
+    addr: SocketAddr,
+    handle: thread::JoinHandle<Result<(), Error>>,
+}
+
+impl RpcServer {
+    /// Serve `node`'s control API on localhost at `port` until the node
+    /// starts draining, resolving keys through the aliases saved at
+    /// `aliases`. Port 0 picks a free port
+    pub fn start<P: AsRef<Path>>(
+        node: Peer,
+        port: u16,
+        aliases: P,
+    ) -> Result<Self, Error> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let aliases = aliases.as_ref().to_path_buf();
+        let handle = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(serve(node, aliases, listener))
+        });
+        Ok(Self { addr, handle })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the server to stop
+    pub fn join(self) -> Result<(), Error> {
+        self.handle
+            .join()
+            .map_err(|_| io::Error::other("the rpc server panicked"))?
+    }
+}
+
+async fn serve(node: Peer, aliases: PathBuf, listener: TcpListener) -> Result<(), Error> {
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let incoming =
+        TcpIncoming::from_listener(listener, true, None).map_err(io::Error::other)?;
+    let stopping = {
+        let node = node.clone();
+        async move {
+            while node.state() < State::Draining {
+                tokio::time::sleep(SHUTDOWN_POLL).await;
+            }
+        }
+    };
+    tonic::transport::Server::builder()
+        .add_service(ControlServer::new(Handler { node, aliases }))
+        .serve_with_incoming_shutdown(incoming, stopping)
+        .await
+        .map_err(|e| io::Error::other(e).into())
+}
+
+struct Handler {
+    node: Peer,
+
+    /// Where the aliases keys are resolved through are saved
+    aliases: PathBuf,
+}
+
+impl Handler {
+    /// The key a caller gave, or the key it is an alias for
+    fn key(&self, name_or_key: &str) -> Result<Key, Status> {
+        let aliases = Aliases::load(&self.aliases).map_err(status)?;
+        Ok(aliases.resolve(name_or_key))
+    }
+}
+
+#[tonic::async_trait]
+impl Control for Handler {
+    async fn ping(
+        &self,
+        req: Request<PingRequest>,
+    ) -> Result<Response<PingReply>, Status> {
+        let node = self.node.clone();
+        blocking(move || {
+            let targets = match req.into_inner().peer.as_str() {
+                "" => node.peers.lock().iter().map(|p| p.id().clone()).collect(),
+                peer => vec![parse_peer(peer)?],
+            };
+            let answered = targets
+                .into_iter()
+                .filter(|id| node.send_ping(id).is_ok())
+                .map(|id| id.to_string())
+                .collect();
+            Ok(PingReply { answered })
+        })
+        .await
+    }
+
+    async fn get_peers(
+        &self,
+        _: Request<GetPeersRequest>,
+    ) -> Result<Response<GetPeersReply>, Status> {
+        let peers = self.node.lock_peers().map_err(|e| status(e.into()))?;
+        let peers = peers.iter().map(|p| p.id().to_string()).collect();
+        Ok(Response::new(GetPeersReply { peers }))
+    }
+
+    async fn put(&self, req: Request<PutRequest>) -> Result<Response<PutReply>, Status> {
+        let PutRequest { key, value } = req.into_inner();
+        let old = self.node.put(self.key(&key)?, value).map_err(status)?;
+        let replaced = old.is_some();
+        Ok(Response::new(PutReply { replaced }))
+    }
+
+    async fn get(&self, req: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
+        let key = self.key(&req.into_inner().key)?;
+        match self.node.get(&key).map_err(status)? {
+            Some(value) => Ok(Response::new(GetReply { value })),
+            None => Err(Status::not_found(format!("no value for key {key}"))),
+        }
+    }
+
+    async fn join(
+        &self,
+        req: Request<JoinRequest>,
+    ) -> Result<Response<JoinReply>, Status> {
+        let mut node = self.node.clone();
+        blocking(move || {
+            let to = parse_peer(&req.into_inner().peer)?;
+            let added = node.join(&to).map_err(status)?;
+            Ok(JoinReply { added })
+        })
+        .await
+    }
+
+    async fn get_greylist(
+        &self,
+        _: Request<GetGreylistRequest>,
+    ) -> Result<Response<GetGreylistReply>, Status> {
+        let peers = self
+            .node
+            .greylisted()
+            .into_iter()
+            .map(|(id, left)| Greylisted {
+                peer: id.to_string(),
+                seconds_left: left.as_secs(),
+            })
+            .collect();
+        Ok(Response::new(GetGreylistReply { peers }))
+    }
+}
+
+/// Run a handler that dials peers off the runtime's thread
+async fn blocking<T, F>(f: F) -> Result<Response<T>, Status>
+where
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map(Response::new)
+}
+
+fn parse_peer(peer: &str) -> Result<PeerId, Status> {
+    peer.parse()
+        .map_err(|e: Error| Status::invalid_argument(e.to_string()))
+}
+
+fn status(e: Error) -> Status {
+    if e.is_retryable() {
+        Status::unavailable(e.to_string())
+    } else {
+        Status::internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::tests::test_peer;
+
+    #[test]
+    fn test_rpc() {
+        let node = test_peer(9927);
+        let aliases = std::env::temp_dir().join("harbor-rpc-aliases.txt");
+        std::fs::write(&aliases, "hi greeting\n").unwrap();
+        let server = RpcServer::start(node.clone(), 0, &aliases).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let url = format!("http://{}", server.addr());
+            let channel = tonic::transport::Endpoint::from_shared(url).unwrap();
+            let mut client = ControlClient::new(channel.connect().await.unwrap());
+
+            let put = PutRequest {
+                key: "greeting".to_string(),
+                value: b"hello".to_vec(),
+            };
+            assert!(!client.put(put).await.unwrap().into_inner().replaced);
+            let get = GetRequest {
+                key: "greeting".to_string(),
+            };
+            let reply = client.get(get).await.unwrap().into_inner();
+            assert_eq!(reply.value, b"hello");
+            let alias = GetRequest {
+                key: "hi".to_string(),
+            };
+            let reply = client.get(alias).await.unwrap().into_inner();
+            assert_eq!(reply.value, b"hello");
+            let missing = GetRequest {
+                key: "missing".to_string(),
+            };
+            let err = client.get(missing).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::NotFound);
+
+            let peers = client.get_peers(GetPeersRequest {}).await.unwrap();
+            assert!(peers.into_inner().peers.is_empty());
+            let greylist = client.get_greylist(GetGreylistRequest {}).await.unwrap();
+            assert!(greylist.into_inner().peers.is_empty());
+            let bad = JoinRequest {
+                peer: "not a peer".to_string(),
+            };
+            let err = client.join(bad).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        });
+
+        // Shutting down waits for open connections to close
+        drop(runtime);
+        node.stop();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,23 @@
+use crate::peer::PeerStoreEntry;
+use std::fmt;
+
+/// Decides how much this peer prefers each known peer. The PeerStore evicts
+/// the lowest scoring peers first, and queries fan out to the highest
+/// scoring peers first. Implement this to plug in custom trust levels
+pub trait PeerScorer: fmt::Debug + Send + Sync {
+    /// Score a known peer. Higher is better
+    fn score(&self, entry: &PeerStoreEntry) -> i64;
+}
+
+/// The built-in scoring. A peer's score is roughly when it was last seen
+/// (in seconds since the epoch), with every ping in a row it answered worth
+/// a minute and every ping in a row it missed costing an hour
+#[derive(Debug, Default)]
+pub struct DefaultScorer;
+
+impl PeerScorer for DefaultScorer {
+    fn score(&self, entry: &PeerStoreEntry) -> i64 {
+        let seen = entry.last_seen().map_or(0, |t| t.timestamp());
+        seen + entry.streak() as i64 * 60 - entry.failures() as i64 * 3600
+    }
+}
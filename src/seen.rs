@@ -0,0 +1,88 @@
+use crate::trace::TraceId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// The messages a peer has handled recently, so a flooded message that
+/// comes back around a loop is dropped instead of being flooded again
+#[derive(Debug)]
+pub struct SeenCache {
+    seen: HashMap<TraceId, Instant>,
+
+    /// Ids in the order they were seen, oldest first
+    order: VecDeque<TraceId>,
+
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl SeenCache {
+    /// Remember up to `capacity` messages, each for `ttl`
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Record a message. Returns false if it was already seen
+    pub fn insert(&mut self, id: TraceId) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        if self.seen.contains_key(&id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id, now);
+        self.order.push_back(id);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(oldest) = self.order.front() {
+            if now.duration_since(self.seen[oldest]) < self.ttl {
+                break;
+            }
+            self.seen.remove(oldest);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_cache() {
+        let mut cache = SeenCache::new(2, Duration::from_secs(60));
+        let (a, b, c) = (TraceId(1), TraceId(2), TraceId(3));
+        assert!(cache.insert(a));
+        assert!(!cache.insert(a));
+        assert!(cache.insert(b));
+
+        // The oldest id makes room
+        assert!(cache.insert(c));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.insert(a));
+
+        let mut cache = SeenCache::new(2, Duration::ZERO);
+        assert!(cache.insert(a));
+        assert!(cache.insert(a));
+    }
+}
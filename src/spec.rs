@@ -0,0 +1,154 @@
+use crate::{
+    peer::{DERIVATION_VECTORS, KEYED_DERIVATION_VECTORS},
+    protocol::{Envelope, Request, Response, MAX_TRANSFER_SIZE},
+    MAX_PEERSTORE_RESPONSE, MAX_TTS, PROTOCOL_VERSION,
+};
+use schemars::{schema::RootSchema, schema_for};
+use serde::{
+    de::{self, value::Error as ValueError, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer, Serialize,
+};
+
+/// A machine-readable description of the wire protocol, generated from the
+/// types the node itself uses, for other implementations to build against
+#[derive(Serialize, Debug)]
+pub struct Spec {
+    pub protocol: &'static str,
+    pub version: &'static str,
+
+    /// Version of the wire protocol, bumped whenever it changes
+    pub protocol_version: u16,
+    pub encoding: &'static str,
+    pub framing: &'static str,
+    pub encryption: &'static str,
+    pub limits: Limits,
+
+    /// How a PeerId is derived from a peer's address
+    pub peer_id: &'static str,
+
+    /// (address, PeerId) pairs to check a derivation against
+    pub peer_id_vectors: Vec<(&'static str, &'static str)>,
+
+    /// (public key, address, PeerId) triples to check a derivation from a
+    /// key against
+    pub keyed_peer_id_vectors: Vec<(&'static str, &'static str, &'static str)>,
+
+    /// Request variant names, in the order of their bincode tags
+    pub requests: Vec<&'static str>,
+
+    /// Response variant names, in the order of their bincode tags
+    pub responses: Vec<&'static str>,
+
+    pub request: RootSchema,
+    pub response: RootSchema,
+}
+
+/// Limits a node enforces on what it is sent
+#[derive(Serialize, Debug)]
+pub struct Limits {
+    /// Largest request or response in bytes
+    pub max_transfer_size: usize,
+
+    /// Highest tts a flooded request may carry
+    pub max_tts: u16,
+
+    /// Most entries in a Response::PeerStore
+    pub max_peerstore_response: usize,
+}
+
+/// Describe the protocol this build speaks
+pub fn generate() -> Spec {
+    Spec {
+        protocol: "harbor",
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        encoding: "bincode 1 with default options: little endian, fixed width \
+            integers, u64 lengths, enum variants tagged by a u32 index",
+        framing: "one request and one response per TCP connection, each sent \
+            as a frame: the message length as a little endian u32, then the \
+            bincode message. Requests are sent in an Envelope, which carries \
+            an optional trace id ahead of the request itself",
+        encryption: "a Noise_XX_25519_ChaChaPoly_BLAKE2s handshake right after \
+            connecting, the dialer as initiator, with no handshake payloads \
+            and the protocol version as a big endian u16 for the prologue. \
+            After it, frames are split into Noise messages of at most 65535 \
+            bytes. Handshake and transport messages are each prefixed by \
+            their length as a big endian u16. A peer with a key uses the \
+            X25519 form of it as its static key",
+        limits: Limits {
+            max_transfer_size: MAX_TRANSFER_SIZE,
+            max_tts: MAX_TTS,
+            max_peerstore_response: MAX_PEERSTORE_RESPONSE,
+        },
+        peer_id: "/peer/<hash>/<ip>/<port>, where hash is the first 32 hex \
+            digits of the sha256 of the peer's 32 byte Ed25519 public key, \
+            or of \"<ip>:<port>\" for peers without a key",
+        peer_id_vectors: DERIVATION_VECTORS.to_vec(),
+        keyed_peer_id_vectors: KEYED_DERIVATION_VECTORS.to_vec(),
+        requests: variants::<Request>(),
+        responses: variants::<Response>(),
+        request: schema_for!(Envelope),
+        response: schema_for!(Response),
+    }
+}
+
+/// The variant names of an enum, in declaration order, which is the order
+/// bincode numbers them in
+fn variants<'de, T: Deserialize<'de>>() -> Vec<&'static str> {
+    let mut names = VariantNames(&[]);
+    let _ = T::deserialize(&mut names);
+    names.0.to_vec()
+}
+
+/// A deserializer that fails straight away, but not before the enum it was
+/// asked for has told it its variant names
+struct VariantNames(&'static [&'static str]);
+
+impl<'de> Deserializer<'de> for &mut VariantNames {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, ValueError> {
+        Err(de::Error::custom("not an enum"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        variants: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, ValueError> {
+        self.0 = variants;
+        Err(de::Error::custom("only after the variant names"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_spec() {
+        let spec = generate();
+        assert_eq!(spec.requests[0], "Ping");
+
+        // The tags in the spec are the ones bincode puts on the wire
+        let tag = |req: &Request| {
+            let bytes = bincode::serialize(req).unwrap();
+            u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize
+        };
+        assert_eq!(spec.requests[tag(&Request::Time)], "Time");
+        assert_eq!(spec.requests[tag(&Request::Info)], "Info");
+        assert_eq!(spec.requests.len(), 22);
+
+        let json = serde_json::to_string(&spec).unwrap();
+        assert!(json.contains("\"QueryKey\"") && json.contains("\"NodeInfo\""));
+        assert!(json.contains("\"TraceId\""));
+    }
+}
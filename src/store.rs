@@ -0,0 +1,454 @@
+use crate::{peer::Key, STORE_LAYOUT, STORE_LAYOUT_FILE};
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where a Store keeps its values
+pub trait StoreBackend: Send + fmt::Debug {
+    /// Store a value, returning the one it replaced
+    fn put(&mut self, key: Key, value: Vec<u8>) -> io::Result<Option<Vec<u8>>>;
+
+    fn get(&self, key: &Key) -> io::Result<Option<Vec<u8>>>;
+
+    /// Remove a value, returning it if it was stored
+    fn delete(&mut self, key: &Key) -> io::Result<Option<Vec<u8>>>;
+
+    /// Every stored key, in order
+    fn keys(&self) -> Vec<Key>;
+
+    fn contains(&self, key: &Key) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of the stored values
+    fn bytes(&self) -> usize;
+}
+
+/// The values stored on a peer, by key, in whichever backend it was given.
+/// Values are held in memory unless the store is opened on a directory
+#[derive(Debug)]
+pub struct Store {
+    backend: Box<dyn StoreBackend>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::with_backend(MemoryBackend::default())
+    }
+}
+
+impl Store {
+    /// A store held in memory, and gone once the peer stops
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store keeping its values in files under `dir`, which is created
+    /// if need be. Values stored there before are picked up again
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        Ok(Self::with_backend(DiskBackend::open(dir)?))
+    }
+
+    pub fn with_backend<B: StoreBackend + 'static>(backend: B) -> Self {
+        Self {
+            backend: Box::new(backend),
+        }
+    }
+
+    /// Store a value, returning the one it replaced
+    pub fn put(&mut self, key: Key, value: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        self.backend.put(key, value)
+    }
+
+    pub fn get(&self, key: &Key) -> io::Result<Option<Vec<u8>>> {
+        self.backend.get(key)
+    }
+
+    /// Remove a value, returning it if it was stored
+    pub fn delete(&mut self, key: &Key) -> io::Result<Option<Vec<u8>>> {
+        self.backend.delete(key)
+    }
+
+    /// Every stored key, in order
+    pub fn list(&self) -> Vec<Key> {
+        self.backend.keys()
+    }
+
+    pub fn contains(&self, key: &Key) -> bool {
+        self.backend.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.backend.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backend.is_empty()
+    }
+
+    /// Total size of the stored values
+    pub fn bytes(&self) -> usize {
+        self.backend.bytes()
+    }
+}
+
+/// Values held in memory, for tests and nodes that don't need to keep them
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    values: BTreeMap<Key, Vec<u8>>,
+
+    /// Total size of the stored values
+    bytes: usize,
+}
+
+impl StoreBackend for MemoryBackend {
+    fn put(&mut self, key: Key, value: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        self.bytes += value.len();
+        let old = self.values.insert(key, value);
+        if let Some(old) = old.as_ref() {
+            self.bytes -= old.len();
+        }
+        Ok(old)
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn delete(&mut self, key: &Key) -> io::Result<Option<Vec<u8>>> {
+        let old = self.values.remove(key);
+        if let Some(old) = old.as_ref() {
+            self.bytes -= old.len();
+        }
+        Ok(old)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.values.keys().cloned().collect()
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.values.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// Values kept in a directory, a file per key named by the hex of the key.
+/// The keys and sizes are indexed in memory, so only reading and writing
+/// values touches the disk. The index is rebuilt from the directory on
+/// open, and each value is replaced in one rename, so there is no index on
+/// disk to fall out of step with the values and nothing to journal
+#[derive(Debug)]
+pub struct DiskBackend {
+    dir: PathBuf,
+
+    /// The size of each stored value
+    sizes: BTreeMap<Key, usize>,
+
+    /// Total size of the stored values
+    bytes: usize,
+}
+
+impl DiskBackend {
+    /// Open the values under `dir`, creating it if need be. Values a
+    /// crash left half written are cleared out, and files that aren't
+    /// named like a stored value are skipped. Stores in any layout but the
+    /// current one are refused
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        match layout(&dir)? {
+            STORE_LAYOUT => (),
+            // New, or from before stores were marked, which changed nothing
+            // else
+            0 => mark(&dir)?,
+            v if v > STORE_LAYOUT => return Err(newer(&dir, v)),
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} is in store layout {v}, run `harbor migrate-store` first",
+                        dir.display()
+                    ),
+                ))
+            }
+        }
+        let (sizes, _) = scan(&dir)?;
+        let bytes = sizes.values().sum();
+        Ok(Self { dir, sizes, bytes })
+    }
+
+    fn path(&self, key: &Key) -> PathBuf {
+        self.dir.join(hex::encode(key.to_string()))
+    }
+}
+
+impl StoreBackend for DiskBackend {
+    fn put(&mut self, key: Key, value: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        let old = self.get(&key)?;
+
+        // Write next to the value and rename over it, so a crash leaves
+        // the old value or the new one, never half of either
+        let path = self.path(&key);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, &value)?;
+        fs::rename(&partial, &path)?;
+
+        self.bytes += value.len();
+        if let Some(size) = self.sizes.insert(key, value.len()) {
+            self.bytes -= size;
+        }
+        Ok(old)
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Vec<u8>>> {
+        if !self.sizes.contains_key(key) {
+            return Ok(None);
+        }
+        fs::read(self.path(key)).map(Some)
+    }
+
+    fn delete(&mut self, key: &Key) -> io::Result<Option<Vec<u8>>> {
+        let old = match self.get(key)? {
+            Some(old) => old,
+            None => return Ok(None),
+        };
+        fs::remove_file(self.path(key))?;
+        if let Some(size) = self.sizes.remove(key) {
+            self.bytes -= size;
+        }
+        Ok(Some(old))
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.sizes.keys().cloned().collect()
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.sizes.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// Bring the store under `dir` up to the current layout in place,
+/// returning the layout it was in. `harbor migrate-store`
+pub fn migrate<P: AsRef<Path>>(dir: P) -> io::Result<u32> {
+    let dir = dir.as_ref();
+    if !dir.is_dir() {
+        let msg = format!("there is no store at {}", dir.display());
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+    let from = layout(dir)?;
+    match from {
+        v if v > STORE_LAYOUT => return Err(newer(dir, v)),
+        // Marking stores changed nothing else about them
+        0 => mark(dir)?,
+        _ => (),
+    }
+    Ok(from)
+}
+
+/// The layout the store under `dir` is in. Stores from before layouts
+/// were marked are in layout 0
+fn layout(dir: &Path) -> io::Result<u32> {
+    match fs::read_to_string(dir.join(STORE_LAYOUT_FILE)) {
+        Ok(text) => text.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad store layout marker {text:?}"),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mark the store under `dir` as in the current layout
+fn mark(dir: &Path) -> io::Result<()> {
+    fs::write(dir.join(STORE_LAYOUT_FILE), format!("{STORE_LAYOUT}\n"))
+}
+
+fn newer(dir: &Path, layout: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} is in store layout {layout}, newer than this build's {STORE_LAYOUT}",
+            dir.display()
+        ),
+    )
+}
+
+/// What checking a store directory found
+#[derive(Debug, Default)]
+pub struct Fsck {
+    /// Values read back in full
+    pub values: usize,
+
+    /// Total size of those values
+    pub bytes: usize,
+
+    /// Values a crash left half written, which were removed
+    pub removed: Vec<PathBuf>,
+
+    /// Files that aren't stored values, which are left alone
+    pub unknown: Vec<PathBuf>,
+
+    /// Values that couldn't be read back, and why
+    pub unreadable: Vec<(Key, String)>,
+}
+
+impl Fsck {
+    /// Whether every stored value could be read
+    pub fn is_ok(&self) -> bool {
+        self.unreadable.is_empty()
+    }
+}
+
+/// Check the store under `dir`: clear out values left half written, and
+/// read back every other value in full. `harbor fsck`
+pub fn fsck<P: AsRef<Path>>(dir: P) -> io::Result<Fsck> {
+    let dir = dir.as_ref();
+    let (sizes, mut report) = scan(dir)?;
+    for key in sizes.keys() {
+        match fs::read(dir.join(hex::encode(key.to_string()))) {
+            Ok(value) => {
+                report.values += 1;
+                report.bytes += value.len();
+            }
+            Err(e) => report.unreadable.push((key.clone(), e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+/// Index the values under `dir` by their size, removing any left half
+/// written
+fn scan(dir: &Path) -> io::Result<(BTreeMap<Key, usize>, Fsck)> {
+    let mut sizes = BTreeMap::new();
+    let mut report = Fsck::default();
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let path = file.path();
+        let name = file.file_name();
+        let name = name.to_str().unwrap_or_default();
+        if name == STORE_LAYOUT_FILE {
+            continue;
+        }
+        let key = |name: &str| {
+            hex::decode(name)
+                .ok()
+                .and_then(|name| String::from_utf8(name).ok())
+        };
+        match name.strip_suffix(".partial") {
+            Some(stem) if key(stem).is_some() => {
+                fs::remove_file(&path)?;
+                report.removed.push(path);
+            }
+            _ => match key(name) {
+                Some(key) => {
+                    sizes.insert(Key::new(&key), file.metadata()?.len() as usize);
+                }
+                None => report.unknown.push(path),
+            },
+        }
+    }
+    Ok((sizes, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Put the same values in a store and check what it makes of them
+    fn check(mut store: Store) {
+        let (a, b) = (Key::new("a"), Key::new("b"));
+        assert!(store.put(b.clone(), vec![1, 2, 3]).unwrap().is_none());
+        assert!(store.put(a.clone(), vec![4]).unwrap().is_none());
+        assert_eq!(store.put(a.clone(), vec![5, 6]).unwrap(), Some(vec![4]));
+        assert_eq!(store.get(&a).unwrap(), Some(vec![5, 6]));
+        assert_eq!(store.list(), vec![a.clone(), b.clone()]);
+        assert_eq!(store.bytes(), 5);
+
+        assert_eq!(store.delete(&b).unwrap(), Some(vec![1, 2, 3]));
+        assert!(store.get(&b).unwrap().is_none() && store.delete(&b).unwrap().is_none());
+        assert_eq!((store.len(), store.bytes()), (1, 2));
+    }
+
+    #[test]
+    fn test_store() {
+        check(Store::new());
+    }
+
+    #[test]
+    fn test_disk_store() {
+        let dir =
+            std::env::temp_dir().join(format!("harbor-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        check(Store::open(&dir).unwrap());
+
+        // What was stored is still there after reopening, and leftovers
+        // from an interrupted write are cleared out
+        let key = Key::new("a");
+        let partial = dir.join(format!("{}.partial", hex::encode("b")));
+        fs::write(&partial, b"half").unwrap();
+        fs::write(dir.join("notes.txt"), b"not a value").unwrap();
+        let mut store = Store::open(&dir).unwrap();
+        assert!(!partial.exists());
+        assert_eq!(store.list(), vec![key.clone()]);
+        assert_eq!(store.bytes(), 2);
+        assert_eq!(store.get(&key).unwrap(), Some(vec![5, 6]));
+        assert!(store.put(Key::new("a/../b"), vec![7]).unwrap().is_none());
+        assert_eq!(Store::open(&dir).unwrap().len(), 2);
+
+        fs::write(&partial, b"half").unwrap();
+        let report = fsck(&dir).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.values, report.bytes), (2, 3));
+        assert_eq!(report.removed, vec![partial]);
+        assert_eq!(report.unknown, vec![dir.join("notes.txt")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_layout() {
+        let dir =
+            std::env::temp_dir().join(format!("harbor-layout-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // New stores are marked with the current layout
+        drop(Store::open(&dir).unwrap());
+        assert_eq!(layout(&dir).unwrap(), STORE_LAYOUT);
+        assert_eq!(migrate(&dir).unwrap(), STORE_LAYOUT);
+
+        // Ones from before the marker are taken as they are
+        fs::remove_file(dir.join(STORE_LAYOUT_FILE)).unwrap();
+        assert_eq!(migrate(&dir).unwrap(), 0);
+        assert_eq!(layout(&dir).unwrap(), STORE_LAYOUT);
+
+        // Ones written by a newer build are left alone
+        let marker = dir.join(STORE_LAYOUT_FILE);
+        fs::write(&marker, format!("{}\n", STORE_LAYOUT + 1)).unwrap();
+        assert!(Store::open(&dir).is_err() && migrate(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
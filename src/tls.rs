@@ -0,0 +1,282 @@
+use crate::{
+    noise::{Conn, Deadlined},
+    peer::PeerId,
+    Error, FRAME_TIMEOUT, PROTOCOL_VERSION,
+};
+use parking_lot::RwLock;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, InvalidDnsNameError, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    convert::TryFrom,
+    fs,
+    io::{self, prelude::*, BufReader},
+    net::{IpAddr, TcpStream},
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+/// The TLS settings every connection uses instead of Noise, once enabled
+static TLS: RwLock<Option<Arc<TlsConfig>>> = RwLock::new(None);
+
+/// TLS settings for both ends of a connection. Both sides show a
+/// certificate signed by one of the trusted roots: the listener proves it
+/// is the peer that was dialed, and the dialer proves it belongs to the
+/// network. Requests a dialer makes speaking for a peer, like Join, are
+/// only taken if its certificate names that peer
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    /// Settings from a PEM certificate chain with its private key, and the
+    /// PEM roots peers' certificates must chain up to. A certificate names
+    /// the peer it belongs to with a `<hash>.harbor` DNS name for keyed
+    /// PeerIds, or with its IP address for the rest
+    pub fn from_pem(chain: &[u8], key: &[u8], roots: &[u8]) -> Result<Self, Error> {
+        let chain = rustls_pemfile::certs(&mut BufReader::new(chain))
+            .collect::<io::Result<Vec<CertificateDer>>>()
+            .map_err(|e| bad(format!("bad certificate chain: {e}")))?;
+        let key: PrivateKeyDer = rustls_pemfile::private_key(&mut BufReader::new(key))
+            .map_err(|e| bad(format!("bad private key: {e}")))?
+            .ok_or_else(|| bad("no private key"))?;
+        let mut store = RootCertStore::empty();
+        for root in rustls_pemfile::certs(&mut BufReader::new(roots)) {
+            let root = root.map_err(|e| bad(format!("bad root: {e}")))?;
+            store.add(root).map_err(bad)?;
+        }
+        if chain.is_empty() || store.is_empty() {
+            return Err(bad("a certificate and at least one root are needed"));
+        }
+        let store = Arc::new(store);
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(store.clone(), provider.clone())
+                .build()
+                .map_err(bad)?;
+        let mut server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(bad)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain.clone(), key.clone_key())
+            .map_err(bad)?;
+        let mut client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(bad)?
+            .with_root_certificates(store)
+            .with_client_auth_cert(chain, key)
+            .map_err(bad)?;
+
+        // Peers on other protocol versions are turned away in the handshake
+        let alpn = format!("harbor/{PROTOCOL_VERSION}").into_bytes();
+        server.alpn_protocols = vec![alpn.clone()];
+        client.alpn_protocols = vec![alpn];
+        Ok(Self {
+            server: Arc::new(server),
+            client: Arc::new(client),
+        })
+    }
+
+    /// Settings from PEM files
+    pub fn load<P: AsRef<Path>>(chain: P, key: P, roots: P) -> Result<Self, Error> {
+        Self::from_pem(&fs::read(chain)?, &fs::read(key)?, &fs::read(roots)?)
+    }
+}
+
+/// Encrypt every connection this process makes or accepts with TLS
+/// instead of Noise. Every peer in the network has to do the same
+pub fn enable(config: TlsConfig) {
+    *TLS.write() = Some(Arc::new(config));
+}
+
+pub(crate) fn config() -> Option<Arc<TlsConfig>> {
+    TLS.read().clone()
+}
+
+/// Handshake as the dialer, checking the certificate shown belongs to `to`
+pub(crate) fn initiate(
+    tcp: TcpStream,
+    config: &TlsConfig,
+    to: &PeerId,
+) -> io::Result<Conn> {
+    let name = name(to).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let tls = ClientConnection::new(config.client.clone(), name).map_err(broken)?;
+    handshake(tcp, tls.into())
+}
+
+/// Whether a certificate, already checked against the roots, names `id`
+pub(crate) fn names(cert: &CertificateDer, id: &PeerId) -> bool {
+    let cert = match webpki::EndEntityCert::try_from(cert) {
+        Ok(cert) => cert,
+        Err(_) => return false,
+    };
+    name(id).is_ok_and(|name| cert.verify_is_valid_for_subject_name(&name).is_ok())
+}
+
+/// The name a peer's certificate goes by
+fn name(id: &PeerId) -> Result<ServerName<'static>, InvalidDnsNameError> {
+    match id.key() {
+        Some(_) => ServerName::try_from(format!("{}.harbor", id.hash())),
+        None => Ok(ServerName::IpAddress(IpAddr::V4(id.ip()).into())),
+    }
+}
+
+/// Handshake as the listener, requiring the dialer's certificate
+pub(crate) fn respond(tcp: TcpStream, config: &TlsConfig) -> io::Result<Conn> {
+    let tls = ServerConnection::new(config.server.clone()).map_err(broken)?;
+    handshake(tcp, tls.into())
+}
+
+fn handshake(tcp: TcpStream, mut tls: rustls::Connection) -> io::Result<Conn> {
+    let mut io = Deadlined::new(&tcp, Some(Instant::now() + FRAME_TIMEOUT));
+    while tls.is_handshaking() {
+        tls.complete_io(&mut io)?;
+    }
+    Ok(Conn::from_tls(tcp, tls))
+}
+
+/// Read decrypted bytes, pulling in records until some arrive
+pub(crate) fn read(
+    tls: &mut rustls::Connection,
+    io: &mut Deadlined,
+    out: &mut [u8],
+) -> io::Result<usize> {
+    while tls.wants_read() {
+        if tls.complete_io(io)?.0 == 0 {
+            break;
+        }
+    }
+    tls.reader().read(out)
+}
+
+/// Encrypt `buf` and send all of it
+pub(crate) fn write(
+    tls: &mut rustls::Connection,
+    io: &mut Deadlined,
+    buf: &[u8],
+) -> io::Result<usize> {
+    let n = tls.writer().write(buf)?;
+    while tls.wants_write() {
+        tls.complete_io(io)?;
+    }
+    Ok(n)
+}
+
+fn bad<E: ToString>(e: E) -> Error {
+    Error::BadIdentity(e.to_string())
+}
+
+fn broken(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{identity::Identity, noise::Remote};
+    use rcgen::{
+        BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    /// A root, and a certificate it signed for each of `names`
+    fn certs(names: &[Vec<String>]) -> (String, Vec<(String, String)>) {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let root_key = KeyPair::generate().unwrap();
+        let root = params.self_signed(&root_key).unwrap();
+        let leaves = names
+            .iter()
+            .map(|names| {
+                let mut params = CertificateParams::new(names.clone()).unwrap();
+                params.extended_key_usages = vec![
+                    ExtendedKeyUsagePurpose::ServerAuth,
+                    ExtendedKeyUsagePurpose::ClientAuth,
+                ];
+                let key = KeyPair::generate().unwrap();
+                let cert = params.signed_by(&key, &root, &root_key).unwrap();
+                (cert.pem(), key.serialize_pem())
+            })
+            .collect();
+        (root.pem(), leaves)
+    }
+
+    #[test]
+    fn test_tls() {
+        let identity = Identity::generate().unwrap();
+        let listening = identity.peer_id("127.0.0.1".parse().unwrap(), 0);
+        let dialer = Identity::generate().unwrap();
+        let dialer = dialer.peer_id("127.0.0.2".parse().unwrap(), 3300);
+        let (root, leaves) = certs(&[
+            vec![format!("{}.harbor", listening.hash()), "127.0.0.1".into()],
+            vec![format!("{}.harbor", dialer.hash())],
+        ]);
+        let configs: Vec<TlsConfig> = leaves
+            .iter()
+            .map(|(cert, key)| {
+                TlsConfig::from_pem(cert.as_bytes(), key.as_bytes(), root.as_bytes())
+                    .unwrap()
+            })
+            .collect();
+        assert!(
+            TlsConfig::from_pem(b"", leaves[0].1.as_bytes(), root.as_bytes()).is_err()
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = {
+            let config = configs[0].clone();
+            let dialer = dialer.clone();
+            thread::spawn(move || {
+                for _ in 0..3 {
+                    let (tcp, _) = listener.accept().unwrap();
+                    if let Ok(mut conn) = respond(tcp, &config) {
+                        // The dialer's certificate names the peer it is,
+                        // and no other
+                        let cert = match conn.remote() {
+                            Some(Remote::Cert(cert)) => cert,
+                            remote => panic!("expected a certificate, got {:?}", remote),
+                        };
+                        assert!(names(&cert, &dialer));
+                        let keyless = PeerId::from(dialer.ip(), dialer.port());
+                        assert!(!names(&cert, &keyless));
+                        let mut buf = [0u8; 5];
+                        if conn.read_exact(&mut buf).is_ok() {
+                            conn.write_all(&buf).unwrap();
+                        }
+                    }
+                }
+            })
+        };
+
+        // The listener's certificate names its keyed id and its address
+        let keyless = PeerId::from("127.0.0.1".parse().unwrap(), addr.port());
+        for to in [&listening, &keyless] {
+            let tcp = TcpStream::connect(addr).unwrap();
+            let mut conn = initiate(tcp, &configs[1], to).unwrap();
+            assert!(conn.remote_key().is_none());
+            conn.write_all(b"hello").unwrap();
+            let mut got = [0u8; 5];
+            conn.read_exact(&mut got).unwrap();
+            assert_eq!(&got, b"hello");
+        }
+
+        // Someone else's id is caught
+        let other = Identity::generate().unwrap();
+        let tcp = TcpStream::connect(addr).unwrap();
+        let other = other.peer_id("127.0.0.1".parse().unwrap(), addr.port());
+        assert!(initiate(tcp, &configs[1], &other).is_err());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use crate::{
+    crawler,
+    peer::{Peer, PeerId},
+    protocol::{NetworkResult, Request, Response},
+    transport::Transport,
+    NetworkError, DIAL_TIMEOUT,
+};
+use serde::Serialize;
+
+/// The connection graph of the part of the network reachable from some
+/// peer. An edge from a to b means b is in a's PeerStore
+#[derive(Serialize, Debug, Default)]
+pub struct Topology {
+    pub nodes: Vec<String>,
+    pub edges: Vec<(String, String)>,
+
+    /// Peers that were found but did not answer
+    pub unreachable: Vec<String>,
+}
+
+impl Topology {
+    /// Walk the network breadth first from `start` by asking each peer for
+    /// its PeerStore, going at most `depth` hops out
+    pub fn crawl(start: &PeerId, depth: usize) -> Self {
+        let mut topology = Topology::default();
+        let unreachable = crawler::walk(start, depth, |id| {
+            let peers = peerstore(id)?;
+            topology.nodes.push(id.to_string());
+            for peer in peers.iter() {
+                topology.edges.push((id.to_string(), peer.to_string()));
+            }
+            Ok(peers)
+        });
+        topology.unreachable = unreachable.iter().map(|id| id.to_string()).collect();
+        topology
+    }
+
+    /// Render this graph in Graphviz dot format
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph harbor {\n");
+        for node in self.nodes.iter() {
+            dot += &format!("    \"{node}\";\n");
+        }
+        for node in self.unreachable.iter() {
+            dot += &format!("    \"{node}\" [style=dashed];\n");
+        }
+        for (from, to) in self.edges.iter() {
+            dot += &format!("    \"{from}\" -> \"{to}\";\n");
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+/// Ask a peer for the PeerIds in its PeerStore
+fn peerstore(id: &PeerId) -> NetworkResult<Vec<PeerId>> {
+    let mut conn = Peer::send_request_timeout(id, Request::PeerStore, DIAL_TIMEOUT)?;
+    match Peer::recv_response(&mut conn)? {
+        Response::PeerStore(store) => Ok(store.iter().map(|p| p.id().clone()).collect()),
+        res => Err(NetworkError::Fail(format!("unexpected response {res:?}"))),
+    }
+}
@@ -0,0 +1,125 @@
+use crate::protocol::{Envelope, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Identifies one logical operation, like a lookup, across every peer it
+/// touches, so their logs can be correlated
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "tools", derive(schemars::JsonSchema))]
+pub struct TraceId(pub u64);
+
+impl TraceId {
+    /// A new random trace id
+    pub fn new() -> Self {
+        Self(RandomState::new().build_hasher().finish())
+    }
+}
+
+impl Default for TraceId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceId>> = const { Cell::new(None) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The trace this thread is working on, if any
+pub fn current() -> Option<TraceId> {
+    CURRENT.with(Cell::get)
+}
+
+/// Run `f` as part of a trace. Requests sent from this thread while it
+/// runs carry the trace id to the next hop
+pub fn with_trace<T, F: FnOnce() -> T>(trace: TraceId, f: F) -> T {
+    let outer = CURRENT.with(|c| c.replace(Some(trace)));
+    let res = f();
+    CURRENT.with(|c| c.set(outer));
+    res
+}
+
+/// Run `f` with the work it does due by `deadline`, or by the deadline it
+/// is already under if that is sooner. Handlers wait on the network for
+/// no longer than the time left
+pub fn with_deadline<T, F: FnOnce() -> T>(deadline: Instant, f: F) -> T {
+    let outer = DEADLINE.with(Cell::get);
+    let deadline = outer.map_or(deadline, |outer| outer.min(deadline));
+    DEADLINE.with(|d| d.set(Some(deadline)));
+    let res = f();
+    DEADLINE.with(|d| d.set(outer));
+    res
+}
+
+/// When the work this thread is doing is due, if it has a deadline
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.with(Cell::get)
+}
+
+/// As much of `wanted` as is left before the deadline, if there is one
+pub fn time_left(wanted: Duration) -> Duration {
+    deadline().map_or(wanted, |deadline| {
+        wanted.min(deadline.saturating_duration_since(Instant::now()))
+    })
+}
+
+/// Put an outgoing request in an envelope carrying the current trace, if
+/// there is one
+pub(crate) fn envelope(request: Request) -> Envelope {
+    Envelope {
+        trace: current(),
+        request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        peer::{Peer, PeerId},
+        protocol::Response,
+    };
+
+    #[test]
+    fn test_trace() {
+        let trace = TraceId::new();
+        assert_eq!(envelope(Request::Ping).trace, None);
+        let sent = with_trace(trace, || envelope(Request::Ping));
+        assert_eq!(sent.trace, Some(trace));
+        assert_eq!(current(), None);
+
+        // The trace survives the trip, and can't nest
+        let bytes = bincode::serialize(&sent).unwrap();
+        let received: Envelope = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(received.trace, Some(trace));
+        assert!(matches!(received.request, Request::Ping));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let wanted = Duration::from_secs(5);
+        assert_eq!(time_left(wanted), wanted);
+        let soon = Instant::now() + Duration::from_secs(1);
+        let left = with_deadline(soon, || {
+            // A later deadline inside can't extend the outer one
+            with_deadline(soon + wanted, || time_left(wanted))
+        });
+        assert!(left <= Duration::from_secs(1));
+        assert_eq!(deadline(), None);
+        let past = Instant::now() - Duration::from_millis(1);
+        assert_eq!(with_deadline(past, || time_left(wanted)), Duration::ZERO);
+    }
+}
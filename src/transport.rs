@@ -1,42 +1,544 @@
+#[cfg(feature = "async")]
+use crate::noise::AsyncConn;
 use crate::{
+    budget::{MemoryBudget, Reservation},
+    metrics,
+    noise::Conn,
     peer::{Peer, PeerId},
-    protocol::{NetworkResult, Request, Response},
-    NetworkError,
+    protocol::{Envelope, NetworkResult, Request, Response, MAX_TRANSFER_SIZE},
+    record::{self, Direction, FrameKind},
+    trace, NetworkError, Reason, DIAL_TIMEOUT, FRAME_TIMEOUT, LISTEN_BACKLOG,
+    MAX_INBOUND, MAX_INBOUND_PER_SOURCE, MIN_READ_RATE,
 };
-use log::info;
+use log::{info, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
+    io,
     io::prelude::*,
-    net::{Shutdown, TcpStream},
-    thread, time,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{self, Duration, Instant},
 };
 
+/// Write a message as one frame: its length as a little endian u32, then
+/// the message itself
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_TRANSFER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} byte message is too large to send", payload.len()),
+        ));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    w.write_all(&frame)
+}
+
+/// Read one frame written by `write_frame`, however many reads it takes.
+/// Frames over MAX_TRANSFER_SIZE are refused without reading them
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_TRANSFER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{len} byte frame is over the limit of {MAX_TRANSFER_SIZE}"),
+        ));
+    }
+    read_payload(r, len)
+}
+
+/// Read the `len` bytes of a frame after its length. The buffer grows as
+/// bytes arrive, so a length that lies costs no more than what was sent
+fn read_payload<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    r.by_ref().take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("frame cut short at {} of {len} bytes", payload.len()),
+        ));
+    }
+    Ok(payload)
+}
+
+/// Read one frame like `read_frame`, but first reserve room for it in
+/// `budget`, waiting up to `wait` for room. While it waits, nothing is read,
+/// so TCP flow control pushes back on the sender. The length has to arrive
+/// within FRAME_TIMEOUT, and the rest at no less than MIN_READ_RATE
+pub fn read_frame_within(
+    conn: &mut Conn,
+    budget: &Arc<MemoryBudget>,
+    wait: Duration,
+) -> io::Result<(Vec<u8>, Reservation)> {
+    conn.set_deadline(Some(Instant::now() + FRAME_TIMEOUT));
+    let frame = read_frame_by_deadline(conn, budget, wait);
+    conn.set_deadline(None);
+    frame.map_err(as_rejection)
+}
+
+fn read_frame_by_deadline(
+    conn: &mut Conn,
+    budget: &Arc<MemoryBudget>,
+    wait: Duration,
+) -> io::Result<(Vec<u8>, Reservation)> {
+    let mut len = [0u8; 4];
+    conn.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_TRANSFER_SIZE {
+        return Err(rejected(
+            io::ErrorKind::InvalidData,
+            Reason::TooLarge,
+            format!("{len} byte frame is over the limit of {MAX_TRANSFER_SIZE}"),
+        ));
+    }
+    let reservation = budget.reserve(len, wait).ok_or_else(|| {
+        rejected(
+            io::ErrorKind::WouldBlock,
+            Reason::Overloaded,
+            format!("no room in the memory budget for a {len} byte frame"),
+        )
+    })?;
+    let timeout = FRAME_TIMEOUT + Duration::from_secs(len as u64 / MIN_READ_RATE);
+    conn.set_deadline(Some(Instant::now() + timeout));
+    Ok((read_payload(conn, len)?, reservation))
+}
+
+/// Read one frame like `read_frame_within`, from an async connection
+#[cfg(feature = "async")]
+pub async fn read_frame_within_async(
+    conn: &mut AsyncConn,
+    budget: &Arc<MemoryBudget>,
+    wait: Duration,
+) -> io::Result<(Vec<u8>, Reservation)> {
+    read_frame_by_deadline_async(conn, budget, wait)
+        .await
+        .map_err(as_rejection)
+}
+
+#[cfg(feature = "async")]
+async fn read_frame_by_deadline_async(
+    conn: &mut AsyncConn,
+    budget: &Arc<MemoryBudget>,
+    wait: Duration,
+) -> io::Result<(Vec<u8>, Reservation)> {
+    let mut len = [0u8; 4];
+    within(FRAME_TIMEOUT, conn.read_exact(&mut len)).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_TRANSFER_SIZE {
+        return Err(rejected(
+            io::ErrorKind::InvalidData,
+            Reason::TooLarge,
+            format!("{len} byte frame is over the limit of {MAX_TRANSFER_SIZE}"),
+        ));
+    }
+    let reservation = budget.reserve_async(len, wait).await.ok_or_else(|| {
+        rejected(
+            io::ErrorKind::WouldBlock,
+            Reason::Overloaded,
+            format!("no room in the memory budget for a {len} byte frame"),
+        )
+    })?;
+    let timeout = FRAME_TIMEOUT + Duration::from_secs(len as u64 / MIN_READ_RATE);
+    let payload = within(timeout, read_payload_async(conn, len)).await?;
+    Ok((payload, reservation))
+}
+
+/// Read one frame like `read_frame`, from an async connection
+#[cfg(feature = "async")]
+pub async fn read_frame_async(conn: &mut AsyncConn) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    conn.read_exact(&mut len).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_TRANSFER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{len} byte frame is over the limit of {MAX_TRANSFER_SIZE}"),
+        ));
+    }
+    read_payload_async(conn, len).await
+}
+
+/// Read the `len` bytes of a frame like `read_payload`, growing the buffer
+/// as bytes arrive
+#[cfg(feature = "async")]
+async fn read_payload_async(conn: &mut AsyncConn, len: usize) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let mut chunk = vec![0u8; len.min(1 << 16)];
+    while payload.len() < len {
+        let want = chunk.len().min(len - payload.len());
+        match conn.read(&mut chunk[..want]).await? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("frame cut short at {} of {len} bytes", payload.len()),
+                ))
+            }
+            n => payload.extend_from_slice(&chunk[..n]),
+        }
+    }
+    Ok(payload)
+}
+
+/// Write a message as one frame like `write_frame`, to an async connection
+#[cfg(feature = "async")]
+pub async fn write_frame_async(conn: &mut AsyncConn, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_TRANSFER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} byte message is too large to send", payload.len()),
+        ));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    conn.write_all(&frame).await
+}
+
+/// Run io that has to be done within `timeout`
+#[cfg(feature = "async")]
+pub(crate) async fn within<T, E, F>(timeout: Duration, io: F) -> Result<T, E>
+where
+    F: std::future::Future<Output = Result<T, E>>,
+    E: From<io::Error>,
+{
+    match tokio::time::timeout(timeout, io).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "frame took too long to arrive",
+        )
+        .into()),
+    }
+}
+
+/// Turn the ways a frame can fail to arrive into the rejection the
+/// requester is told about
+fn as_rejection(e: io::Error) -> io::Error {
+    match e.kind() {
+        _ if is_rejection(&e) => e,
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            rejected(e.kind(), Reason::TooSlow, e.to_string())
+        }
+        io::ErrorKind::UnexpectedEof => {
+            rejected(e.kind(), Reason::Malformed, "frame cut short".to_string())
+        }
+        _ => e,
+    }
+}
+
+/// An io error that turns a request away, so the requester can be told why
+fn rejected(kind: io::ErrorKind, reason: Reason, detail: String) -> io::Error {
+    io::Error::new(kind, NetworkError::Rejected(reason, detail))
+}
+
+fn is_rejection(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<NetworkError>())
+}
+
+/// Decode a request frame, counting it in the metrics and recording
+pub(crate) fn parse_request(buf: &[u8]) -> NetworkResult<Envelope> {
+    let req = bincode::deserialize::<Envelope>(buf)?;
+    metrics::record_received(req.request.class(), buf.len());
+    record::record(FrameKind::Request, Direction::Received, buf);
+    Ok(req)
+}
+
+/// Options for the socket a peer listens on
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// Number of pending connections to queue
+    pub backlog: i32,
+
+    /// Disable Nagle's algorithm on accepted connections
+    pub nodelay: bool,
+
+    /// Allow rebinding the address while old connections are in TIME_WAIT
+    pub reuse_address: bool,
+
+    /// Number of threads accepting connections
+    pub acceptors: usize,
+
+    /// Give each acceptor its own socket with SO_REUSEPORT, so the kernel
+    /// spreads connections across them. Otherwise they share one socket.
+    /// Only supported on unix
+    pub reuse_port: bool,
+
+    /// Most incoming connections handled at once. Past this, connections
+    /// are closed straight away, before the handshake, so turning them
+    /// away costs next to nothing
+    pub max_inbound: usize,
+
+    /// Most incoming connections handled at once from one address
+    pub max_inbound_per_source: usize,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            backlog: LISTEN_BACKLOG,
+            nodelay: true,
+            reuse_address: true,
+            acceptors: 1,
+            reuse_port: false,
+            max_inbound: MAX_INBOUND,
+            max_inbound_per_source: MAX_INBOUND_PER_SOURCE,
+        }
+    }
+}
+
+/// Bind a listening socket with the given options
+pub fn listen(addr: SocketAddr, opts: &SocketOptions) -> io::Result<TcpListener> {
+    let socket =
+        Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(opts.reuse_address)?;
+    if opts.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is only supported on unix",
+        ));
+    }
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(opts.backlog)?;
+    Ok(socket.into())
+}
+
+/// Bind a listening socket for each acceptor, either separate sockets
+/// sharing the port or handles to the same socket
+pub fn listen_all(
+    addr: SocketAddr,
+    opts: &SocketOptions,
+) -> io::Result<Vec<TcpListener>> {
+    let count = opts.acceptors.max(1);
+    if opts.reuse_port {
+        return (0..count).map(|_| listen(addr, opts)).collect();
+    }
+    let socket = listen(addr, opts)?;
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 1..count {
+        sockets.push(socket.try_clone()?);
+    }
+    sockets.push(socket);
+    Ok(sockets)
+}
+
 /// Send requests to a peer, and send responses back
-pub trait Transport {
-    fn send_request(to_peer: &PeerId, req: Request) -> NetworkResult<TcpStream>;
-    fn send_response(conn: &mut TcpStream, res: Response) -> NetworkResult<usize>;
+pub trait Transport: crate::sealed::Sealed {
+    fn send_request(to_peer: &PeerId, req: Request) -> NetworkResult<Conn>;
+    fn send_request_timeout(
+        to_peer: &PeerId,
+        req: Request,
+        timeout: Duration,
+    ) -> NetworkResult<Conn>;
+    fn send_request_as(
+        to_peer: &PeerId,
+        req: Request,
+        timeout: Duration,
+        secret: Option<&[u8; 32]>,
+    ) -> NetworkResult<Conn>;
+    fn send_response(conn: &mut Conn, res: Response) -> NetworkResult<usize>;
+    fn recv_request(conn: &mut Conn) -> NetworkResult<Request>;
+    fn recv_response(conn: &mut Conn) -> NetworkResult<Response>;
 }
 
 impl Transport for Peer {
-    /// Send a request to a peer. The input PeerId `to_peer` should always
-    /// be from the output of the routing function.
-    fn send_request(to_peer: &PeerId, req: Request) -> NetworkResult<TcpStream> {
-        // Dial the peer
-        let mut conn = TcpStream::connect(to_peer.as_socket())?;
+    /// Send a request to a peer, giving up if the peer cannot be dialed
+    /// within DIAL_TIMEOUT. Reads on the returned stream wait as long as it
+    /// takes. The input PeerId `to_peer` should always be from the output
+    /// of the routing function
+    fn send_request(to_peer: &PeerId, req: Request) -> NetworkResult<Conn> {
+        let conn = Self::send_request_timeout(to_peer, req, DIAL_TIMEOUT)?;
+        conn.set_read_timeout(None)?;
+        Ok(conn)
+    }
+
+    /// Send a request to a peer, giving up if the peer cannot be dialed
+    /// within `timeout`. Reads on the returned stream share the same
+    /// timeout. If `to_peer` was derived from a key, the peer must prove it
+    /// holds that key
+    fn send_request_timeout(
+        to_peer: &PeerId,
+        req: Request,
+        timeout: Duration,
+    ) -> NetworkResult<Conn> {
+        Self::send_request_as(to_peer, req, timeout, None)
+    }
+
+    /// Send a request like `send_request_timeout`, proving to the peer we
+    /// hold `secret`. Requests that speak for a peer, like Join and Leave,
+    /// have to be sent with that peer's key
+    fn send_request_as(
+        to_peer: &PeerId,
+        req: Request,
+        timeout: Duration,
+        secret: Option<&[u8; 32]>,
+    ) -> NetworkResult<Conn> {
+        let req = trace::envelope(req);
+        let addr = SocketAddr::from((to_peer.ip(), to_peer.port()));
+        let conn = TcpStream::connect_timeout(&addr, timeout)?;
+        conn.set_read_timeout(Some(timeout))?;
+        let mut conn = Conn::dial_as(conn, to_peer, secret)?;
         info!("dialed peer {:?}", to_peer);
 
-        // Assume, for now, that req is of type Request::Ping (why did i write this)
         let ser = &bincode::serialize(&req)?[..];
 
-        conn.write(ser)?;
-        info!("wrote request {req:?} to {to_peer:?}");
+        write_frame(&mut conn, ser)?;
+        metrics::record_sent(req.request.class(), ser.len());
+        record::record(FrameKind::Request, Direction::Sent, ser);
+        info!("wrote request {:?} to {to_peer:?}", req.request);
         Ok(conn)
     }
 
-    /// Send a response to a request to the given TcpStream
-    fn send_response(conn: &mut TcpStream, res: Response) -> NetworkResult<usize> {
+    /// Send a response to a request on the given connection
+    fn send_response(conn: &mut Conn, res: Response) -> NetworkResult<usize> {
         let ser = &bincode::serialize(&res)?[..];
-        let status = conn.write(ser)?;
+        write_frame(conn, ser)?;
+        metrics::record_sent(res.class(), ser.len());
+        record::record(FrameKind::Response, Direction::Sent, ser);
+        info!("wrote response {res:?} to {conn:?}");
+        Ok(ser.len())
+    }
+
+    /// Read a request frame from the given connection
+    fn recv_request(conn: &mut Conn) -> NetworkResult<Request> {
+        Ok(parse_request(&read_frame(conn)?)?.request)
+    }
+
+    /// Read a response frame from the given connection
+    fn recv_response(conn: &mut Conn) -> NetworkResult<Response> {
+        let buf = read_frame(conn)?;
+        let res = bincode::deserialize::<Response>(&buf[..])?;
+        metrics::record_received(res.class(), buf.len());
+        record::record(FrameKind::Response, Direction::Received, &buf);
+        if let Response::Err(e @ NetworkError::Rejected(..)) = &res {
+            warn!("{:?} turned our request away: {e}", conn.peer_addr());
+        }
+        Ok(res)
+    }
+}
+
+/// Send requests to a peer and responses back like Transport, over async
+/// connections, for callers on a tokio runtime. These only speak Noise, so
+/// with `tls::enable` called they fail and Transport has to be used
+#[cfg(feature = "async")]
+pub trait AsyncTransport: crate::sealed::Sealed {
+    fn send_request_async(
+        to_peer: &PeerId,
+        req: Request,
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = NetworkResult<AsyncConn>> + Send;
+    fn send_response_async(
+        conn: &mut AsyncConn,
+        res: Response,
+    ) -> impl std::future::Future<Output = NetworkResult<usize>> + Send;
+    fn recv_request_async(
+        conn: &mut AsyncConn,
+    ) -> impl std::future::Future<Output = NetworkResult<Request>> + Send;
+    fn recv_response_async(
+        conn: &mut AsyncConn,
+    ) -> impl std::future::Future<Output = NetworkResult<Response>> + Send;
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for Peer {
+    /// Send a request to a peer like `send_request_timeout`, giving up if
+    /// the dial, the handshake or the write take longer than `timeout`
+    async fn send_request_async(
+        to_peer: &PeerId,
+        req: Request,
+        timeout: Duration,
+    ) -> NetworkResult<AsyncConn> {
+        #[cfg(feature = "tls")]
+        if crate::tls::config().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "async connections don't speak TLS",
+            )
+            .into());
+        }
+        let req = trace::envelope(req);
+        let ser = bincode::serialize(&req)?;
+        let addr = SocketAddr::from((to_peer.ip(), to_peer.port()));
+        let conn = within(timeout, async {
+            let conn = tokio::net::TcpStream::connect(addr).await?;
+            let mut conn = AsyncConn::dial_as(conn, to_peer, None).await?;
+            info!("dialed peer {:?}", to_peer);
+            write_frame_async(&mut conn, &ser).await?;
+            Ok::<_, io::Error>(conn)
+        })
+        .await?;
+        metrics::record_sent(req.request.class(), ser.len());
+        record::record(FrameKind::Request, Direction::Sent, &ser);
+        info!("wrote request {:?} to {to_peer:?}", req.request);
+        Ok(conn)
+    }
+
+    async fn send_response_async(
+        conn: &mut AsyncConn,
+        res: Response,
+    ) -> NetworkResult<usize> {
+        let ser = bincode::serialize(&res)?;
+        write_frame_async(conn, &ser).await?;
+        metrics::record_sent(res.class(), ser.len());
+        record::record(FrameKind::Response, Direction::Sent, &ser);
         info!("wrote response {res:?} to {conn:?}");
-        Ok(status)
+        Ok(ser.len())
+    }
+
+    async fn recv_request_async(conn: &mut AsyncConn) -> NetworkResult<Request> {
+        Ok(parse_request(&read_frame_async(conn).await?)?.request)
+    }
+
+    async fn recv_response_async(conn: &mut AsyncConn) -> NetworkResult<Response> {
+        let buf = read_frame_async(conn).await?;
+        let res = bincode::deserialize::<Response>(&buf[..])?;
+        metrics::record_received(res.class(), buf.len());
+        record::record(FrameKind::Response, Direction::Received, &buf);
+        if let Response::Err(e @ NetworkError::Rejected(..)) = &res {
+            warn!("{:?} turned our request away: {e}", conn.peer_addr());
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out one byte per read, like a slow connection
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((byte, rest)) if !buf.is_empty() => {
+                    buf[0] = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        // Bigger than the old single read buffer, and split across reads
+        let payload = vec![7u8; 10_000];
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &payload).unwrap();
+        assert_eq!(read_frame(&mut Trickle(&frame)).unwrap(), payload);
+
+        // Cut short, or claiming to be too large
+        let err = read_frame(&mut Trickle(&frame[..100])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let huge = ((MAX_TRANSFER_SIZE + 1) as u32).to_le_bytes();
+        assert!(read_frame(&mut &huge[..]).is_err());
     }
 }
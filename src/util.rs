@@ -24,7 +24,7 @@ pub fn hash_sha256(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(bytes);
     let result = hasher.finalize();
-    hex::encode(&result)[0..HASH_LEN].to_string()
+    hex::encode(result)[0..HASH_LEN].to_string()
 }
 
 /// Get this system's local ip address